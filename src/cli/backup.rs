@@ -1,12 +1,16 @@
 use anyhow::Result;
 use console::style;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use jmap_client::client::Client;
-use log::info;
+use log::{error, info};
 use opendal::Operator;
-use tantivy::IndexWriter;
 
+use crate::conf::{self, Conf};
 use crate::core::email::emails;
+use crate::core::jmap::JmapConnection;
+use crate::core::layout;
+use crate::core::lock;
+use crate::core::search::{create_indexer, Indexer};
+use crate::core::storage::create_storage_backend;
 use crate::core::mailboxes::mailboxes;
 use crate::core::helpers;
 use crate::core::progress::Progressable;
@@ -33,7 +37,8 @@ impl Progressable for ProgressBar {
     }
 }
 
-pub async fn backup(client: Client, operator: Operator, multi: MultiProgress, indexer: Option<IndexWriter>) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn backup(connection: &JmapConnection, operator: &Operator, multi: &MultiProgress, indexer: Option<&mut Indexer>, conf: &conf::Backup) -> Result<(), Box<dyn std::error::Error>> {
+    let client = connection.client().await?;
     let max_objects = helpers::max_objects_in_get(&client);
     let progress = multi;
     let sty = ProgressStyle::with_template(
@@ -44,18 +49,21 @@ pub async fn backup(client: Client, operator: Operator, multi: MultiProgress, in
 
     let pb_mailboxes = progress.add(ProgressBar::new(0));
     let pb_emails = progress.add(ProgressBar::new(0));
+    let pb_skipped = progress.add(ProgressBar::new(0));
     // Set style of all progress bars
     pb_mailboxes.set_style(sty.clone());
     pb_mailboxes.set_message("Mailboxes:");
     pb_emails.set_style(sty.clone());
     pb_emails.set_message("Emails:");
+    pb_skipped.set_style(ProgressStyle::with_template("{msg:10} {pos:>7} blobs already stored").unwrap());
+    pb_skipped.set_message("Skipped:");
     
 
     // Process mailboxes
-    mailboxes(&client, &operator, max_objects, &pb_mailboxes).await?;
+    mailboxes(&client, operator, max_objects, &pb_mailboxes).await?;
 
     // Process emails
-    emails(&client, &operator, max_objects, &pb_emails, indexer).await?;
+    emails(connection, operator, max_objects, &pb_emails, &pb_skipped, indexer, conf).await?;
 
 
     // Print mailboxes
@@ -69,8 +77,67 @@ pub async fn backup(client: Client, operator: Operator, multi: MultiProgress, in
         style("Found").green(),
         style(pb_emails.position()).green()
     );
+    info!(
+        "{} {} blobs already in storage",
+        style("Skipped").green(),
+        style(pb_skipped.position()).green()
+    );
 
     Ok(())
 }
 
+/**
+ * Back up a single profile, setting up the JMAP client, storage operator and indexer from its config
+ */
+pub async fn backup_profile(conf: &mut Conf, multi: &MultiProgress, break_lock: bool) -> anyhow::Result<()> {
+    // We need to configure the jmap client and operator for backup to work
+    conf.set_jmap_secret()?;
+    conf.set_storage_secret()?;
+
+    let connection = JmapConnection::new(&conf.jmap, &conf.name).await?;
+    let operator = create_storage_backend(conf.storage.scheme.into(), conf.storage.config.clone())?;
+    let mut indexer = match &conf.search {
+        Some(search) if search.enable => Some(create_indexer(search)?),
+        _ => None,
+    };
+
+    // Keep other processes from writing the archive while we back up, including the descriptor of a new archive
+    let lock = lock::acquire(&operator, "backup", break_lock).await?;
+    let backup_conf = conf.backup.take().unwrap_or_default();
+
+    lock.hold(async {
+        // Creates the format descriptor on the first backup, and refuses archives with an unknown layout
+        layout::open_archive(&operator, true).await?;
+
+        backup(&connection, &operator, multi, indexer.as_mut(), &backup_conf)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    })
+    .await
+}
+
+/**
+ * Back up every profile one after the other.
+ * A failing profile does not stop the others, but the process exits with an error at the end.
+ */
+pub async fn backup_all(confs: Vec<Conf>, multi: &MultiProgress, break_lock: bool) -> anyhow::Result<()> {
+    let mut failed = vec![];
 
+    for mut conf in confs {
+        info!("Backing up profile {}", style(&conf.name).bold());
+
+        if let Err(e) = backup_profile(&mut conf, multi, break_lock).await {
+            let err = format!("Error backing up {}. {}", conf.name, e);
+            error!("{}", style(err).red().bold());
+            failed.push(conf.name);
+        }
+    }
+
+    if !failed.is_empty() {
+        let err = format!("Backup failed for profiles: {}", failed.join(", "));
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -1,16 +1,28 @@
 use std::path::PathBuf;
-use clap::{Parser, Subcommand, arg};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::core::search::SearchSort;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Optional name to operate on
+    /// Name of the profile to operate on, required when the config has several profiles
     pub name: Option<String>,
 
+    /// Run the command for every profile in the config
+    #[arg(long, conflicts_with = "name")]
+    pub all: bool,
+
     /// Sets a custom config file
-    #[arg(short, long, value_name = "FILE")]
+    // The id keeps clap from confusing the option with the config subcommand
+    #[arg(short, long, value_name = "FILE", id = "config_file")]
     pub config: Option<PathBuf>,
 
+    /// Remove the lock held by another process on the archive before writing to it.
+    /// Only use this if you are sure the other process is no longer running
+    #[arg(long)]
+    pub break_lock: bool,
+
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub debug: u8,
@@ -22,9 +34,15 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 pub enum Commands {
+    /// Create a config file by answering a few questions
+    Init {},
+
     /// Backup JMAP data from a JMAP server
     Backup {},
 
+    /// Keep running and back up on a schedule and whenever the JMAP server pushes new changes
+    Daemon {},
+
     /// Show the status of the backup, i.e. what was the last message backed up
     Status {},
 
@@ -33,21 +51,144 @@ pub enum Commands {
         /// Search query
         query: String,
 
-        /// Which fields to show in the search results
-        /// Default is id and subject
-        #[arg(short, long, value_parser, num_args = 1.., value_delimiter = ' ', default_value = "id subject")]
+        /// Which fields to show in the search results, any of id, blob_id, thread_id, date, subject, from, to,
+        /// cc, bcc, mailboxes, mailbox_ids, keywords, size, has_attachment, attachments, score and snippet
+        #[arg(short, long, value_parser, num_args = 1.., value_delimiter = ' ', default_value = "id date from subject snippet")]
         fields: Option<Vec<String>>,
         
         /// Limit the number of results
         #[arg(short, long, default_value = "100")]
-        limit: Option<usize>,
+        limit: usize,
+
+        /// Skip this many results, to page through results together with the limit
+        #[arg(long, default_value = "0")]
+        offset: usize,
+
+        /// Order of the results
+        #[arg(long, value_enum, default_value = "relevance")]
+        sort: SearchSort,
+
+        /// How to print the results
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+
+    /// Copy the archive to the storage of another profile, e.g. from a NAS to object storage
+    /// Files already copied are skipped, so an interrupted migration can be restarted
+    Migrate {
+        /// Name of the profile whose storage the archive is copied to
+        #[arg(long)]
+        to: String,
+
+        /// Read every copied file back from the target and compare hashes
+        #[arg(long)]
+        verify: bool,
+
+        /// Number of files to copy concurrently
+        #[arg(long, default_value = "16")]
+        concurrency: usize,
+
+        /// Store large attachments separately in the target, using the attachment threshold of the target profile
+        #[arg(long, conflicts_with = "join_attachments")]
+        split_attachments: bool,
+
+        /// Store messages with split out attachments whole in the target
+        #[arg(long)]
+        join_attachments: bool,
+    },
+
+    /// Upgrade an archive written by an older version of postkasse to the current layout
+    UpgradeArchive {},
+
+    /// Rebuild the search index from the emails in the archive, without connecting to the JMAP server
+    /// The current index is used for searches until the new one is complete
+    Reindex {
+        /// Number of emails to read from the storage concurrently
+        #[arg(long, default_value = "16")]
+        concurrency: usize,
+    },
+
+    /// Remove duplicate documents left in the search index by older versions of postkasse
+    /// Indexes written with an older schema are rebuilt from the archive instead
+    DedupeIndex {
+        /// Number of emails to read from the storage concurrently, when the index is rebuilt
+        #[arg(long, default_value = "16")]
+        concurrency: usize,
     },
 
     Open {
         /// Show the email with the given id
         id: String,
-    }
+    },
 
+    /// Inspect and validate the config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Print a whole conversation from the archive in chronological order
+    Thread {
+        /// Id of the thread, or of any email in it
+        id: String,
+    },
+
+    /// Manage the secrets of the profile stored in the keyring
+    Secrets {
+        #[command(subcommand)]
+        command: SecretsCommands,
+    },
+
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Validate the config and test the JMAP server and storage backend without backing up
+    /// Checks every profile unless one is selected
+    Check {},
+}
+
+#[derive(Subcommand)]
+pub enum SecretsCommands {
+    /// Show where each secret of the profile is read from, without showing the secrets
+    List {},
+
+    /// Store a secret in the keyring, replacing any stored before
+    Set {
+        kind: SecretKind,
+    },
+
+    /// Remove a secret from the keyring, it is asked for again the next time it is needed
+    Delete {
+        kind: SecretKind,
+    },
+
+    /// Replace a secret in the keyring after checking that the new one works
+    Rotate {
+        kind: SecretKind,
+    },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SecretKind {
+    /// The JMAP password or token
+    Jmap,
+    /// The OAuth refresh token, set by logging in again
+    Oauth,
+    /// The secret of the storage scheme, e.g. the S3 secret access key
+    Storage,
+}
 
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Table for reading in the terminal
+    Table,
+    /// JSON object with the result count and an array of results
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma separated values with a header row
+    Csv,
+    /// Only the email ids, one per line
+    Ids,
+}
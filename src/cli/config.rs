@@ -0,0 +1,113 @@
+use anyhow::Context;
+use console::style;
+use log::error;
+
+use crate::cli::cli::Cli;
+use crate::conf::{keyring_entry, AuthMode, Conf};
+use crate::core::email::email_properties;
+use crate::core::jmap::JmapConnection;
+use crate::core::oauth::REFRESH_TOKEN_SECRET;
+use crate::core::search::search_languages;
+use crate::core::storage::{check_permissions, create_storage_backend};
+
+/**
+ * Validate the config of the selected profiles, or all of them, and test that the JMAP server
+ * and the storage backend can be used with it. Prints a report and exits with an error if any check failed.
+ */
+pub async fn check_config(cli: &Cli) {
+    let checks = Conf::check(cli).unwrap_or_else(|e| {
+        let err = format!("Error reading config file {}", e);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    });
+
+    let mut failed = 0;
+
+    for check in checks {
+        println!("{} {}", style("Profile").bold(), style(&check.name).bold());
+
+        let Some(mut conf) = check.conf else {
+            for problem in check.problems {
+                report("config", Err(problem));
+            }
+            failed += 1;
+            println!();
+            continue;
+        };
+
+        let mut passed = true;
+        let backup_conf = conf.backup.take().unwrap_or_default();
+        passed &= report("config", email_properties(&backup_conf.properties).map(|_| ()).map_err(|e| e.to_string()));
+        if let Some(search) = &conf.search {
+            passed &= report("search", search_languages(&search.languages).map(|_| ()).map_err(|e| e.to_string()));
+        }
+
+        // Secrets are only read, a missing one is reported rather than prompted for or logged in to
+        let jmap = match conf.read_jmap_secret().and_then(|_| check_oauth_token(&conf)) {
+            Ok(()) => match JmapConnection::new(&conf.jmap, &conf.name).await {
+                Ok(connection) => match connection.client().await {
+                    Ok(client) => Ok(format!("connected to {} as {}", conf.jmap.host, client.session().username())),
+                    Err(e) => Err(format!("{:#}", e)),
+                },
+                Err(e) => Err(format!("{:#}", e)),
+            },
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        passed &= report_with("jmap", jmap);
+
+        let operator = conf
+            .read_storage_secret()
+            .and_then(|_| create_storage_backend(conf.storage.scheme.into(), conf.storage.config.clone()));
+
+        match operator {
+            Ok(operator) => {
+                for (operation, result) in check_permissions(&operator).await {
+                    passed &= report(&format!("storage {}", operation), result.map_err(|e| format!("{:#}", e)));
+                }
+            }
+            Err(e) => passed &= report("storage", Err(format!("{:#}", e))),
+        }
+
+        if !passed {
+            failed += 1;
+        }
+        println!();
+    }
+
+    if failed > 0 {
+        let err = format!("{} profiles failed the config check", failed);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+
+    println!("{}", style("All checks passed").green().bold());
+}
+
+/// Connecting with OAuth logs in when there is no refresh token, which a check should not do
+fn check_oauth_token(conf: &Conf) -> anyhow::Result<()> {
+    if conf.jmap.auth_mode != AuthMode::OAuth {
+        return Ok(());
+    }
+
+    match keyring_entry(&conf.name, REFRESH_TOKEN_SECRET)?.get_password() {
+        Ok(_) => Ok(()),
+        Err(keyring::Error::NoEntry) => {
+            anyhow::bail!("Not logged in, log in with `postkasse secrets set oauth`")
+        }
+        Err(e) => Err(e).with_context(|| "Error reading OAuth refresh token from keyring"),
+    }
+}
+
+fn report(check: &str, result: Result<(), String>) -> bool {
+    report_with(check, result.map(|_| String::new()))
+}
+
+fn report_with(check: &str, result: Result<String, String>) -> bool {
+    match &result {
+        Ok(detail) if detail.is_empty() => println!("  {} {}", style("✔").green(), check),
+        Ok(detail) => println!("  {} {}: {}", style("✔").green(), check, detail),
+        Err(e) => println!("  {} {}", style("✘").red(), style(format!("{}: {}", check, e)).red()),
+    }
+
+    result.is_ok()
+}
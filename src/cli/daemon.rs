@@ -0,0 +1,308 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use console::style;
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use jmap_client::TypeState;
+use log::{error, info, warn};
+use opendal::Operator;
+use tokio::{
+    sync::{mpsc, watch},
+    time::{sleep, sleep_until, Instant, Interval, MissedTickBehavior},
+};
+
+use crate::cli::backup::backup;
+use crate::conf::{Backup, Daemon};
+use crate::core::{jmap::JmapConnection, layout, lock, search::Indexer};
+
+/// Ask the server to ping us this often so a dead push connection is noticed
+const PUSH_PING_SECONDS: u32 = 60;
+/// How long to wait before reconnecting a push connection that failed or was closed
+const PUSH_RECONNECT_SECONDS: u64 = 30;
+
+/// Keeps track of when a backup is due after push notifications.
+/// Every notification pushes the deadline out, so a burst of new mail results in a single backup.
+struct Debouncer {
+    window: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Debouncer {
+    fn new(window: Duration) -> Self {
+        Debouncer { window, deadline: None }
+    }
+
+    fn notify(&mut self, now: Instant) {
+        self.deadline = Some(now + self.window);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn clear(&mut self) {
+        self.deadline = None;
+    }
+}
+
+/// What made a backup due
+#[derive(Debug, PartialEq)]
+enum Trigger {
+    Schedule,
+    Push,
+}
+
+/// Waits for the next backup to be due, on schedule or after push notifications settle, until shutting down
+struct Triggers<'a> {
+    schedule: Interval,
+    debouncer: Debouncer,
+    notifications: mpsc::UnboundedReceiver<()>,
+    push: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl<'a> Triggers<'a> {
+    fn new(connection: &'a JmapConnection, conf: &Daemon, push: bool, shutdown: watch::Receiver<bool>) -> Self {
+        let mut schedule = tokio::time::interval(Duration::from_secs(conf.interval.max(1)));
+        schedule.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // The push connection only makes progress while waiting, notifications arriving during a backup are
+        // picked up afterwards
+        let (notify, notifications) = mpsc::unbounded_channel();
+
+        Triggers {
+            schedule,
+            debouncer: Debouncer::new(Duration::from_secs(conf.debounce)),
+            notifications,
+            push: Box::pin(push_changes(connection, notify, push)),
+            shutdown,
+        }
+    }
+
+    /// The next backup to run, None once the daemon is asked to shut down
+    async fn next(&mut self) -> Option<Trigger> {
+        loop {
+            if *self.shutdown.borrow() {
+                return None;
+            }
+
+            let deadline = self.debouncer.deadline();
+            let trigger = tokio::select! {
+                _ = self.shutdown.changed() => {
+                    return None;
+                }
+                _ = self.schedule.tick() => Trigger::Schedule,
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => Trigger::Push,
+                _ = &mut self.push => {
+                    continue;
+                }
+                Some(()) = self.notifications.recv() => {
+                    self.debouncer.notify(Instant::now());
+                    continue;
+                }
+            };
+
+            // Changes seen before the backup starts are covered by this backup
+            self.debouncer.clear();
+            return Some(trigger);
+        }
+    }
+}
+
+/**
+ * Run backups until the process is asked to shut down.
+ * Backups run on a fixed schedule, and additionally shortly after the server pushes
+ * a state change for emails or mailboxes when push is enabled and supported.
+ * The client, operator and indexer are kept alive between backups.
+ */
+pub async fn daemon(
+    connection: &JmapConnection,
+    operator: &Operator,
+    conf: &Daemon,
+    backup_conf: &Backup,
+    mut indexer: Option<Indexer>,
+    break_lock: bool,
+) -> anyhow::Result<()> {
+    // The lock is held for as long as the daemon runs, so backups started from e.g. cron back off
+    let lock = lock::acquire(operator, "daemon", break_lock).await?;
+    // Progress bars make no sense in a long running process, so draw them nowhere
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+
+    let push_supported = !connection.client().await?.session().event_source_url().is_empty();
+    if conf.push && !push_supported {
+        warn!("JMAP server does not support push, falling back to scheduled backups only");
+    }
+
+    let mut triggers = Triggers::new(connection, conf, conf.push && push_supported, shutdown_signal());
+
+    info!(
+        "{} backing up every {} seconds",
+        style("Daemon started").green(),
+        conf.interval
+    );
+
+    // Losing the lock to another process stops the daemon, even in the middle of a backup
+    lock.hold(async {
+        // Creates the format descriptor on the first backup, and refuses archives with an unknown layout
+        layout::open_archive(operator, true).await?;
+
+        while let Some(trigger) = triggers.next().await {
+            match trigger {
+                Trigger::Schedule => info!("Running scheduled backup"),
+                Trigger::Push => info!("Running backup triggered by push notification"),
+            }
+
+            // A failed backup should not bring the daemon down, the next run resumes from the stored progress
+            if let Err(e) = backup(connection, operator, &multi, indexer.as_mut(), backup_conf).await {
+                let err = format!("Error backing up. {}", e);
+                error!("{}", style(err).red().bold());
+            }
+        }
+
+        Ok(())
+    })
+    .await?;
+    info!("{}", style("Daemon stopped").green());
+
+    Ok(())
+}
+
+/**
+ * Subscribe to JMAP push notifications and send a notification for every change to emails or mailboxes.
+ * Failed or closed connections are reconnected with a fresh client, so an OAuth token expiring does not
+ * end push for good. Never completes, and does nothing if push is disabled.
+ */
+async fn push_changes(connection: &JmapConnection, notify: mpsc::UnboundedSender<()>, enabled: bool) {
+    if !enabled {
+        return futures::future::pending().await;
+    }
+
+    loop {
+        let client = match connection.client().await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Could not connect for JMAP push notifications. {}", e);
+                sleep(Duration::from_secs(PUSH_RECONNECT_SECONDS)).await;
+                continue;
+            }
+        };
+
+        match client
+            .event_source(Some([TypeState::Email, TypeState::Mailbox]), false, Some(PUSH_PING_SECONDS), None)
+            .await
+        {
+            Ok(stream) => {
+                info!("Subscribed to JMAP push notifications");
+                tokio::pin!(stream);
+
+                while let Some(change) = stream.next().await {
+                    match change {
+                        Ok(changes) => {
+                            if changes.has_type(TypeState::Email) || changes.has_type(TypeState::Mailbox) {
+                                let _ = notify.send(());
+                            }
+                        }
+                        Err(e) => {
+                            warn!("JMAP push connection failed. {}", e);
+                            break;
+                        }
+                    }
+                }
+                info!("JMAP push connection closed, reconnecting");
+            }
+            Err(e) => warn!("Could not subscribe to JMAP push notifications. {}", e),
+        }
+
+        sleep(Duration::from_secs(PUSH_RECONNECT_SECONDS)).await;
+    }
+}
+
+/**
+ * Listen for Ctrl-C (and SIGTERM on unix) in the background.
+ * The first signal lets the current backup finish before stopping, a second one exits immediately.
+ */
+fn shutdown_signal() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+
+    tokio::spawn(async move {
+        terminate().await;
+        warn!("Shutting down after the current backup. Press Ctrl-C again to stop immediately");
+        let _ = sender.send(true);
+
+        terminate().await;
+        std::process::exit(130);
+    });
+
+    receiver
+}
+
+async fn terminate() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Error installing SIGTERM handler");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::core::jmap::mock::{mock_server, Handler};
+
+    #[test]
+    fn test_debouncer_extends_deadline() {
+        let now = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_secs(30));
+        assert!(debouncer.deadline().is_none());
+
+        debouncer.notify(now);
+        assert_eq!(debouncer.deadline(), Some(now + Duration::from_secs(30)));
+
+        // A second notification pushes the backup further out
+        debouncer.notify(now + Duration::from_secs(10));
+        assert_eq!(debouncer.deadline(), Some(now + Duration::from_secs(40)));
+
+        debouncer.clear();
+        assert!(debouncer.deadline().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_triggers_push_and_shutdown() {
+        let handler: Handler = Arc::new(|path, mut socket| {
+            Box::pin(async move {
+                assert!(path.starts_with("/events?types=Email,Mailbox"));
+                let change = r#"{"@type": "StateChange", "changed": {"A1": {"Email": "S2"}}}"#;
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\nevent: state\ndata: {}\n\n", change);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                // Keep the connection open like a server waiting for the next change
+                futures::future::pending::<()>().await;
+            })
+        });
+        let connection = mock_server(handler).await;
+        let conf = Daemon { interval: 60 * 60, debounce: 0, push: true };
+        let (shutdown, receiver) = watch::channel(false);
+        let mut triggers = Triggers::new(&connection, &conf, true, receiver);
+
+        // The first scheduled backup runs right away, the next one only after the change is pushed
+        assert_eq!(triggers.next().await, Some(Trigger::Schedule));
+        let pushed = tokio::time::timeout(Duration::from_secs(10), triggers.next()).await;
+        assert_eq!(pushed.unwrap(), Some(Trigger::Push));
+
+        shutdown.send(true).unwrap();
+        assert_eq!(triggers.next().await, None);
+    }
+}
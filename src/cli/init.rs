@@ -0,0 +1,307 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use console::style;
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password, Select};
+
+use crate::conf::{default_config_path, keyring_entry, Scheme, AUTH_MODES};
+
+/// Known JMAP providers, with the host and authentication mode they use
+const PRESETS: [(&str, Option<&str>, Option<&str>); 3] = [
+    ("Fastmail", Some("https://api.fastmail.com"), Some("token")),
+    ("Stalwart (self-hosted)", None, Some("basic")),
+    ("Other", None, None),
+];
+
+/// Everything asked for by the wizard, rendered into the config file
+struct Answers {
+    name: String,
+    host: String,
+    auth_mode: String,
+    username: Option<String>,
+    oauth_client_id: Option<String>,
+    scheme: Scheme,
+    storage: Vec<(String, String)>,
+    search_folder: Option<String>,
+}
+
+/**
+ * Ask for the settings of a single profile and write them to a commented config file.
+ * Secrets are stored in the keyring and never written to the file.
+ */
+pub fn init(config: Option<PathBuf>) -> anyhow::Result<()> {
+    let path = config
+        .or_else(default_config_path)
+        .context("Could not find a config directory, use --config to choose where to write the config")?;
+    let theme = ColorfulTheme::default();
+
+    if path.exists() {
+        let overwrite = Confirm::with_theme(&theme)
+            .with_prompt(format!("{} already exists, overwrite it?", path.display()))
+            .default(false)
+            .interact()?;
+
+        if !overwrite {
+            return Ok(());
+        }
+    }
+
+    let name: String = Input::with_theme(&theme)
+        .with_prompt("Profile name, used to name secrets in the keyring")
+        .default("personal".to_string())
+        .interact_text()?;
+
+    let preset = Select::with_theme(&theme)
+        .with_prompt("JMAP provider")
+        .items(&PRESETS.map(|(provider, _, _)| provider))
+        .default(0)
+        .interact()?;
+    let (_, preset_host, preset_auth_mode) = PRESETS[preset];
+
+    let host = match preset_host {
+        Some(host) => host.to_string(),
+        None => Input::with_theme(&theme)
+            .with_prompt("JMAP server URL")
+            .validate_with(|host: &String| match host.starts_with("https://") || host.starts_with("http://") {
+                true => Ok(()),
+                false => Err("Enter a URL like https://mail.example.com"),
+            })
+            .interact_text()?,
+    };
+
+    let auth_mode = match preset_auth_mode {
+        Some(auth_mode) => auth_mode.to_string(),
+        None => {
+            let selected = Select::with_theme(&theme)
+                .with_prompt("Authentication mode")
+                .items(&AUTH_MODES)
+                .default(0)
+                .interact()?;
+            AUTH_MODES[selected].to_string()
+        }
+    };
+
+    let username = match auth_mode.as_str() {
+        "basic" => Some(Input::with_theme(&theme).with_prompt("JMAP username").interact_text()?),
+        _ => None,
+    };
+
+    let oauth_client_id = match auth_mode.as_str() {
+        "oauth" => Some(
+            Input::with_theme(&theme)
+                .with_prompt("OAuth client id registered with the server")
+                .interact_text()?,
+        ),
+        _ => None,
+    };
+
+    match oauth_client_id {
+        Some(_) => println!("{}", style("You will be asked to log in the first time postkasse connects").dim()),
+        None => {
+            println!("{}", style("The JMAP password or token is stored in the keyring").dim());
+            store_secret(&theme, &name, "jmap_secret")?;
+        }
+    }
+
+    let schemes = Scheme::ALL.map(String::from);
+    let selected = Select::with_theme(&theme)
+        .with_prompt("Storage backend")
+        .items(&schemes)
+        .default(Scheme::ALL.iter().position(|scheme| *scheme == Scheme::Fs).unwrap_or(0))
+        .interact()?;
+    let scheme = Scheme::ALL[selected];
+
+    let mut storage = vec![];
+    for key in scheme.required_keys() {
+        let value: String = Input::with_theme(&theme)
+            .with_prompt(format!("Storage {}", key))
+            .interact_text()?;
+        storage.push((key.to_string(), value));
+    }
+
+    if let Some(key) = scheme.identity_key() {
+        let value: String = Input::with_theme(&theme)
+            .with_prompt(format!("Storage {} (leave empty to skip)", key))
+            .allow_empty(true)
+            .interact_text()?;
+        if !value.is_empty() {
+            storage.push((key.to_string(), value));
+        }
+    }
+
+    if let Some(key) = scheme.secret_key() {
+        println!("{}", style(format!("The storage {} is stored in the keyring", key)).dim());
+        store_secret(&theme, &name, &String::from(scheme))?;
+    }
+
+    let search = Confirm::with_theme(&theme)
+        .with_prompt("Index emails for local search?")
+        .default(true)
+        .interact()?;
+
+    let search_folder = match search {
+        true => {
+            let default_folder = dirs::data_dir()
+                .map(|dir| dir.join("postkasse").join(&name).join("search"))
+                .and_then(|dir| dir.to_str().map(String::from))
+                .unwrap_or_default();
+
+            Some(
+                Input::with_theme(&theme)
+                    .with_prompt("Search index folder")
+                    .default(default_folder)
+                    .interact_text()?,
+            )
+        }
+        false => None,
+    };
+
+    let answers = Answers { name, host, auth_mode, username, oauth_client_id, scheme, storage, search_folder };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Error creating {}", dir.display()))?;
+    }
+    std::fs::write(&path, render_config(&answers))
+        .with_context(|| format!("Error writing config file {}", path.display()))?;
+
+    println!("{} {}", style("Wrote").green().bold(), path.display());
+    println!("Run {} to test it", style("postkasse config check").bold());
+
+    Ok(())
+}
+
+/**
+ * Prompt for a secret and store it in the keyring.
+ * A secret already stored for the profile, e.g. when init is run again, is only replaced if the user wants to.
+ */
+fn store_secret(theme: &ColorfulTheme, name: &str, secret_name: &str) -> anyhow::Result<()> {
+    let entry = keyring_entry(name, secret_name)?;
+
+    let exists = match entry.get_password() {
+        Ok(_) => true,
+        Err(keyring::Error::NoEntry) => false,
+        Err(e) => return Err(e).with_context(|| format!("Error reading secret {}_{} from the keyring", name, secret_name)),
+    };
+
+    if exists
+        && !Confirm::with_theme(theme)
+            .with_prompt("A secret is already stored in the keyring, replace it?")
+            .default(false)
+            .interact()?
+    {
+        return Ok(());
+    }
+
+    let secret = Password::with_theme(theme)
+        .with_prompt("Enter your password or token")
+        .with_confirmation("Repeat the secret", "The secrets do not match")
+        .interact()
+        .with_context(|| format!("Error reading secret {} from prompt", secret_name))?;
+
+    entry
+        .set_password(&secret)
+        .with_context(|| format!("Error setting secret for {}_{}", name, secret_name))
+}
+
+fn render_config(answers: &Answers) -> String {
+    let mut toml = String::new();
+
+    toml.push_str("# Written by postkasse init, see the README for every setting\n");
+    toml.push_str("# Secrets are read from the keyring, they can also be set in this file\n\n");
+    toml.push_str("# Name of the profile, used to name secrets in the keyring\n");
+    toml.push_str(&format!("name = {}\n\n", quote(&answers.name)));
+
+    toml.push_str("[jmap]\n");
+    toml.push_str(&format!("host = {}\n", quote(&answers.host)));
+    toml.push_str(&format!("auth_mode = {} # One of {}\n", quote(&answers.auth_mode), AUTH_MODES.join(", ")));
+    if let Some(username) = &answers.username {
+        toml.push_str(&format!("username = {}\n", quote(username)));
+    }
+
+    if let Some(client_id) = &answers.oauth_client_id {
+        toml.push_str("\n[jmap.oauth]\n");
+        toml.push_str(&format!("client_id = {}\n", quote(client_id)));
+        toml.push_str("flow = \"device\" # Or \"pkce\" to log in with a browser on this machine\n");
+        toml.push_str("# scopes = [\"offline_access\"]\n");
+    }
+
+    toml.push_str("\n[storage]\n");
+    toml.push_str(&format!("scheme = {} # See https://opendal.apache.org/ for the settings of each service\n", quote(&String::from(answers.scheme))));
+    toml.push_str("\n[storage.config]\n");
+    for (key, value) in &answers.storage {
+        toml.push_str(&format!("{} = {}\n", key, quote(value)));
+    }
+
+    if let Some(folder) = &answers.search_folder {
+        toml.push_str("\n[search]\n");
+        toml.push_str("enable = true # Index emails during backup for `postkasse search`\n");
+        toml.push_str(&format!("folder = {}\n", quote(folder)));
+        toml.push_str("# languages = [\"no\", \"en\"] # Match other forms of words in these languages, e.g. invoice and invoices\n");
+        toml.push_str("# sync = true # Copy the index to the storage, so it can be restored or searched from other machines\n");
+    }
+
+    toml.push_str("\n# [backup]\n");
+    toml.push_str("# memory_limit_mb = 512 # Upper bound on memory used by messages being downloaded\n");
+    toml.push_str("# split_attachments = false # Store large attachments once, separately from the messages\n");
+
+    toml
+}
+
+/// Quote a value as a TOML basic string
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+    use crate::conf::Conf;
+
+    #[test]
+    fn test_rendered_config_is_valid() {
+        let answers = Answers {
+            name: "work".to_string(),
+            host: "https://jmap.example.com".to_string(),
+            auth_mode: "basic".to_string(),
+            username: Some("jdoe".to_string()),
+            oauth_client_id: Some("postkasse".to_string()),
+            scheme: Scheme::S3,
+            storage: vec![
+                ("bucket".to_string(), "mail \"backups\"".to_string()),
+                ("region".to_string(), "eu-north-1".to_string()),
+            ],
+            search_folder: Some("C:\\postkasse\\search".to_string()),
+        };
+
+        let conf: Conf = Config::builder()
+            .add_source(File::from_str(&render_config(&answers), FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(conf.name, "work");
+        assert_eq!(conf.jmap.username.as_deref(), Some("jdoe"));
+        assert_eq!(conf.jmap.oauth.unwrap().client_id, "postkasse");
+        assert_eq!(conf.storage.scheme, Scheme::S3);
+        assert_eq!(conf.storage.config["bucket"], "mail \"backups\"");
+        assert_eq!(conf.search.unwrap().folder, "C:\\postkasse\\search");
+        assert!(conf.backup.is_none());
+    }
+}
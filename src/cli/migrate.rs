@@ -0,0 +1,54 @@
+use console::style;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info};
+use opendal::Operator;
+
+use crate::core::lock;
+use crate::core::migrate::{migrate, Convert};
+
+pub async fn migrate_archive(
+    source: &Operator,
+    target: &Operator,
+    multi: &MultiProgress,
+    concurrency: usize,
+    verify: bool,
+    convert: Convert,
+    break_lock: bool,
+) -> anyhow::Result<()> {
+    let sty = ProgressStyle::with_template(
+        "{msg:10} {bar:40.cyan/blue} {pos:>7}/{len:7} {elapsed_precise}/{eta_precise} ",
+    )
+    .unwrap()
+    .progress_chars("##-");
+
+    let pb_files = multi.add(ProgressBar::new(0));
+    pb_files.set_style(sty);
+    pb_files.set_message("Files:");
+
+    // Only the target is written to, reading the source while it is backed up is harmless
+    let lock = lock::acquire(target, "migrate", break_lock).await?;
+    let report = lock.hold(migrate(source, target, &pb_files, concurrency, verify, convert)).await?;
+
+    info!(
+        "{} {} files ({} bytes), skipped {} already migrated files",
+        style("Copied").green(),
+        style(report.copied).green(),
+        report.bytes,
+        report.skipped
+    );
+
+    if let Some(version) = report.upgraded_from {
+        info!("{} the copy from layout version {}", style("Upgraded").green(), version);
+    }
+
+    if !report.failed.is_empty() {
+        let err = format!(
+            "Failed to migrate {} files, run the migration again to retry them and to copy the backup progress",
+            report.failed.len()
+        );
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -1,3 +1,11 @@
 pub mod backup;
+pub mod config;
+pub mod daemon;
+pub mod init;
+pub mod migrate;
+pub mod reindex;
 pub mod search;
+pub mod secrets;
+pub mod thread;
+#[allow(clippy::module_inception)]
 pub mod cli;
\ No newline at end of file
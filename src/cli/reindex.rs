@@ -0,0 +1,108 @@
+use console::style;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info};
+use opendal::Operator;
+
+use crate::conf;
+use crate::core::index_sync::push_index;
+use crate::core::lock;
+use crate::core::reindex::{dedupe_index, reindex, ReindexReport};
+
+pub async fn reindex_archive(
+    operator: &Operator,
+    search_conf: &conf::Search,
+    multi: &MultiProgress,
+    concurrency: usize,
+    memory_limit_mb: usize,
+    break_lock: bool,
+) -> anyhow::Result<()> {
+    let sty = ProgressStyle::with_template(
+        "{msg:10} {bar:40.cyan/blue} {pos:>7}/{len:7} {elapsed_precise}/{eta_precise} ",
+    )
+    .unwrap()
+    .progress_chars("##-");
+
+    let pb_emails = multi.add(ProgressBar::new(0));
+    pb_emails.set_style(sty);
+    pb_emails.set_message("Emails:");
+
+    // Backups write to the index too, so they must not run while it is rebuilt
+    let lock = lock::acquire(operator, "reindex", break_lock).await?;
+    let report = lock
+        .hold(async {
+            let report = reindex(operator, search_conf, &pb_emails, concurrency, memory_limit_mb * 1024 * 1024).await?;
+            if search_conf.sync {
+                push_index(operator, &search_conf.folder).await?;
+            }
+            Ok(report)
+        })
+        .await?;
+
+    print_reindex_report(&report);
+
+    Ok(())
+}
+
+fn print_reindex_report(report: &ReindexReport) {
+    info!("{} {} emails", style("Indexed").green(), style(report.indexed).green());
+
+    if !report.without_message.is_empty() {
+        info!(
+            "{} emails were indexed without their text as their message is missing from the archive",
+            report.without_message.len()
+        );
+    }
+
+    if !report.failed.is_empty() {
+        let err = format!("Failed to index {} emails, see the warnings above", report.failed.len());
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+}
+
+pub async fn dedupe_archive_index(
+    operator: &Operator,
+    search_conf: &conf::Search,
+    multi: &MultiProgress,
+    concurrency: usize,
+    memory_limit_mb: usize,
+    break_lock: bool,
+) -> anyhow::Result<()> {
+    let sty = ProgressStyle::with_template("{msg:10} {bar:40.cyan/blue} {pos:>7}/{len:7} {elapsed_precise} ")
+        .unwrap()
+        .progress_chars("##-");
+
+    let pb_emails = multi.add(ProgressBar::new(0));
+    pb_emails.set_style(sty);
+    pb_emails.set_message("Emails:");
+
+    let lock = lock::acquire(operator, "dedupe-index", break_lock).await?;
+    let report = lock
+        .hold(async {
+            let report = dedupe_index(operator, search_conf, &pb_emails, concurrency, memory_limit_mb * 1024 * 1024).await?;
+            if search_conf.sync && (report.deduplicated > 0 || report.rebuilt.is_some()) {
+                push_index(operator, &search_conf.folder).await?;
+            }
+            Ok(report)
+        })
+        .await?;
+
+    if let Some(rebuilt) = &report.rebuilt {
+        info!("{} the search index from the archive as it used an older schema", style("Rebuilt").green());
+        print_reindex_report(rebuilt);
+        return Ok(());
+    }
+
+    info!("{} {} emails indexed more than once", style("Deduplicated").green(), style(report.deduplicated).green());
+
+    if !report.failed.is_empty() {
+        let err = format!(
+            "Could not read {} emails from the archive, they are still indexed more than once. Run `postkasse reindex` to rebuild the index",
+            report.failed.len()
+        );
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
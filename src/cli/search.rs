@@ -1,48 +1,189 @@
 use log::{error, info};
 use console::style;
 use prettytable::{format, Cell, Row, Table};
+use serde_json::{json, Map, Value};
 
+use crate::cli::cli::OutputFormat;
 use crate::conf::Search;
-use crate::core::search::search;
+use crate::core::search::{search, SearchOptions, SearchResult, Snippet, RESULT_FIELDS};
 
-pub fn search_emails(search_conf: Search, query: String, limit: Option<usize>, fields: Option<Vec<String>>) {
+pub fn search_emails(
+    search_conf: Search,
+    query: String,
+    options: SearchOptions,
+    fields: Option<Vec<String>>,
+    output_format: OutputFormat,
+) {
     if !search_conf.enable {
-        let err = format!("Search is not enabled in config");
+        let err = "Search is not enabled in config";
         error!("{}", style(err).red().bold());
         std::process::exit(1);
     } else {
-        let mut table = Table::new();
-        let mut header = Row::empty();
         let fields = fields.unwrap_or_default();
 
-        for field in fields.iter() {
-            header.add_cell(Cell::new(&field));
+        if let Some(unknown) = fields.iter().find(|field| !RESULT_FIELDS.contains(&field.as_str())) {
+            let err = format!("Unknown field {}, use one of {}", unknown, RESULT_FIELDS.join(", "));
+            error!("{}", style(err).red().bold());
+            std::process::exit(1);
         }
 
-        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-        table.set_titles(header);
-
-        
-        let result = search(search_conf.folder, query, limit).unwrap_or_else(|_e| {
-            let err = format!("Could not search index");
+        let result = search(&search_conf, query, &options).unwrap_or_else(|e| {
+            let err = format!("Could not search index. {:#}", e);
             error!("{}", style(err).red().bold());
             std::process::exit(1);
         });
 
         info!("Number of results: {}", result.len());
-        for doc in result {
-            let mut result = Row::empty();
-            for field in fields.iter() {
-                match field.as_str() {
-                    "id" => result.add_cell(Cell::new(&doc.id)),
-                    "blob_id" => result.add_cell(Cell::new(&doc.blob_id)),
-                    "subject" => result.add_cell(Cell::new(&doc.subject)),
-                    _ => (),
+        match output_format {
+            OutputFormat::Table => print_table(&result, &fields),
+            OutputFormat::Json => println!("{}", to_json(&result, &fields, &options)),
+            OutputFormat::Ndjson => {
+                for doc in &result {
+                    println!("{}", Value::Object(json_object(doc, &fields)));
                 }
             }
-            table.add_row(result);
+            OutputFormat::Csv => print!("{}", to_csv(&result, &fields)),
+            OutputFormat::Ids => {
+                for doc in &result {
+                    println!("{}", doc.id);
+                }
+            }
+        }
+
+        // Formats without room for the count report it next to the logs, keeping stdout parseable
+        if matches!(output_format, OutputFormat::Ndjson | OutputFormat::Csv | OutputFormat::Ids) {
+            eprintln!("{}", style(format!("{} results", result.len())).dim());
         }
+    }
+}
+
+fn print_table(result: &[SearchResult], fields: &[String]) {
+    let mut table = Table::new();
+    let mut header = Row::empty();
+
+    for field in fields.iter() {
+        header.add_cell(Cell::new(field));
+    }
+
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    table.set_titles(header);
+
+    for doc in result {
+        let mut row = Row::empty();
+        for field in fields.iter() {
+            row.add_cell(Cell::new(&cell(doc, field)));
+        }
+        table.add_row(row);
+    }
+
+    table.printstd();
+    println!("{}", style(format!("{} results", result.len())).dim());
+}
+
+fn cell(doc: &SearchResult, field: &str) -> String {
+    match field {
+        "snippet" => highlight(&doc.snippet),
+        _ => doc.field(field).unwrap_or_default(),
+    }
+}
+
+/// Snippet on a single line, with the matched words in bold
+fn highlight(snippet: &Snippet) -> String {
+    let mut text = String::new();
+    let mut position = 0;
+
+    for range in &snippet.highlighted {
+        text.push_str(&snippet.text[position..range.start]);
+        text.push_str(&style(&snippet.text[range.clone()]).bold().yellow().to_string());
+        position = range.end;
+    }
+    text.push_str(&snippet.text[position..]);
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn json_object(doc: &SearchResult, fields: &[String]) -> Map<String, Value> {
+    fields
+        .iter()
+        .filter_map(|field| Some((field.clone(), doc.json(field)?)))
+        .collect()
+}
+
+fn to_json(result: &[SearchResult], fields: &[String], options: &SearchOptions) -> Value {
+    json!({
+        "count": result.len(),
+        "offset": options.offset,
+        "results": result.iter().map(|doc| Value::Object(json_object(doc, fields))).collect::<Vec<_>>(),
+    })
+}
+
+fn to_csv(result: &[SearchResult], fields: &[String]) -> String {
+    let mut csv = csv_row(fields.iter().cloned());
+    for doc in result {
+        csv.push_str(&csv_row(fields.iter().map(|field| doc.field(field).unwrap_or_default())));
+    }
+    csv
+}
+
+/// A CSV row as in RFC 4180, quoting values containing separators, quotes or line breaks
+fn csv_row(values: impl Iterator<Item = String>) -> String {
+    let values = values
+        .map(|value| match value.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", value.replace('"', "\"\"")),
+            false => value,
+        })
+        .collect::<Vec<_>>();
+
+    format!("{}\r\n", values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::search::Address;
+
+    use super::*;
+
+    #[test]
+    fn test_output_formats() {
+        let doc = SearchResult {
+            id: "M1".to_string(),
+            blob_id: "B1".to_string(),
+            thread_id: "T1".to_string(),
+            received_at: None,
+            subject: "Invoice, \"final\"".to_string(),
+            from: vec![Address { name: "Alice".to_string(), email: "alice@example.com".to_string() }],
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            mailboxes: vec!["Inbox/Receipts".to_string()],
+            mailbox_ids: vec!["P2".to_string()],
+            keywords: vec![],
+            size: 2048,
+            has_attachment: true,
+            attachments: vec![],
+            score: None,
+            snippet: Snippet { text: "Pay the\ninvoice".to_string(), highlighted: vec![0..3, 8..15] },
+        };
+        let fields = ["id", "subject", "from", "size", "snippet"].map(String::from);
+
+        assert_eq!(
+            to_csv(std::slice::from_ref(&doc), &fields),
+            "id,subject,from,size,snippet\r\nM1,\"Invoice, \"\"final\"\"\",Alice <alice@example.com>,2048,\"Pay the\ninvoice\"\r\n"
+        );
 
-        table.printstd();
+        assert_eq!(
+            to_json(&[doc], &fields, &SearchOptions::default()),
+            json!({
+                "count": 1,
+                "offset": 0,
+                "results": [{
+                    "id": "M1",
+                    "subject": "Invoice, \"final\"",
+                    "from": [{"name": "Alice", "email": "alice@example.com"}],
+                    "size": 2048,
+                    "snippet": "Pay the\ninvoice",
+                }],
+            })
+        );
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,261 @@
+use anyhow::Context;
+use clap::ValueEnum;
+use console::style;
+use dialoguer::Password;
+
+use crate::cli::cli::{SecretKind, SecretsCommands};
+use crate::conf::{keyring_entry, AuthMode, Conf, SecretSource};
+use crate::core::jmap::{create_client, JmapConnection};
+use crate::core::oauth::{TokenSource, REFRESH_TOKEN_SECRET};
+use crate::core::storage::{check_permissions, create_storage_backend};
+
+/**
+ * Manage the secrets of a profile in the keyring, stored under the same names they are read from.
+ * The secrets themselves are never printed.
+ */
+pub async fn secrets(conf: &Conf, command: SecretsCommands) -> anyhow::Result<()> {
+    match command {
+        SecretsCommands::List {} => list(conf),
+        SecretsCommands::Set { kind } => store(conf, kind, false).await,
+        SecretsCommands::Delete { kind } => delete(conf, kind),
+        SecretsCommands::Rotate { kind } => store(conf, kind, true).await,
+    }
+}
+
+fn list(conf: &Conf) -> anyhow::Result<()> {
+    for kind in SecretKind::value_variants() {
+        // Secrets the profile does not use, e.g. the storage secret of Fs
+        let Ok(secret_name) = secret_name(conf, *kind) else {
+            continue;
+        };
+
+        let status = match overridden_by(conf, *kind) {
+            Some(source) => format!("read from {}", source),
+            None => match keyring_entry(&conf.name, &secret_name)?.get_password() {
+                Ok(_) => format!("stored in the keyring as {}_{}", conf.name, secret_name),
+                Err(keyring::Error::NoEntry) => format!("{}", style("not set, asked for the next time it is needed").yellow()),
+                Err(e) => format!("{}", style(format!("error reading the keyring: {}", e)).red()),
+            },
+        };
+
+        println!("{:<8} {}", label(*kind), status);
+    }
+
+    Ok(())
+}
+
+/**
+ * Store a new secret in the keyring. With `verify` the secret is first checked against the JMAP server or
+ * the storage backend, and the stored secret is left untouched if the check fails.
+ */
+async fn store(conf: &Conf, kind: SecretKind, verify: bool) -> anyhow::Result<()> {
+    let secret_name = secret_name(conf, kind)?;
+
+    if let Some(source) = overridden_by(conf, kind) {
+        let warning = format!("The {} secret is read from {}, which takes precedence over the keyring", label(kind), source);
+        println!("{}", style(warning).yellow().bold());
+    }
+
+    let secret = match kind {
+        SecretKind::Oauth => {
+            let oauth = conf
+                .jmap
+                .oauth
+                .as_ref()
+                .context("The oauth auth mode needs a [jmap.oauth] section with at least a client_id")?;
+            let tokens = TokenSource::login(oauth, &conf.jmap.host).await?;
+            let refresh_token = tokens.refresh_token().to_string();
+
+            if verify {
+                JmapConnection::with_tokens(&conf.jmap, tokens)
+                    .await
+                    .with_context(|| format!("Error connecting to {} with the new login", conf.jmap.host))?;
+            }
+
+            refresh_token
+        }
+        SecretKind::Jmap | SecretKind::Storage => {
+            let secret = Password::new()
+                .with_prompt(format!("New {} secret", label(kind)))
+                .with_confirmation("Repeat the secret", "The secrets do not match")
+                .interact()
+                .with_context(|| format!("Error reading secret {} from prompt", secret_name))?;
+
+            if verify {
+                check(conf, kind, &secret).await?;
+            }
+
+            secret
+        }
+    };
+
+    keyring_entry(&conf.name, &secret_name)?
+        .set_password(&secret)
+        .with_context(|| format!("Error setting secret for {}_{}", conf.name, secret_name))?;
+
+    println!("{} {} secret of {} in the keyring", style("Stored").green().bold(), label(kind), conf.name);
+
+    Ok(())
+}
+
+/// Check that a new secret works before it replaces the stored one
+async fn check(conf: &Conf, kind: SecretKind, secret: &str) -> anyhow::Result<()> {
+    match kind {
+        SecretKind::Jmap => {
+            let mut jmap_conf = conf.jmap.clone();
+            jmap_conf.secret = Some(secret.to_string());
+
+            create_client(&jmap_conf)
+                .await
+                .with_context(|| format!("Error connecting to {} with the new secret", conf.jmap.host))?;
+        }
+        SecretKind::Storage => {
+            let mut config = conf.storage.config.clone();
+            if let Some(secret_key) = conf.storage.scheme.secret_key() {
+                config.insert(secret_key.to_string(), secret.to_string());
+            }
+
+            let operator = create_storage_backend(conf.storage.scheme.into(), config)?;
+            for (operation, result) in check_permissions(&operator).await {
+                result.with_context(|| format!("Error checking storage {} permission with the new secret", operation))?;
+            }
+        }
+        SecretKind::Oauth => {} // Checked by connecting with the new login
+    }
+
+    Ok(())
+}
+
+fn delete(conf: &Conf, kind: SecretKind) -> anyhow::Result<()> {
+    let secret_name = secret_name(conf, kind)?;
+
+    match keyring_entry(&conf.name, &secret_name)?.delete_password() {
+        Ok(()) => println!("{} {} secret of {} from the keyring", style("Deleted").green().bold(), label(kind), conf.name),
+        Err(keyring::Error::NoEntry) => println!("No {} secret of {} in the keyring", label(kind), conf.name),
+        Err(e) => return Err(e).with_context(|| format!("Error deleting secret {}_{}", conf.name, secret_name)),
+    }
+
+    Ok(())
+}
+
+/// Name the secret is stored under in the keyring, prefixed by the profile name. Errors if the profile does not use it
+fn secret_name(conf: &Conf, kind: SecretKind) -> anyhow::Result<String> {
+    let oauth = conf.jmap.auth_mode == AuthMode::OAuth;
+
+    match kind {
+        SecretKind::Jmap if oauth => {
+            anyhow::bail!("Profile {} logs in with OAuth, its secret is the oauth refresh token", conf.name)
+        }
+        SecretKind::Jmap => Ok("jmap_secret".to_string()),
+        SecretKind::Oauth if !oauth => anyhow::bail!("Profile {} does not use the oauth auth mode", conf.name),
+        SecretKind::Oauth => Ok(REFRESH_TOKEN_SECRET.to_string()),
+        SecretKind::Storage => match conf.storage.scheme.secret_key() {
+            Some(_) => Ok(String::from(conf.storage.scheme)),
+            None => anyhow::bail!("Storage scheme {:?} has no secret", conf.storage.scheme),
+        },
+    }
+}
+
+/// Where the config says to read the secret from instead of the keyring, if anywhere
+fn overridden_by(conf: &Conf, kind: SecretKind) -> Option<String> {
+    match kind {
+        SecretKind::Jmap => describe_source(
+            conf.jmap.secret.as_ref().map(|_| "jmap.secret".to_string()),
+            &conf.jmap.secret_source,
+        ),
+        SecretKind::Storage => describe_source(
+            conf.storage
+                .scheme
+                .secret_key()
+                .filter(|key| conf.storage.config.contains_key(*key))
+                .map(|key| format!("storage.config.{}", key)),
+            &conf.storage.secret_source,
+        ),
+        SecretKind::Oauth => None,
+    }
+}
+
+/// Describe the secret source with the highest precedence, see `resolve_secret` in the config
+fn describe_source(plain: Option<String>, source: &SecretSource) -> Option<String> {
+    if let Some(setting) = plain {
+        Some(format!("{} in the config", setting))
+    } else if let Some(var) = &source.secret_env {
+        Some(format!("environment variable {}", var))
+    } else if let Some(path) = &source.secret_file {
+        Some(format!("file {}", path.display()))
+    } else {
+        source.secret_command.as_ref().map(|command| format!("command `{}`", command))
+    }
+}
+
+fn label(kind: SecretKind) -> String {
+    kind.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+
+    fn conf_from_str(toml: &str) -> Conf {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_secret_names_and_sources() {
+        let conf = conf_from_str(
+            r#"
+name = "work"
+
+[jmap]
+host = "https://jmap.example.com"
+auth_mode = "token"
+secret_env = "JMAP_TOKEN"
+secret_command = "pass show jmap"
+
+[storage]
+scheme = "S3"
+
+[storage.config]
+bucket = "mail"
+"#,
+        );
+
+        // The names must match those the secrets are read from when backing up
+        assert_eq!(secret_name(&conf, SecretKind::Jmap).unwrap(), "jmap_secret");
+        assert_eq!(secret_name(&conf, SecretKind::Storage).unwrap(), "S3");
+        assert!(secret_name(&conf, SecretKind::Oauth).is_err());
+
+        assert_eq!(overridden_by(&conf, SecretKind::Jmap).as_deref(), Some("environment variable JMAP_TOKEN"));
+        assert_eq!(overridden_by(&conf, SecretKind::Storage), None);
+
+        let conf = conf_from_str(
+            r#"
+name = "nas"
+
+[jmap]
+host = "https://mail.example.com"
+auth_mode = "oauth"
+
+[jmap.oauth]
+client_id = "postkasse"
+
+[storage]
+scheme = "Webdav"
+
+[storage.config]
+password = "hunter2"
+"#,
+        );
+
+        assert!(secret_name(&conf, SecretKind::Jmap).is_err());
+        assert_eq!(secret_name(&conf, SecretKind::Oauth).unwrap(), "jmap_oauth_refresh_token");
+        assert_eq!(overridden_by(&conf, SecretKind::Storage).as_deref(), Some("storage.config.password in the config"));
+    }
+}
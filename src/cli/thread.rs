@@ -0,0 +1,61 @@
+use console::style;
+use log::{error, warn};
+use mail_parser::MessageParser;
+use opendal::Operator;
+
+use crate::core::{blob, threads::read_conversation};
+
+/**
+ * Print a conversation from the archive, oldest email first, with the text body of every email
+ */
+pub async fn show_thread(operator: &Operator, id: &str) {
+    let emails = read_conversation(operator, id).await.unwrap_or_else(|e| {
+        let err = format!("Error reading thread {}. {}", id, e);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    });
+
+    let message_parser = MessageParser::default();
+
+    for email in emails {
+        let from = email
+            .from()
+            .unwrap_or_default()
+            .iter()
+            .map(|address| match address.name() {
+                Some(name) => format!("{} <{}>", name, address.email()),
+                None => address.email().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let date = email
+            .received_at()
+            .and_then(|date| chrono::DateTime::from_timestamp(date, 0))
+            .map(|date| date.to_rfc2822())
+            .unwrap_or_default();
+
+        println!("{}", style(format!("From:    {}", from)).bold());
+        println!("Date:    {}", date);
+        println!("Subject: {}", email.subject().unwrap_or_default());
+        println!("Id:      {}", email.id().unwrap_or_default());
+        println!();
+
+        let body = match email.blob_id() {
+            Some(blob_id) => match blob::read_message(operator, blob_id).await {
+                Ok(raw) => message_parser
+                    .parse(&raw)
+                    .and_then(|message| message.body_text(0).map(|text| text.into_owned())),
+                Err(e) => {
+                    warn!("Error reading message of email {}. {}", email.id().unwrap_or_default(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        println!("{}", body.unwrap_or_default().trim_end());
+        println!();
+        println!("{}", style("-".repeat(72)).dim());
+        println!();
+    }
+}
@@ -4,6 +4,7 @@ use dialoguer::Password;
 use keyring::Entry;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use console::style;
 use log::{info, warn};
@@ -50,10 +51,90 @@ impl From<Scheme> for opendal::Scheme {
     }
 }
 
-impl Into<String> for Scheme {
-    fn into(self) -> String {
+impl Scheme {
+    pub const ALL: [Scheme; 14] = [
+        Scheme::Azblob,
+        Scheme::Azdls,
+        Scheme::Cos,
+        Scheme::Fs,
+        Scheme::Ftp,
+        Scheme::Gcs,
+        Scheme::Hdfs,
+        Scheme::Obs,
+        Scheme::Onedrive,
+        Scheme::Oss,
+        Scheme::S3,
+        Scheme::Sftp,
+        Scheme::Webdav,
+        Scheme::Webhdfs,
+    ];
+
+    /// Storage config keys the backend cannot work without
+    pub fn required_keys(&self) -> &'static [&'static str] {
+        match self {
+            Scheme::Azblob => &["container", "endpoint"],
+            Scheme::Azdls => &["filesystem", "endpoint"],
+            Scheme::Cos | Scheme::Obs | Scheme::Oss => &["bucket", "endpoint"],
+            Scheme::Fs => &["root"],
+            Scheme::Ftp | Scheme::Sftp | Scheme::Webdav => &["endpoint"],
+            Scheme::Gcs => &["bucket"],
+            Scheme::Hdfs => &["name_node"],
+            Scheme::Onedrive | Scheme::Webhdfs => &[],
+            Scheme::S3 => &["bucket", "region"],
+        }
+    }
+
+    /// Storage config keys the backend understands besides the required ones and the secret
+    pub fn optional_keys(&self) -> &'static [&'static str] {
+        match self {
+            Scheme::Azblob => &["root", "account_name", "encryption_key", "encryption_key_sha256", "encryption_algorithm", "sas_token", "batch_max_operations"],
+            Scheme::Azdls => &["root", "account_name"],
+            Scheme::Cos => &["root", "secret_id"],
+            Scheme::Fs => &["atomic_write_dir"],
+            Scheme::Ftp => &["root", "user"],
+            Scheme::Gcs => &["root", "endpoint", "scope", "service_account", "credential", "credential_path", "predefined_acl", "default_storage_class"],
+            Scheme::Hdfs => &["root", "kerberos_ticket_cache_path", "user", "enable_append", "atomic_write_dir"],
+            Scheme::Obs => &["root", "access_key_id"],
+            Scheme::Onedrive => &["root"],
+            Scheme::Oss => &["root", "presign_endpoint", "access_key_id", "server_side_encryption", "server_side_encryption_key_id", "batch_max_operations", "allow_anonymous"],
+            Scheme::S3 => &["root", "endpoint", "access_key_id", "security_token", "role_arn", "external_id", "disable_config_load", "disable_ec2_metadata", "allow_anonymous", "server_side_encryption", "server_side_encryption_aws_kms_key_id", "server_side_encryption_customer_algorithm", "server_side_encryption_customer_key", "server_side_encryption_customer_key_md5", "default_storage_class", "enable_virtual_host_style", "batch_max_operations", "disable_stat_with_override"],
+            Scheme::Sftp => &["root", "user", "key", "known_hosts_strategy", "enable_copy"],
+            Scheme::Webdav => &["root", "username", "token"],
+            Scheme::Webhdfs => &["root", "endpoint", "delegation", "disable_list_batch", "atomic_write_dir"],
+        }
+    }
+
+    /// Storage config key naming the account the secret belongs to, if the scheme has one
+    pub fn identity_key(&self) -> Option<&'static str> {
+        match self {
+            Scheme::S3 | Scheme::Obs | Scheme::Oss => Some("access_key_id"),
+            Scheme::Azblob | Scheme::Azdls => Some("account_name"),
+            Scheme::Cos => Some("secret_id"),
+            Scheme::Ftp | Scheme::Sftp => Some("user"),
+            Scheme::Webdav => Some("username"),
+            _ => None,
+        }
+    }
+
+    /// Storage config key holding the secret, read from the keyring when it is not in the config
+    pub fn secret_key(&self) -> Option<&'static str> {
+        match self {
+            Scheme::S3 | Scheme::Obs => Some("secret_access_key"),
+            Scheme::Azblob | Scheme::Azdls => Some("account_key"),
+            Scheme::Cos => Some("secret_key"),
+            Scheme::Oss => Some("access_key_secret"),
+            Scheme::Ftp | Scheme::Webdav => Some("password"),
+            Scheme::Onedrive => Some("access_token"),
+            // Sftp authenticates with an ssh key, Gcs with a credential file, the others need no secret
+            _ => None,
+        }
+    }
+}
+
+impl From<Scheme> for String {
+    fn from(val: Scheme) -> Self {
         // Use debug trait to format
-        format!("{:?}", self)
+        format!("{:?}", val)
     }
 }
 
@@ -64,9 +145,14 @@ pub struct Conf {
     pub jmap: Jmap,
     pub storage: Storage,
     pub search: Option<Search>,
+    pub daemon: Option<Daemon>,
+    pub backup: Option<Backup>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Values accepted for `jmap.auth_mode`
+pub const AUTH_MODES: [&str; 3] = ["token", "basic", "oauth"];
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
 #[serde(rename_all(deserialize = "lowercase"))]
 pub enum AuthMode {
     /// Use a token for authentication,
@@ -74,15 +160,50 @@ pub enum AuthMode {
     Token,
     /// Use basic authentication (username:password)
     Basic,
+    /// Log in with OAuth 2.0, the refresh token is kept in the keyring
+    OAuth,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Jmap {
     pub host: String,
     pub auth_mode: AuthMode,
     pub username: Option<String>,
     pub secret: Option<String>, // Can be None if user does not want to store secret in config
+    #[serde(flatten)]
+    pub secret_source: SecretSource,
+    /// Required when auth_mode is oauth
+    pub oauth: Option<OAuth>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuth {
+    pub client_id: String,
+    /// Authorization server, its endpoints are discovered from its metadata. Defaults to the JMAP host
+    pub issuer: Option<String>,
+    /// Endpoints override the discovered ones, for servers without metadata
+    pub authorization_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    /// Scopes to request, the server decides when none are given
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub flow: OAuthFlow,
+    /// Local port receiving the redirect of the pkce flow, any free port when 0
+    #[serde(default)]
+    pub redirect_port: u16,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum OAuthFlow {
+    /// Device authorization grant, log in on any device with a code shown in the terminal
+    #[default]
+    Device,
+    /// Authorization code grant with PKCE, log in with a browser on this machine
+    Pkce,
 }
 
 
@@ -91,6 +212,20 @@ pub struct Jmap {
 pub struct Storage {
     pub scheme: Scheme,
     pub config: HashMap<String, String>,
+    /// Where to read the secret of the scheme from when it is not in `config`
+    #[serde(flatten)]
+    pub secret_source: SecretSource,
+}
+
+/// Alternatives to the keyring for reading a secret, for containers and headless servers
+#[derive(Debug, Deserialize, Default, PartialEq, Clone)]
+pub struct SecretSource {
+    /// Name of an environment variable holding the secret
+    pub secret_env: Option<String>,
+    /// File holding the secret, e.g. a docker or systemd credential
+    pub secret_file: Option<PathBuf>,
+    /// Shell command printing the secret, e.g. `pass show fastmail`
+    pub secret_command: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -98,97 +233,579 @@ pub struct Storage {
 pub struct Search {
     pub enable: bool,
     pub folder: String,
+    /// ISO 639-1 codes of the languages to stem words in, e.g. ["no", "en"]
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Copy the index to the storage after every commit, and pull it from there before searching
+    #[serde(default)]
+    pub sync: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Backup {
+    /// Upper bound in megabytes on memory used by message blobs being downloaded at the same time, or read by reindex
+    #[serde(default = "default_backup_memory_limit_mb")]
+    pub memory_limit_mb: usize,
+    /// Store large attachments once by content hash, separately from the message
+    #[serde(default)]
+    pub split_attachments: bool,
+    /// Attachments of at least this many kilobytes, still transfer encoded, are split out
+    #[serde(default = "default_backup_attachment_threshold_kb")]
+    pub attachment_threshold_kb: usize,
+    /// JMAP Email properties stored in /emails/, including header properties like `header:List-Id:asText`
+    #[serde(default = "default_backup_properties")]
+    pub properties: Vec<String>,
+}
+
+fn default_backup_memory_limit_mb() -> usize {
+    512
+}
+
+fn default_backup_attachment_threshold_kb() -> usize {
+    256
+}
+
+/// Every metadata property of an email. Body values are left out as the message itself is in the blob
+fn default_backup_properties() -> Vec<String> {
+    [
+        "id",
+        "blobId",
+        "threadId",
+        "mailboxIds",
+        "keywords",
+        "size",
+        "receivedAt",
+        "messageId",
+        "inReplyTo",
+        "references",
+        "sender",
+        "from",
+        "to",
+        "cc",
+        "bcc",
+        "replyTo",
+        "subject",
+        "sentAt",
+        "hasAttachment",
+        "preview",
+        "bodyStructure",
+        "header:List-Id:asText",
+        "header:List-Unsubscribe:asURLs",
+    ]
+    .iter()
+    .map(|property| property.to_string())
+    .collect()
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Backup {
+            memory_limit_mb: default_backup_memory_limit_mb(),
+            split_attachments: false,
+            attachment_threshold_kb: default_backup_attachment_threshold_kb(),
+            properties: default_backup_properties(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Daemon {
+    /// Seconds between scheduled backups, regardless of push notifications
+    #[serde(default = "default_daemon_interval")]
+    pub interval: u64,
+    /// Seconds to wait for the server to go quiet after a push notification before backing up
+    #[serde(default = "default_daemon_debounce")]
+    pub debounce: u64,
+    /// Subscribe to JMAP push (EventSource) to trigger backups when mail arrives
+    #[serde(default = "default_daemon_push")]
+    pub push: bool,
+}
+
+fn default_daemon_interval() -> u64 {
+    60 * 60 // Once an hour
+}
+
+fn default_daemon_debounce() -> u64 {
+    30
+}
+
+fn default_daemon_push() -> bool {
+    true
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Daemon {
+            interval: default_daemon_interval(),
+            debounce: default_daemon_debounce(),
+            push: default_daemon_push(),
+        }
+    }
 }
 
 impl Conf {
     // Read the secret from the config map, depending on the scheme
     pub fn set_storage_secret(&mut self) -> anyhow::Result<()> {
+        self.resolve_storage_secret(true)
+    }
 
-        info!("Setting secret for {:?}", self.storage.scheme);
-        
-        let secret_from_config = match self.storage.scheme {
-            Scheme::S3 => Some(self.storage.config.get("secret_access_key").unwrap().to_string()),
-            Scheme::Azblob | Scheme::Azdls => Some(self.storage.config.get("account_key").unwrap().to_string()),
-            Scheme::Cos => Some(self.storage.config.get("secret_key").unwrap().to_string()),
-            Scheme::Sftp | Scheme::Webdav => Some(self.storage.config.get("password").unwrap().to_string()),
-            _ => return Ok(()), // No secret needed for e.g. Fs, return early
-        };
+    pub fn set_jmap_secret(&mut self) -> anyhow::Result<()> {
+        self.resolve_jmap_secret(true)
+    }
 
-        if secret_from_config.is_some() { // If we have a secret in the config, no need to prompt
-            // Warn user that storing secrets in config is not recommended
-            let err = format!("Storing secrets in config is not recommended. Consider using keyring instead");
-            warn!("{}", style(err).yellow().bold());
-            return Ok(())
-        }
+    /// Like `set_storage_secret`, but a secret missing from the keyring is an error instead of being prompted for
+    pub fn read_storage_secret(&mut self) -> anyhow::Result<()> {
+        self.resolve_storage_secret(false)
+    }
+
+    /// Like `set_jmap_secret`, but a secret missing from the keyring is an error instead of being prompted for
+    pub fn read_jmap_secret(&mut self) -> anyhow::Result<()> {
+        self.resolve_jmap_secret(false)
+    }
 
-        let scheme: String = self.storage.scheme.try_into()?;
+    fn resolve_storage_secret(&mut self, prompt: bool) -> anyhow::Result<()> {
 
-        let secret_from_keyring = secret_from_keyring_or_prompt(&self.name, &scheme).with_context(|| {
-            format!("Error getting secret from keyring or prompt")
-        })?;
+        info!("Setting secret for {:?}", self.storage.scheme);
+
+        let Some(secret_key) = self.storage.scheme.secret_key() else {
+            return Ok(()); // No secret needed for e.g. Fs, return early
+        };
+
+        let scheme: String = self.storage.scheme.into();
+        let secret = resolve_secret(
+            self.storage.config.get(secret_key).cloned(),
+            &self.storage.secret_source,
+            &self.name,
+            &scheme,
+            prompt,
+        )?;
 
         // Set the secret in the config map
-        self.storage.config.insert(self.storage.scheme.into(), secret_from_keyring);
+        self.storage.config.insert(secret_key.to_string(), secret);
 
-        return Ok(())
+        Ok(())
     }
 
-    pub fn set_jmap_secret(&mut self) -> anyhow::Result<()> {
-        if self.jmap.secret.is_some() { // If we have a secret in the config, no need to prompt
-            let err = format!("Storing secrets in plaintext in config is not recommended. Consider using keyring instead");
-            warn!("{}", style(err).yellow().bold());
-            return Ok(())
+    fn resolve_jmap_secret(&mut self, prompt: bool) -> anyhow::Result<()> {
+        if self.jmap.auth_mode == AuthMode::OAuth {
+            return Ok(()); // Tokens are handled when connecting, logging in if needed
         }
 
-        let secret_from_keyring = secret_from_keyring_or_prompt(&self.name, "jmap_secret").with_context(|| {
-            format!("Error getting secret from keyring or prompt")
-        })?;
+        let secret = resolve_secret(
+            self.jmap.secret.take(),
+            &self.jmap.secret_source,
+            &self.name,
+            "jmap_secret",
+            prompt,
+        )?;
 
         // Set the secret in the config map
-        self.jmap.secret = Some(secret_from_keyring);
+        self.jmap.secret = Some(secret);
 
-        return Ok(())
+        Ok(())
     }
 }
 
+/**
+ * Find a secret, trying each place it can be configured in order of precedence:
+ *
+ * 1. The secret itself in the config, e.g. `jmap.secret`, which is not recommended
+ * 2. `secret_env`, an environment variable holding the secret
+ * 3. `secret_file`, a file holding the secret
+ * 4. `secret_command`, a command printing the secret
+ * 5. The OS keyring, prompting for the secret and storing it there if it is missing and `prompt` is set
+ *
+ * The first place configured is used, a failure there is an error rather than a reason to try the next.
+ */
+fn resolve_secret(
+    plain: Option<String>,
+    source: &SecretSource,
+    name: &str,
+    secret_name: &str,
+    prompt: bool,
+) -> anyhow::Result<String> {
+    if let Some(secret) = plain {
+        let err = "Storing secrets in plaintext in config is not recommended. Consider using keyring instead";
+        warn!("{}", style(err).yellow().bold());
+        return Ok(secret);
+    }
+
+    if let Some(secret) = secret_from_source(source, |var| std::env::var(var))? {
+        return Ok(secret);
+    }
+
+    if !prompt {
+        return match keyring_entry(name, secret_name)?.get_password() {
+            Ok(secret) => Ok(secret),
+            Err(keyring::Error::NoEntry) => anyhow::bail!(
+                "No secret {}_{} in the keyring, store it with `postkasse secrets set`",
+                name,
+                secret_name
+            ),
+            Err(e) => Err(e).with_context(|| format!("Error reading secret {}_{} from keyring", name, secret_name)),
+        };
+    }
+
+    secret_from_keyring_or_prompt(name, secret_name).with_context(|| {
+        "Error getting secret from keyring or prompt"
+    })
+}
+
+/**
+ * Read the secret from the env, file or command source with the highest precedence, None if none is configured.
+ * Environment variables are looked up with `env`, so tests do not have to change the environment of the process.
+ */
+fn secret_from_source(
+    source: &SecretSource,
+    env: impl Fn(&str) -> Result<String, std::env::VarError>,
+) -> anyhow::Result<Option<String>> {
+    let secret = if let Some(var) = &source.secret_env {
+        env(var).with_context(|| format!("Error reading secret from environment variable {}", var))?
+    } else if let Some(path) = &source.secret_file {
+        std::fs::read_to_string(path).with_context(|| format!("Error reading secret from file {}", path.display()))?
+    } else if let Some(command) = &source.secret_command {
+        secret_from_command(command)?
+    } else {
+        return Ok(None);
+    };
+
+    // Files and command output usually end with a newline that is not part of the secret
+    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+    if secret.is_empty() {
+        anyhow::bail!("Secret source {:?} gave an empty secret", source);
+    }
+
+    Ok(Some(secret))
+}
+
+fn secret_from_command(command: &str) -> anyhow::Result<String> {
+    let output = if cfg!(windows) {
+        std::process::Command::new("cmd").args(["/C", command]).output()
+    } else {
+        std::process::Command::new("sh").args(["-c", command]).output()
+    }
+    .with_context(|| format!("Error running secret command {}", command))?;
 
+    if !output.status.success() {
+        anyhow::bail!(
+            "Secret command {} failed with {}. {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
 
+    String::from_utf8(output.stdout).with_context(|| format!("Secret command {} printed invalid UTF-8", command))
+}
 
 impl Conf {
+    /**
+     * Read the configuration for the profile selected on the command line.
+     * If the config has no `[profiles.<name>]` tables the whole file is a single profile.
+     * If it has exactly one profile that profile is used when no name is given.
+     */
     pub fn new(cli: &Cli) -> anyhow::Result<Self> {
+        let config = read_config(cli)?;
+        let mut confs = select_profiles(&config, cli.name.as_deref(), false)?;
+
+        Ok(confs.remove(0))
+    }
+
+    /// Read the configuration of a named profile, e.g. the target of a migration
+    pub fn profile(cli: &Cli, name: &str) -> anyhow::Result<Self> {
+        let config = read_config(cli)?;
+        let mut confs = select_profiles(&config, Some(name), false)?;
+
+        Ok(confs.remove(0))
+    }
 
-        let path = match cli.config.as_ref().map(|path| path.to_str()).flatten() {
-            Some(path) => path,
-            None => "postkasse.toml",
+    /// Read the configuration of every profile in the config file, used by `--all`
+    pub fn all(cli: &Cli) -> anyhow::Result<Vec<Self>> {
+        let config = read_config(cli)?;
+        select_profiles(&config, None, true)
+    }
+
+    /**
+     * Validate the profile selected on the command line, or every profile with `--all` or when none is selected.
+     * Unlike `Conf::new` this does not stop at the first error, every problem found is reported.
+     */
+    pub fn check(cli: &Cli) -> anyhow::Result<Vec<ProfileCheck>> {
+        let config = read_config(cli)?;
+        check_profiles(&config, cli.name.as_deref())
+    }
+}
+
+/// Result of validating a single profile, the profile is only deserialized if it has no problems
+pub struct ProfileCheck {
+    pub name: String,
+    pub problems: Vec<String>,
+    pub conf: Option<Conf>,
+}
+
+/// The config file written by `postkasse init`, e.g. ~/.config/postkasse/postkasse.toml on Linux
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("postkasse").join("postkasse.toml"))
+}
+
+fn read_config(cli: &Cli) -> anyhow::Result<Config> {
+    // A postkasse.toml in the working directory takes precedence over the one in the config directory
+    let path = match &cli.config {
+        Some(path) => path.clone(),
+        None if Path::new("postkasse.toml").exists() => PathBuf::from("postkasse.toml"),
+        None => default_config_path().unwrap_or_else(|| PathBuf::from("postkasse.toml")),
+    };
+    let path = path.to_str().with_context(|| format!("Config path {} is not valid UTF-8", path.display()))?;
+
+    let config = Config::builder()
+        .add_source(File::with_name("dev.toml").required(false)) // Read dev config file if it exists
+        .add_source(Environment::with_prefix("POSTKASSE").separator("__")) // Read any env vars with prefix POSTKASSE__
+        .add_source(File::with_name(path).required(false)) // Read config file if it exists 
+        .build()?;
+
+    Ok(config)
+}
+
+/**
+ * Pick the profiles to operate on from the raw config.
+ * Settings at the top level of the file act as shared defaults for every profile,
+ * and each `[profiles.<name>]` table is deep merged on top of them.
+ */
+fn select_profiles(config: &Config, name: Option<&str>, all: bool) -> anyhow::Result<Vec<Conf>> {
+    let mut profiles: Vec<String> = config
+        .get_table("profiles")
+        .map(|profiles| profiles.into_keys().collect())
+        .unwrap_or_default();
+    profiles.sort();
+
+    if profiles.is_empty() {
+        // Single profile config, the name is given at the top level
+        let conf: Conf = match config.clone().try_deserialize() {
+            Ok(conf) => conf,
+            Err(e) => anyhow::bail!(e),
         };
 
-        let conf_builder = Config::builder()
-            .add_source(File::with_name("dev.toml").required(false)) // Read dev config file if it exists
-            .add_source(Environment::with_prefix("POSTKASSE").separator("__")) // Read any env vars with prefix POSTKASSE__
-            .add_source(File::with_name(path).required(false)) // Read config file if it exists 
-            .build()?;
+        if let Some(name) = name {
+            if name != conf.name {
+                anyhow::bail!("Profile {} not found, the config only contains {}", name, conf.name);
+            }
+        }
+
+        return Ok(vec![conf]);
+    }
+
+    let selected = match (name, all) {
+        (_, true) => profiles.clone(),
+        (Some(name), false) => vec![name.to_lowercase()],
+        (None, false) if profiles.len() == 1 => profiles.clone(),
+        (None, false) => anyhow::bail!(
+            "Config contains several profiles, select one with `postkasse <name> <command>` or use --all. Available profiles: {}",
+            profiles.join(", ")
+        ),
+    };
 
-        match conf_builder.try_deserialize() {
-            Ok(conf) => return Ok(conf),
-            Err(e) => {
-                anyhow::bail!(e)
+    selected
+        .iter()
+        .map(|name| profile_conf(config, name, &profiles))
+        .collect()
+}
+
+fn profile_conf(config: &Config, name: &str, profiles: &[String]) -> anyhow::Result<Conf> {
+    profile_config(config, name, profiles)?
+        .try_deserialize()
+        .with_context(|| format!("Error reading profile {}", name))
+}
+
+fn profile_config(config: &Config, name: &str, profiles: &[String]) -> anyhow::Result<Config> {
+    // Config keys are case insensitive and stored in lower case. The name names the keyring entries of the
+    // profile, so it is lower cased too, whichever way it was typed on the command line
+    let name = name.to_lowercase();
+    let profile = config
+        .get_table(&format!("profiles.{}", name))
+        .map_err(|_| anyhow::anyhow!("Profile {} not found. Available profiles: {}", name, profiles.join(", ")))?;
+
+    let mut builder = Config::builder().add_source(config.clone());
+    for (key, value) in profile {
+        builder = builder.set_override(key, value)?; // Tables are deep merged with the shared defaults
+    }
+
+    Ok(builder.set_override("name", name.as_str())?.build()?)
+}
+
+fn check_profiles(config: &Config, name: Option<&str>) -> anyhow::Result<Vec<ProfileCheck>> {
+    let mut profiles: Vec<String> = config
+        .get_table("profiles")
+        .map(|profiles| profiles.into_keys().collect())
+        .unwrap_or_default();
+    profiles.sort();
+
+    if profiles.is_empty() {
+        let name = config.get_string("name").unwrap_or_default();
+        return Ok(vec![check_profile(name, config.clone())]);
+    }
+
+    let selected = match name {
+        Some(name) => vec![name.to_lowercase()],
+        None => profiles.clone(),
+    };
+
+    selected
+        .into_iter()
+        .map(|name| {
+            let config = profile_config(config, &name, &profiles)?;
+            Ok(check_profile(name, config))
+        })
+        .collect()
+}
+
+fn check_profile(name: String, config: Config) -> ProfileCheck {
+    let mut problems = vec![];
+
+    if name.is_empty() {
+        problems.push("name: missing, every profile needs a name".to_string());
+    }
+
+    match config.get_string("jmap.host") {
+        Ok(host) if !host.starts_with("https://") && !host.starts_with("http://") => {
+            problems.push(format!("jmap.host: {} is not a URL, e.g. https://api.fastmail.com", host));
+        }
+        Ok(_) => {}
+        Err(_) => problems.push("jmap.host: missing, e.g. https://api.fastmail.com".to_string()),
+    }
+
+    match config.get_string("jmap.auth_mode") {
+        Ok(mode) if !AUTH_MODES.contains(&mode.as_str()) => {
+            problems.push(unknown_value("jmap.auth_mode", &mode, &AUTH_MODES));
+        }
+        Ok(mode) if mode == "basic" && config.get_string("jmap.username").is_err() => {
+            problems.push("jmap.username: missing, basic authentication needs a username".to_string());
+        }
+        Ok(mode) if mode == "oauth" && config.get_string("jmap.oauth.client_id").is_err() => {
+            problems.push("jmap.oauth.client_id: missing, oauth needs the client id registered with the server".to_string());
+        }
+        Ok(_) => {}
+        Err(_) => problems.push(format!("jmap.auth_mode: missing, expected one of {}", AUTH_MODES.join(", "))),
+    }
+
+    let schemes = Scheme::ALL.map(String::from);
+    let schemes = schemes.iter().map(String::as_str).collect::<Vec<_>>();
+
+    match config.get_string("storage.scheme") {
+        Ok(name) => match Scheme::ALL.iter().find(|scheme| String::from(**scheme) == name) {
+            Some(scheme) => {
+                let storage_config = config
+                    .get::<HashMap<String, String>>("storage.config")
+                    .unwrap_or_default();
+                problems.extend(check_storage_config(*scheme, &storage_config));
             }
+            None => problems.push(unknown_value("storage.scheme", &name, &schemes)),
+        },
+        Err(_) => problems.push(format!("storage.scheme: missing, expected one of {}", schemes.join(", "))),
+    }
+
+    // Anything the checks above do not cover, e.g. wrong types, is reported as the deserializer sees it
+    let conf = match config.try_deserialize::<Conf>() {
+        Ok(conf) if problems.is_empty() => Some(conf),
+        Ok(_) => None,
+        Err(e) => {
+            if problems.is_empty() {
+                problems.push(format!("{}", e));
+            }
+            None
+        }
+    };
+
+    ProfileCheck { name, problems, conf }
+}
+
+/// Report missing and unknown storage config keys for the scheme, suggesting the closest known key
+fn check_storage_config(scheme: Scheme, config: &HashMap<String, String>) -> Vec<String> {
+    let mut problems = vec![];
+
+    for key in scheme.required_keys() {
+        if !config.contains_key(*key) {
+            problems.push(format!("storage.config.{}: missing, required by {:?}", key, scheme));
         }
     }
+
+    let known = scheme
+        .required_keys()
+        .iter()
+        .chain(scheme.optional_keys())
+        .chain(scheme.secret_key().as_ref())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let mut keys = config.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    for key in keys {
+        if !known.contains(&key.as_str()) {
+            let problem = match closest(key, &known) {
+                Some(suggestion) => format!("storage.config.{}: unknown key for {:?}, did you mean {}?", key, scheme, suggestion),
+                None => format!("storage.config.{}: unknown key for {:?}, known keys are {}", key, scheme, known.join(", ")),
+            };
+            problems.push(problem);
+        }
+    }
+
+    problems
 }
 
+fn unknown_value(key: &str, value: &str, expected: &[&str]) -> String {
+    match closest(value, expected) {
+        Some(suggestion) => format!("{}: unknown value {}, did you mean {}?", key, value, suggestion),
+        None => format!("{}: unknown value {}, expected one of {}", key, value, expected.join(", ")),
+    }
+}
 
-fn secret_from_keyring_or_prompt(name: &str, secret_name: &str) -> anyhow::Result<String> {
+/// The candidate closest to the value, if it is close enough to be a likely typo
+fn closest<'a>(value: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let value = value.to_lowercase();
+
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(&value, &candidate.to_lowercase()), *candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(1))
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// The keyring entry of a secret of the named profile
+pub fn keyring_entry(name: &str, secret_name: &str) -> anyhow::Result<Entry> {
     let secret_key = format!("{}_{}", name, secret_name);
-    
-    let keyring_entry = Entry::new("postkasse", &secret_key).with_context(|| {
+
+    Entry::new("postkasse", &secret_key).with_context(|| {
         format!("Error creating keyring entry for {}", secret_key)
-    })?;
+    })
+}
+
+pub fn secret_from_keyring_or_prompt(name: &str, secret_name: &str) -> anyhow::Result<String> {
+    let secret_key = format!("{}_{}", name, secret_name);
+    let keyring_entry = keyring_entry(name, secret_name)?;
 
     let secret = keyring_entry.get_password();
 
     match secret {
-        Ok(secret) => return Ok(secret),
+        Ok(secret) => Ok(secret),
         Err(keyring::Error::NoEntry) => {
             let password = Password::new()
                 .with_prompt("Enter your password or token")
@@ -201,13 +818,213 @@ fn secret_from_keyring_or_prompt(name: &str, secret_name: &str) -> anyhow::Resul
                 format!("Error setting secret for {}", secret_key)
             })?;
 
-            return Ok(password)
+            Ok(password)
         },
-        Err(e) => return Err(anyhow::anyhow!(e)),
+        Err(e) => Err(anyhow::anyhow!(e)),
+    }
+
+
+
+
+
+}
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    fn config_from_str(toml: &str) -> Config {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    const PROFILES: &str = r#"
+[jmap]
+host = "https://api.fastmail.com"
+auth_mode = "token"
+
+[storage]
+scheme = "Fs"
+
+[storage.config]
+root = "/backups/default"
+
+[profiles.personal.storage.config]
+root = "/backups/personal"
+
+[profiles.work.jmap]
+host = "https://jmap.example.com"
+auth_mode = "basic"
+username = "jdoe"
+"#;
+
+    #[test]
+    fn test_single_profile_config() {
+        let config = config_from_str(
+            r#"
+name = "personal"
+
+[jmap]
+host = "https://api.fastmail.com"
+auth_mode = "token"
+
+[storage]
+scheme = "Fs"
+
+[storage.config]
+root = "/backups"
+"#,
+        );
+
+        let confs = select_profiles(&config, None, false).unwrap();
+        assert_eq!(confs.len(), 1);
+        assert_eq!(confs[0].name, "personal");
+
+        assert!(select_profiles(&config, Some("personal"), false).is_ok());
+        assert!(select_profiles(&config, Some("work"), false).is_err());
+    }
+
+    #[test]
+    fn test_profile_inherits_shared_defaults() {
+        let config = config_from_str(PROFILES);
+
+        let personal = select_profiles(&config, Some("personal"), false).unwrap().remove(0);
+        assert_eq!(personal.name, "personal");
+        assert_eq!(personal.jmap.host, "https://api.fastmail.com");
+        assert_eq!(personal.storage.config["root"], "/backups/personal");
+
+        let work = select_profiles(&config, Some("work"), false).unwrap().remove(0);
+        assert_eq!(work.name, "work");
+        assert_eq!(work.jmap.host, "https://jmap.example.com");
+        assert_eq!(work.jmap.username.as_deref(), Some("jdoe"));
+        assert_eq!(work.storage.config["root"], "/backups/default");
+    }
+
+    #[test]
+    fn test_check_reports_typos_and_missing_keys() {
+        let config = config_from_str(
+            r#"
+name = "personal"
+
+[jmap]
+host = "https://api.fastmail.com"
+auth_mode = "tokn"
+
+[storage]
+scheme = "S3"
+
+[storage.config]
+bucket = "mail"
+secret_acces_key = "hunter2"
+"#,
+        );
+
+        let check = check_profiles(&config, None).unwrap().remove(0);
+        assert!(check.conf.is_none());
+        assert_eq!(
+            check.problems,
+            vec![
+                "jmap.auth_mode: unknown value tokn, did you mean token?",
+                "storage.config.region: missing, required by S3",
+                "storage.config.secret_acces_key: unknown key for S3, did you mean secret_access_key?",
+            ]
+        );
+
+        let checks = check_profiles(&config_from_str(PROFILES), None).unwrap();
+        assert!(checks.iter().all(|check| check.problems.is_empty() && check.conf.is_some()));
     }
 
+    #[test]
+    fn test_secret_source_precedence() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("jmap");
+        std::fs::write(&path, "from-file\n").unwrap();
+        // Setting variables races with other tests reading the environment, so the lookup is replaced instead
+        let env = |var: &str| match var {
+            "POSTKASSE_TEST_SECRET" => Ok("from-env".to_string()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+
+        let file = SecretSource { secret_file: Some(path.clone()), ..Default::default() };
+        let command = SecretSource { secret_command: Some("echo from-command".to_string()), ..Default::default() };
+        let all = SecretSource {
+            secret_env: Some("POSTKASSE_TEST_SECRET".to_string()),
+            secret_file: Some(path),
+            secret_command: Some("echo from-command".to_string()),
+        };
 
+        assert_eq!(secret_from_source(&SecretSource::default(), env).unwrap(), None);
+        assert_eq!(secret_from_source(&file, env).unwrap().as_deref(), Some("from-file"));
+        assert_eq!(secret_from_source(&command, env).unwrap().as_deref(), Some("from-command"));
+        assert_eq!(secret_from_source(&all, env).unwrap().as_deref(), Some("from-env"));
+
+        // A secret in the config wins over every source, without touching the keyring
+        assert_eq!(resolve_secret(Some("plain".to_string()), &all, "test", "jmap_secret", true).unwrap(), "plain");
+
+        // A broken source is an error rather than a fall through to the keyring
+        let failing = SecretSource { secret_command: Some("exit 3".to_string()), ..Default::default() };
+        assert!(secret_from_source(&failing, env).is_err());
+        let missing = SecretSource { secret_env: Some("POSTKASSE_TEST_MISSING".to_string()), ..Default::default() };
+        assert!(secret_from_source(&missing, env).is_err());
+    }
 
+    #[test]
+    fn test_secret_source_in_config() {
+        let config = config_from_str(
+            r#"
+name = "personal"
 
+[jmap]
+host = "https://api.fastmail.com"
+auth_mode = "token"
+secret_command = "pass show fastmail"
 
-}
\ No newline at end of file
+[storage]
+scheme = "S3"
+secret_file = "/run/secrets/s3"
+
+[storage.config]
+bucket = "mail"
+region = "eu-north-1"
+"#,
+        );
+
+        let conf = select_profiles(&config, None, false).unwrap().remove(0);
+        assert_eq!(conf.jmap.secret_source.secret_command.as_deref(), Some("pass show fastmail"));
+        assert_eq!(conf.storage.secret_source.secret_file, Some(PathBuf::from("/run/secrets/s3")));
+        assert!(!conf.storage.config.contains_key("secret_file"));
+    }
+
+    #[test]
+    fn test_profile_selection() {
+        let config = config_from_str(PROFILES);
+
+        // Ambiguous without a name, unknown names are reported
+        assert!(select_profiles(&config, None, false).is_err());
+        assert!(select_profiles(&config, Some("holiday"), false).is_err());
+
+        let all = select_profiles(&config, None, true).unwrap();
+        let names: Vec<&str> = all.iter().map(|conf| conf.name.as_str()).collect();
+        assert_eq!(names, vec!["personal", "work"]);
+    }
+
+    #[test]
+    fn test_mixed_case_profile_name() {
+        let config = config_from_str(PROFILES);
+
+        // The name scopes keyring entries, so it must not depend on how it was typed
+        let work = select_profiles(&config, Some("Work"), false).unwrap().remove(0);
+        assert_eq!(work.name, "work");
+        assert_eq!(work.jmap.host, "https://jmap.example.com");
+
+        let all = select_profiles(&config, None, true).unwrap();
+        assert_eq!(all[1].name, work.name);
+
+        let checks = check_profiles(&config, Some("WORK")).unwrap();
+        assert_eq!(checks[0].name, "work");
+    }
+}
@@ -0,0 +1,720 @@
+// Streaming of message blobs from the JMAP download endpoint straight into storage.
+// Blobs can be large, so they are never held in memory as a whole while downloading. Messages to
+// index are read back from storage whole, so attachments anywhere in them are indexed the same
+// way a reindex does. The memory used by blobs being transferred or read back is capped by a semaphore,
+// and messages read back keep their share of it until they are indexed.
+//
+// Messages can optionally be stored with their large attachments split out. The attachments are
+// stored once by content hash, and the message itself as a skeleton listing the segments needed
+// to reassemble the original bytes. Messages are streamed to storage whole first and split from
+// there, as only the finished download tells whether a message is large enough to split.
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use jmap_client::{blob::URLParameter, client::Client, core::session::URLPart};
+use log::{info, warn};
+use mail_parser::{MessageParser, PartType};
+use opendal::{Metakey, Operator};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::layout;
+
+/// Size of the chunks uploaded to storage, large enough for S3 and GCS multipart uploads
+const WRITE_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Blobs stored or read back at the same time
+const CONCURRENCY: usize = 50;
+
+/// Messages read back from storage for indexing, holding their share of the memory limit until dropped
+pub struct StoredMessages {
+    /// Messages in the order of their blob ids, None for messages that could not be read
+    pub messages: Vec<Option<Vec<u8>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// A message stored with its attachments split out, in the order the bytes appear in the message
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Skeleton {
+    pub size: usize,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Segment {
+    /// Bytes kept in the skeleton, most headers and text bodies are valid UTF-8
+    Text(String),
+    /// Bytes kept in the skeleton that are not valid UTF-8
+    Base64(String),
+    /// Raw, still transfer encoded, body of an attachment stored under /attachments/
+    Attachment { hash: String, size: usize },
+}
+
+/// Hash and raw body of an attachment split out of a message
+pub type Attachment<'a> = (String, &'a [u8]);
+
+pub struct BlobStreamer<'a> {
+    client: &'a Client,
+    http: reqwest::Client,
+    memory: Arc<Semaphore>,
+    memory_limit_kb: u32,
+    split_threshold: Option<usize>,
+}
+
+impl<'a> BlobStreamer<'a> {
+    /**
+     * Create a streamer downloading blobs with the credentials of the given client.
+     * At most `memory_limit` bytes are buffered across all blobs in flight.
+     * Attachments of at least `split_threshold` bytes are stored separately when set.
+     */
+    pub fn new(client: &'a Client, memory_limit: usize, split_threshold: Option<usize>) -> anyhow::Result<Self> {
+        let mut headers = client.headers().clone();
+        headers.remove(CONTENT_TYPE);
+
+        // No overall timeout, large blobs take a while to download on slow connections
+        let http = reqwest::Client::builder()
+            .connect_timeout(client.timeout())
+            .default_headers(headers)
+            .build()
+            .with_context(|| "Error creating HTTP client for blob downloads")?;
+
+        let memory_limit_kb = (memory_limit / 1024).clamp(1, u32::MAX as usize) as u32;
+
+        Ok(BlobStreamer {
+            client,
+            http,
+            memory: Arc::new(Semaphore::new(memory_limit_kb as usize)),
+            memory_limit_kb,
+            split_threshold,
+        })
+    }
+
+    /**
+     * Download a blob and write it to storage chunk by chunk.
+     */
+    pub async fn download(&self, blob_id: &str, operator: &Operator) -> anyhow::Result<()> {
+        let path = layout::blob_path(blob_id);
+        let response = self
+            .http
+            .get(self.download_url(blob_id))
+            .send()
+            .await
+            .with_context(|| format!("Error downloading blob {}", blob_id))?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Error downloading blob {}, server responded with {}", blob_id, response.status());
+        }
+
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+
+        // Unknown sizes are assumed to be large, reserving a full write buffer
+        let size = content_length.unwrap_or(WRITE_BUFFER);
+        let permit = self.reserve(size.min(WRITE_BUFFER)).await?;
+
+        let mut writer = operator
+            .writer_with(&path)
+            .buffer(WRITE_BUFFER)
+            .await
+            .with_context(|| format!("Error writing blob {}", path))?;
+
+        let mut body = response.bytes_stream();
+        let mut written = 0;
+
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = writer.abort().await;
+                    return Err(e).with_context(|| format!("Error downloading blob {}", blob_id));
+                }
+            };
+
+            written += chunk.len();
+            if let Err(e) = writer.write(chunk).await {
+                let _ = writer.abort().await;
+                return Err(e).with_context(|| format!("Error writing blob {}", path));
+            }
+        }
+
+        writer
+            .close()
+            .await
+            .with_context(|| format!("Error writing blob {}", path))?;
+        drop(permit);
+
+        // Only messages at least as large as the threshold can contain an attachment to split out.
+        // The size is known now even if the server did not send it up front
+        if let Some(threshold) = self.split_threshold.filter(|threshold| written >= *threshold) {
+            // The message and its parsed parts are held in memory while splitting
+            if written.saturating_mul(2) / 1024 > self.memory_limit_kb as usize {
+                info!("Storing blob {} of {} bytes whole, it is too large to split within the memory limit", blob_id, written);
+            } else {
+                let _permit = self.reserve(written * 2).await?;
+                split_stored(operator, blob_id, threshold).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Read messages in storage back for indexing, in consecutive groups that fit in the memory limit together.
+     * A group is only read once the previous one is dropped, so the groups should be indexed one at a time.
+     */
+    pub async fn read_stored<'b>(
+        &'b self,
+        operator: &'b Operator,
+        blob_ids: &'b [&'b str],
+    ) -> impl Stream<Item = anyhow::Result<StoredMessages>> + 'b {
+        // Messages that cannot be read take no room, they are reported when read
+        let sizes = stream::iter(blob_ids.iter().map(|blob_id| stored_size(operator, blob_id)))
+            .buffered(CONCURRENCY)
+            .map(|size| size.unwrap_or_default())
+            .collect::<Vec<_>>()
+            .await;
+
+        let limit = self.memory_limit_kb as usize * 1024;
+        let mut groups = vec![];
+        let mut start = 0;
+        let mut group_size = 0;
+        for (i, size) in sizes.into_iter().enumerate() {
+            if i > start && group_size + size > limit {
+                groups.push((start..i, group_size));
+                start = i;
+                group_size = 0;
+            }
+            group_size += size;
+        }
+        if start < blob_ids.len() {
+            groups.push((start..blob_ids.len(), group_size));
+        }
+
+        stream::iter(groups).then(move |(range, size)| async move {
+            let permit = self.reserve(size).await?;
+            let messages = stream::iter(blob_ids[range].iter().map(|blob_id| async move {
+                read_message(operator, blob_id)
+                    .await
+                    .map_err(|e| warn!("Error reading message {} for indexing. {:#}", blob_id, e))
+                    .ok()
+            }))
+            .buffered(CONCURRENCY)
+            .collect()
+            .await;
+
+            Ok(StoredMessages { messages, _permit: permit })
+        })
+    }
+
+    /// Wait until there is room for another blob in memory, the room is freed when the permit is dropped
+    async fn reserve(&self, bytes: usize) -> anyhow::Result<OwnedSemaphorePermit> {
+        // A single blob may use the whole budget, but never more or it would wait forever
+        let kb = ((bytes / 1024).min(u32::MAX as usize) as u32).clamp(1, self.memory_limit_kb);
+
+        self.memory
+            .clone()
+            .acquire_many_owned(kb)
+            .await
+            .with_context(|| "Error reserving memory for blob")
+    }
+
+    fn download_url(&self, blob_id: &str) -> String {
+        let account_id = self.client.default_account_id();
+        let mut url = String::new();
+
+        for part in self.client.download_url() {
+            match part {
+                URLPart::Value(value) => url.push_str(value),
+                URLPart::Parameter(URLParameter::AccountId) => url.push_str(account_id),
+                URLPart::Parameter(URLParameter::BlobId) => url.push_str(blob_id),
+                URLPart::Parameter(URLParameter::Name) => url.push_str("message.eml"),
+                URLPart::Parameter(URLParameter::Type) => url.push_str("message/rfc822"),
+            }
+        }
+
+        url
+    }
+}
+
+/**
+ * Ids of the messages in storage, stored whole or split.
+ * The folders of an id prefix are listed the first time an id with that prefix is looked up, and kept
+ * for the rest of the backup, so checking a message costs no request of its own.
+ */
+#[derive(Debug, Default)]
+pub struct StoredIds {
+    prefixes: HashSet<String>,
+    ids: HashSet<String>,
+}
+
+impl StoredIds {
+    /// List the folders of the prefixes of these ids that were not listed yet
+    pub async fn load(&mut self, operator: &Operator, blob_ids: &[&str]) {
+        let prefixes = blob_ids
+            .iter()
+            .map(|blob_id| blob_id[..2].to_string())
+            .filter(|prefix| !self.prefixes.contains(prefix))
+            .collect::<HashSet<_>>();
+
+        let listings = stream::iter(prefixes.into_iter().map(|prefix| async move {
+            let blobs = list_ids(operator, &format!("{}{}/", layout::BLOBS_FOLDER, prefix), "").await;
+            let skeletons = list_ids(operator, &format!("{}{}/", layout::SKELETONS_FOLDER, prefix), ".json").await;
+            (prefix, blobs.and_then(|blobs| Ok([blobs, skeletons?].concat())))
+        }))
+        .buffer_unordered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+        for (prefix, ids) in listings {
+            match ids {
+                Ok(ids) => {
+                    self.ids.extend(ids);
+                    self.prefixes.insert(prefix);
+                }
+                // Messages that cannot be found are downloaded again, and the prefix is listed again next time
+                Err(e) => warn!("Error listing stored messages with prefix {}. {:#}", prefix, e),
+            }
+        }
+    }
+
+    pub fn contains(&self, blob_id: &str) -> bool {
+        self.ids.contains(blob_id)
+    }
+
+    pub fn insert(&mut self, blob_id: &str) {
+        self.ids.insert(blob_id.to_string());
+    }
+}
+
+/// Names of the non-empty files in a folder without the suffix, an empty list if the folder does not exist
+async fn list_ids(operator: &Operator, folder: &str, suffix: &str) -> anyhow::Result<Vec<String>> {
+    let lister = operator
+        .lister_with(folder)
+        .metakey(Metakey::Mode | Metakey::ContentLength)
+        .await
+        .with_context(|| format!("Error listing {}", folder))?;
+
+    lister
+        .try_filter(|entry| futures::future::ready(entry.metadata().is_file() && entry.metadata().content_length() > 0))
+        .map_ok(|entry| entry.name().trim_end_matches(suffix).to_string())
+        .try_collect()
+        .await
+        .with_context(|| format!("Error listing {}", folder))
+}
+
+/// Size of the original message, stored whole or split
+pub async fn stored_size(operator: &Operator, blob_id: &str) -> anyhow::Result<usize> {
+    let blob_path = layout::blob_path(blob_id);
+    if let Ok(metadata) = operator.stat(&blob_path).await {
+        return Ok(metadata.content_length() as usize);
+    }
+
+    let skeleton_path = layout::skeleton_path(blob_id);
+    let skeleton = operator
+        .read(&skeleton_path)
+        .await
+        .with_context(|| format!("Error reading blob {}, it is neither stored whole nor split", blob_id))?;
+    let skeleton: Skeleton = serde_json::from_slice(&skeleton)
+        .with_context(|| format!("Error deserializing {}", skeleton_path))?;
+
+    Ok(skeleton.size)
+}
+
+/**
+ * Read the original bytes of a message, reassembling it if its attachments were split out.
+ */
+pub async fn read_message(operator: &Operator, blob_id: &str) -> anyhow::Result<Vec<u8>> {
+    read_message_prefix(operator, blob_id, usize::MAX).await
+}
+
+/// Read at most `limit` bytes from the start of a message
+async fn read_message_prefix(operator: &Operator, blob_id: &str, limit: usize) -> anyhow::Result<Vec<u8>> {
+    let blob_path = layout::blob_path(blob_id);
+    if operator.is_exist(&blob_path).await.unwrap_or(false) {
+        let read = match limit {
+            usize::MAX => operator.read(&blob_path).await,
+            limit => operator.read_with(&blob_path).range(0..limit as u64).await,
+        };
+
+        return read.with_context(|| format!("Error reading blob {}", blob_path));
+    }
+
+    let skeleton_path = layout::skeleton_path(blob_id);
+    let skeleton = operator
+        .read(&skeleton_path)
+        .await
+        .with_context(|| format!("Error reading blob {}, it is neither stored whole nor split", blob_id))?;
+    let skeleton: Skeleton = serde_json::from_slice(&skeleton)
+        .with_context(|| format!("Error deserializing {}", skeleton_path))?;
+
+    let mut message = Vec::with_capacity(skeleton.size.min(limit));
+    for segment in skeleton.segments {
+        if message.len() >= limit {
+            break;
+        }
+
+        match segment {
+            Segment::Text(text) => message.extend_from_slice(text.as_bytes()),
+            Segment::Base64(data) => message.extend_from_slice(
+                &STANDARD
+                    .decode(data)
+                    .with_context(|| format!("Error decoding segment of {}", skeleton_path))?,
+            ),
+            Segment::Attachment { hash, size } => {
+                let attachment_path = layout::attachment_path(&hash);
+                let attachment = operator
+                    .read(&attachment_path)
+                    .await
+                    .with_context(|| format!("Error reading attachment {}", attachment_path))?;
+
+                if attachment.len() != size {
+                    anyhow::bail!("Attachment {} has size {}, expected {}", attachment_path, attachment.len(), size);
+                }
+                message.extend_from_slice(&attachment);
+            }
+        }
+    }
+
+    message.truncate(limit);
+    Ok(message)
+}
+
+/**
+ * Split the attachments of at least `threshold` bytes out of a message stored whole.
+ * Messages without attachments that large stay whole.
+ */
+async fn split_stored(operator: &Operator, blob_id: &str, threshold: usize) -> anyhow::Result<()> {
+    let blob_path = layout::blob_path(blob_id);
+    let raw = operator
+        .read(&blob_path)
+        .await
+        .with_context(|| format!("Error reading blob {}", blob_path))?;
+
+    let Some((skeleton, attachments)) = split_message(&raw, threshold) else {
+        return Ok(());
+    };
+
+    write_skeleton(operator, blob_id, &skeleton, &attachments).await?;
+
+    // Messages stored both ways are read whole, so removing it last never leaves the message unreadable
+    operator
+        .delete(&blob_path)
+        .await
+        .with_context(|| format!("Error removing blob {}", blob_path))
+}
+
+/**
+ * Store the split out attachments of a message and the skeleton to reassemble it.
+ * Attachments already stored by another message are not written again.
+ */
+pub async fn write_skeleton(
+    operator: &Operator,
+    blob_id: &str,
+    skeleton: &Skeleton,
+    attachments: &[Attachment<'_>],
+) -> anyhow::Result<()> {
+    for (hash, body) in attachments {
+        let attachment_path = layout::attachment_path(hash);
+        if operator.is_exist(&attachment_path).await.unwrap_or(false) {
+            continue;
+        }
+
+        operator
+            .write(&attachment_path, body.to_vec())
+            .await
+            .with_context(|| format!("Error writing attachment {}", attachment_path))?;
+    }
+
+    let skeleton_path = layout::skeleton_path(blob_id);
+    let skeleton_json = serde_json::to_string(skeleton)
+        .with_context(|| format!("Error serializing {}", skeleton_path))?;
+
+    operator
+        .write(&skeleton_path, skeleton_json)
+        .await
+        .with_context(|| format!("Error writing {}", skeleton_path))
+}
+
+/**
+ * Split the transfer encoded bodies of large attachments out of a raw message.
+ * Returns None if there is nothing to split. Only attachments directly in the message are split,
+ * attachments of forwarded messages stay inside the forwarded message.
+ */
+pub fn split_message(raw: &[u8], threshold: usize) -> Option<(Skeleton, Vec<Attachment<'_>>)> {
+    let message = MessageParser::default().parse(raw)?;
+
+    let mut ranges: Vec<(usize, usize)> = message
+        .attachments
+        .iter()
+        .filter_map(|id| message.parts.get(*id))
+        .filter(|part| !matches!(part.body, PartType::Message(_) | PartType::Multipart(_)))
+        .filter(|part| part.offset_end <= raw.len() && part.offset_end.saturating_sub(part.offset_body) >= threshold)
+        .map(|part| (part.offset_body, part.offset_end))
+        .collect();
+    ranges.sort();
+
+    if ranges.is_empty() {
+        return None;
+    }
+
+    let mut segments = vec![];
+    let mut attachments = vec![];
+    let mut position = 0;
+
+    for (start, end) in ranges {
+        if start < position {
+            continue; // Overlapping parts would not reassemble, keep them in the skeleton
+        }
+
+        segments.push(inline_segment(&raw[position..start]));
+
+        let body = &raw[start..end];
+        let hash = Sha256::digest(body)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        segments.push(Segment::Attachment { hash: hash.clone(), size: body.len() });
+        attachments.push((hash, body));
+
+        position = end;
+    }
+    segments.push(inline_segment(&raw[position..]));
+    segments.retain(|segment| !matches!(segment, Segment::Text(text) if text.is_empty()));
+
+    Some((Skeleton { size: raw.len(), segments }, attachments))
+}
+
+fn inline_segment(bytes: &[u8]) -> Segment {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Segment::Text(text.to_string()),
+        Err(_) => Segment::Base64(STANDARD.encode(bytes)),
+    }
+}
+
+/// A message with a text body and a 3000 byte attachment, shared by the tests storing messages
+#[cfg(test)]
+pub(crate) fn message_with_attachment(text: &str) -> Vec<u8> {
+    let attachment = STANDARD.encode(vec![b'x'; 3000]);
+    format!(
+        "From: John Doe <jdoe@machine.example>\r\n\
+To: Mary Smith <mary@example.net>\r\n\
+Subject: {text}\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"boundary\"\r\n\
+\r\n\
+--boundary\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+\r\n\
+{text}\r\n\
+--boundary\r\n\
+Content-Type: application/pdf; name=\"contract.pdf\"\r\n\
+Content-Disposition: attachment; filename=\"contract.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+{attachment}\r\n\
+--boundary--\r\n"
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+    use tokio::{io::AsyncWriteExt, sync::Notify};
+
+    use super::*;
+    use crate::core::{
+        jmap::mock::{mock_server, Handler},
+        storage::fs_operator,
+    };
+
+    #[test]
+    fn test_split_message() {
+        let raw = message_with_attachment("Hello");
+
+        let (skeleton, attachments) = split_message(&raw, 1024).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(skeleton.size, raw.len());
+        assert!(matches!(skeleton.segments[1], Segment::Attachment { size, .. } if size >= 4000));
+
+        // Nothing that large to split out
+        assert!(split_message(&raw, 1024 * 1024).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stored_ids() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        operator.write(&layout::blob_path("Gwhole"), "Subject: Whole\r\n\r\n").await.unwrap();
+        operator.write(&layout::skeleton_path("Gsplit"), "{}").await.unwrap();
+        // Left behind by a write that never finished
+        operator.write(&layout::blob_path("Gempty"), "").await.unwrap();
+
+        let mut stored_ids = StoredIds::default();
+        stored_ids.load(&operator, &["Gwhole", "Gsplit", "Gempty", "Xmissing"]).await;
+        assert!(stored_ids.contains("Gwhole"));
+        assert!(stored_ids.contains("Gsplit"));
+        assert!(!stored_ids.contains("Gempty"));
+        assert!(!stored_ids.contains("Xmissing"));
+
+        // Prefixes are listed once, also those without any folder yet
+        assert_eq!(stored_ids.prefixes, HashSet::from(["Gw", "Gs", "Ge", "Xm"].map(String::from)));
+        operator.write(&layout::blob_path("Gwhole2"), "Subject: Later\r\n\r\n").await.unwrap();
+        stored_ids.load(&operator, &["Gwhole2"]).await;
+        assert!(!stored_ids.contains("Gwhole2"));
+    }
+
+    #[tokio::test]
+    async fn test_split_messages_reassemble_and_share_attachments() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        let first = message_with_attachment("Hello");
+        let second = message_with_attachment("Forwarded");
+
+        operator.write(&layout::blob_path("Gfirst"), first.clone()).await.unwrap();
+        operator.write(&layout::blob_path("Gsecond"), second.clone()).await.unwrap();
+        split_stored(&operator, "Gfirst", 1024).await.unwrap();
+        split_stored(&operator, "Gsecond", 1024).await.unwrap();
+
+        assert!(operator.is_exist(&layout::skeleton_path("Gfirst")).await.unwrap());
+        assert!(!operator.is_exist(&layout::blob_path("Gfirst")).await.unwrap());
+        assert_eq!(read_message(&operator, "Gfirst").await.unwrap(), first);
+        assert_eq!(read_message(&operator, "Gsecond").await.unwrap(), second);
+        assert_eq!(read_message_prefix(&operator, "Gsecond", 10).await.unwrap(), &second[..10]);
+
+        let attachments = operator
+            .list_with(layout::ATTACHMENTS_FOLDER)
+            .recursive(true)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|entry| entry.metadata().is_file())
+            .count();
+        assert_eq!(attachments, 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_without_content_length_is_split() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        let raw = message_with_attachment("Hello");
+
+        let handler: Handler = {
+            let raw = raw.clone();
+            Arc::new(move |path, mut socket| {
+                let raw = raw.clone();
+                Box::pin(async move {
+                    assert!(path.starts_with("/download/A1/Gabc/"));
+                    // Streamed in small chunks without a Content-Length, ending with the connection
+                    socket.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").await.unwrap();
+                    for chunk in raw.chunks(1000) {
+                        socket.write_all(chunk).await.unwrap();
+                    }
+                })
+            })
+        };
+        let connection = mock_server(handler).await;
+        let client = connection.client().await.unwrap();
+        let streamer = BlobStreamer::new(&client, 1024 * 1024, Some(1024)).unwrap();
+
+        streamer.download("Gabc", &operator).await.unwrap();
+
+        assert!(!operator.is_exist(&layout::blob_path("Gabc")).await.unwrap());
+        assert!(operator.is_exist(&layout::skeleton_path("Gabc")).await.unwrap());
+        assert_eq!(read_message(&operator, "Gabc").await.unwrap(), raw);
+        assert_eq!(streamer.memory.available_permits(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_bounds_downloads() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        let (started, finish) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+
+        let handler: Handler = {
+            let (started, finish) = (started.clone(), finish.clone());
+            Arc::new(move |path, mut socket| {
+                let (started, finish) = (started.clone(), finish.clone());
+                Box::pin(async move {
+                    socket.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").await.unwrap();
+                    socket.write_all(&[b'x'; 1000]).await.unwrap();
+                    if path.starts_with("/download/A1/Gslow/") {
+                        started.notify_one();
+                        finish.notified().await;
+                    }
+                    socket.write_all(&[b'x'; 1000]).await.unwrap();
+                })
+            })
+        };
+        let connection = mock_server(handler).await;
+        let client = connection.client().await.unwrap();
+        // Blobs of unknown size reserve a whole write buffer, more than this limit
+        let streamer = BlobStreamer::new(&client, 64 * 1024, None).unwrap();
+
+        let slow = streamer.download("Gslow", &operator);
+        let fast = streamer.download("Gfast", &operator);
+        tokio::pin!(slow, fast);
+
+        tokio::select! {
+            _ = &mut slow => panic!("Slow download finished early"),
+            _ = started.notified() => {}
+        }
+        // The fast download is answered right away, but has to wait for the slow one to free the memory
+        tokio::select! {
+            _ = &mut slow => panic!("Slow download finished early"),
+            _ = &mut fast => panic!("Downloads exceeded the memory limit"),
+            _ = tokio::time::sleep(Duration::from_millis(300)) => {}
+        }
+        assert_eq!(streamer.memory.available_permits(), 0);
+
+        finish.notify_one();
+        let (slow, fast) = tokio::join!(slow, fast);
+        slow.unwrap();
+        fast.unwrap();
+        assert_eq!(operator.read(&layout::blob_path("Gfast")).await.unwrap().len(), 2000);
+        assert_eq!(streamer.memory.available_permits(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_read_stored_in_groups() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        let handler: Handler = Arc::new(|_, _| Box::pin(async {}));
+        let connection = mock_server(handler).await;
+        let client = connection.client().await.unwrap();
+        let streamer = BlobStreamer::new(&client, 8 * 1024, None).unwrap();
+
+        for blob_id in ["Ga", "Gb", "Gc"] {
+            operator.write(&layout::blob_path(blob_id), vec![b'x'; 3 * 1024]).await.unwrap();
+        }
+
+        let blob_ids = ["Ga", "Gb", "Gc", "Gmissing"];
+        let groups = streamer.read_stored(&operator, &blob_ids).await;
+        tokio::pin!(groups);
+
+        // Two messages fit in the limit together, the missing one takes no room
+        let first = groups.next().await.unwrap().unwrap();
+        assert_eq!(first.messages.len(), 2);
+        assert_eq!(streamer.memory.available_permits(), 2);
+        drop(first);
+
+        let second = groups.next().await.unwrap().unwrap();
+        assert_eq!(second.messages.len(), 2);
+        assert!(second.messages[0].is_some() && second.messages[1].is_none());
+        drop(second);
+
+        assert!(groups.next().await.is_none());
+        assert_eq!(streamer.memory.available_permits(), 8);
+    }
+}
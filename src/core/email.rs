@@ -6,40 +6,61 @@ use jmap_client::{
     core::query::Filter,
     email::{self, Property},
 };
-use log::info;
+use log::{info, warn};
 use mail_parser::MessageParser;
 use opendal::Operator;
 use rayon::prelude::*;
-use tantivy::IndexWriter;
 
 
-use super::{progress::{read_backup_progress, write_backup_progress, Progressable}, search::write_document};
+use crate::conf;
+
+use super::{jmap::JmapConnection, blob::{BlobStreamer, StoredIds}, index_sync::push_index, layout, mailboxes::{read_mailbox_names, MailboxNames}, progress::{read_backup_progress, write_backup_progress, Progressable}, search::{write_document, Indexer}, threads::threads};
 
 pub async fn emails(
-    client: &Client,
+    connection: &JmapConnection,
     operator: &Operator,
     max_objects: usize,
     pb: &dyn Progressable,
-    mut indexer: Option<IndexWriter>,
+    pb_skipped: &dyn Progressable,
+    mut indexer: Option<&mut Indexer>,
+    conf: &conf::Backup,
 ) -> Result<()> {
     info!("Backing up emails");
     let message_parser = MessageParser::default();
+    let split_threshold = conf.split_attachments.then_some(conf.attachment_threshold_kb * 1024);
+    let properties = email_properties(&conf.properties)?;
     let mut backup_progress = read_backup_progress(operator, "email.json")
         .await
-        .with_context(|| format!("Error reading backup progress"))?;
+        .with_context(|| "Error reading backup progress")?;
 
-    let total = fetch_total_count(&client, backup_progress.last_processed_date)
+    let total = fetch_total_count(&*connection.client().await?, backup_progress.last_processed_date)
         .await
-        .with_context(|| format!("Error fetching total count"))?;
+        .with_context(|| "Error fetching total count")?;
 
     pb.set_length(total.try_into().unwrap());
 
+    // Emails are indexed by the names of their mailboxes, which are backed up before the emails
+    let mailbox_names = match indexer {
+        Some(_) => read_mailbox_names(operator)
+            .await
+            .with_context(|| "Error reading mailbox names")?,
+        None => MailboxNames::new(),
+    };
+
+    // Messages stored by earlier runs are found by listing storage once per id prefix, rather than per message
+    let mut stored_ids = StoredIds::default();
+
     loop {
+        // Get the client for every page, so an expiring OAuth token is refreshed during long backups
+        let client = connection.client().await?;
+        let streamer = BlobStreamer::new(&client, conf.memory_limit_mb * 1024 * 1024, split_threshold)?;
+
         let emails_res = fetch_email(
             &client,
             backup_progress.last_processed_date,
             pb.position().try_into().unwrap(),
             max_objects,
+            &properties,
         )
         .await
         .with_context(|| format!("Error fetching emails from position {}", pb.position()))?;
@@ -55,27 +76,72 @@ pub async fn emails(
         .collect::<Vec<_>>()
         .await;
 
-        let blobs = stream::iter(emails_res.iter().map(|id| {
-            let blob_id = id.blob_id().unwrap(); // Should always be present in working JMAP implementations
-            process_blob(blob_id, &client, &operator)
-        }))
-        .buffered(50)
-        .collect::<Vec<_>>()
-        .await;
+        // Store the threads of this page so conversations can be rebuilt from the archive
+        threads(&client, operator, &emails_res)
+            .await
+            .with_context(|| format!("Error backing up threads from position {}", pb.position()))?;
+
+        // Should always be present in working JMAP implementations
+        let page_blob_ids = emails_res.iter().map(|email| email.blob_id().unwrap()).collect::<Vec<_>>();
+        stored_ids.load(operator, &page_blob_ids).await;
+
+        let blobs = stream::iter(page_blob_ids.iter().map(|blob_id| process_blob(blob_id, &streamer, operator, &stored_ids)))
+            .buffered(50)
+            .collect::<Vec<_>>()
+            .await;
+
+        for (blob_id, blob) in page_blob_ids.iter().zip(&blobs) {
+            match blob {
+                Ok(false) => stored_ids.insert(blob_id),
+                Ok(true) => {}
+                // The email is backed up without its message, a later run downloads it again
+                Err(e) => warn!("Error storing message {}. {:#}", blob_id, e),
+            }
+        }
+
+        let skipped = blobs
+            .iter()
+            .filter(|blob| matches!(blob, Ok(true)))
+            .count();
+        pb_skipped.inc(skipped.try_into().unwrap());
 
         // Update backup progress
         // Get the unwrapped received_at of the last email
         let last_received = emails_res
             .last()
-            .map(|email| email.received_at())
-            .flatten()
-            .map(|date| DateTime::from_timestamp_millis(date * 1000))
-            .flatten();
+            .and_then(|email| email.received_at())
+            .and_then(|date| DateTime::from_timestamp_millis(date * 1000));
 
         // Borrow indexer mutably if it exists and write email documents then commit
-        if let Some(indexer) = &mut indexer {
-            // Index the emails using parallel processing
-            index_emails(emails_res, blobs, &message_parser, indexer)?;
+        if let Some(indexer) = indexer.as_deref_mut() {
+            // Only emails whose message is in storage are indexed. Skipped messages are read back and indexed too,
+            // as the run that stored them may have stopped before committing the index, or run without search.
+            // Indexing replaces the documents of an email, so emails indexed before are not duplicated
+            let stored = emails_res
+                .iter()
+                .zip(&blobs)
+                .filter(|(_, blob)| blob.is_ok())
+                .map(|(email, _)| email)
+                .collect::<Vec<_>>();
+            let blob_ids = stored.iter().map(|email| email.blob_id().unwrap()).collect::<Vec<_>>();
+
+            // The messages are read back in groups fitting in memory, each group is indexed before the next is read
+            let groups = streamer.read_stored(operator, &blob_ids).await;
+            tokio::pin!(groups);
+            let mut stored = stored.into_iter();
+            while let Some(group) = groups.next().await {
+                let group = group?;
+                let emails = stored.by_ref().take(group.messages.len()).collect::<Vec<_>>();
+                index_emails(&emails, &group.messages, &message_parser, &mailbox_names, indexer);
+            }
+            indexer.commit()?;
+
+            if let Some(folder) = indexer.sync_folder() {
+                // The next commit copies whatever this one failed to, so the backup carries on
+                if let Err(e) = push_index(operator, folder).await {
+                    warn!("Error copying the search index to the storage. {:#}", e);
+                }
+            }
         }
 
         backup_progress.last_processed_date = last_received.unwrap_or_default();
@@ -83,7 +149,7 @@ pub async fn emails(
         info!("Writing backup progress");
         write_backup_progress(operator, "email.json", backup_progress)
             .await
-            .with_context(|| format!("Error writing backup progress"))?;
+            .with_context(|| "Error writing backup progress")?;
 
         pb.inc(length.try_into().unwrap());
 
@@ -97,52 +163,45 @@ pub async fn emails(
     Ok(())
 }
 
-fn index_emails(emails_res: Vec<email::Email>, blobs: Vec<std::prelude::v1::Result<Vec<u8>, anyhow::Error>>, message_parser: &MessageParser, indexer: &mut IndexWriter) -> Result<(), anyhow::Error> {
-    let combined = emails_res
-        .into_iter()
-        .zip(blobs.into_iter())
-        .collect::<Vec<_>>();
-    
-    combined.par_iter().for_each(|(email, blob)| {
-        let _ = blob
-            .as_ref()
-            .map(|blob| message_parser.parse(blob))
-            .map(|message| write_document(indexer, email, &message.unwrap_or_default()));
+/// Index the emails using parallel processing, skipping those whose message could not be read
+fn index_emails(
+    emails: &[&email::Email],
+    messages: &[Option<Vec<u8>>],
+    message_parser: &MessageParser,
+    mailbox_names: &MailboxNames,
+    indexer: &Indexer,
+) {
+    emails.par_iter().zip(messages).for_each(|(email, message)| {
+        if let Some(message) = message {
+            let message = message_parser.parse(message).unwrap_or_default();
+            if let Err(e) = write_document(indexer, email, &message, mailbox_names) {
+                warn!("Error indexing email {}. {:#}", email.id().unwrap_or_default(), e);
+            }
+        }
     });
-    indexer
-        .commit()
-        .with_context(|| format!("Error committing indexer"))?;
-    Ok(())
 }
 
+/// Store a message blob unless an earlier run already did, returning whether it was skipped
 async fn process_blob(
     blob_id: &str,
-    client: &Client,
+    streamer: &BlobStreamer<'_>,
     operator: &Operator,
-) -> anyhow::Result<Vec<u8>> {
-    let blob_path = format!("/blobs/{}/{}", &blob_id[..2], blob_id);
-    let blob = client
-        .download(blob_id)
-        .await
-        .with_context(|| format!("Error downloading blob {}", blob_id))?;
-
-    // Parse the blob to get the email in structured format
+    stored_ids: &StoredIds,
+) -> anyhow::Result<bool> {
+    // Resumed backups overlap with the previous run, so avoid downloading and writing blobs again.
+    // Blobs are immutable in JMAP, so a stored blob with the same id is the same message.
+    if stored_ids.contains(blob_id) {
+        return Ok(true);
+    }
 
-    operator
-        .write(&blob_path, blob.clone())
-        .await
-        .with_context(|| format!("Error writing blob {}", blob_path))?;
+    streamer.download(blob_id, operator).await?;
 
-    Ok(blob)
+    Ok(false)
 }
 
 async fn process_email(email: &email::Email, operator: &Operator) -> anyhow::Result<()> {
     let id = email.id().unwrap();
-    // Split the emails into folders based on the first three characters of the id
-    // Based on the assumption that the ids are random enough to be evenly distributed
-    // Fastmail uses same initial character for all emails, so we use the first 3 characters
-    // Worst case scenario is that we have 16^3 = 4096 folders
-    let path = format!("/emails/{}/{}.json", &id[..3], id);
+    let path = layout::email_path(id);
     let email_json =
         serde_json::to_string(&email).with_context(|| format!("Error serializing email {}", id))?;
 
@@ -155,6 +214,35 @@ async fn process_email(email: &email::Email, operator: &Operator) -> anyhow::Res
     Ok(())
 }
 
+/// Properties the backup itself depends on, fetched even if left out of the configured properties
+const REQUIRED_PROPERTIES: [Property; 4] = [Property::Id, Property::BlobId, Property::ThreadId, Property::ReceivedAt];
+
+/**
+ * Parse the configured JMAP Email property names.
+ * Unknown names are refused here rather than by the server in the middle of a backup.
+ */
+pub fn email_properties(names: &[String]) -> anyhow::Result<Vec<Property>> {
+    let mut properties = REQUIRED_PROPERTIES.to_vec();
+
+    for name in names {
+        let property: Property = serde_json::from_value(serde_json::Value::String(name.clone()))
+            .map_err(|_| anyhow::anyhow!("Invalid email property {}", name))?;
+
+        match property {
+            Property::Other(_) => anyhow::bail!(
+                "Unknown email property {}. Headers are fetched with properties like header:List-Id:asText",
+                name
+            ),
+            // Body values can only be fetched together with extra request arguments
+            Property::BodyValues => anyhow::bail!("The bodyValues property is not supported, bodies are stored as blobs"),
+            property if !properties.contains(&property) => properties.push(property),
+            _ => {}
+        }
+    }
+
+    Ok(properties)
+}
+
 async fn fetch_total_count(
     client: &Client,
     last_processed_date: DateTime<Utc>,
@@ -185,6 +273,7 @@ async fn fetch_email(
     last_processed_date: DateTime<Utc>,
     position: usize,
     max_objects: usize,
+    properties: &[Property],
 ) -> anyhow::Result<Vec<email::Email>> {
     info!("Fetching emails from position {}", position);
     let mut request = client.build();
@@ -200,23 +289,10 @@ async fn fetch_email(
         .limit(max_objects)
         .result_reference();
 
-    let properties_to_fetch = vec![
-        Property::Id,
-        Property::MailboxIds,
-        Property::Keywords,
-        Property::ReceivedAt,
-        Property::BlobId,
-        Property::MessageId,
-        Property::From,
-        Property::To,
-        Property::Cc,
-        Property::Subject,
-    ];
-
     request
         .get_email()
         .ids_ref(result)
-        .properties(properties_to_fetch);
+        .properties(properties.iter().cloned());
 
     let mut response = request.send().await?.unwrap_method_responses();
     let email_res = response.pop();
@@ -230,3 +306,82 @@ async fn fetch_email(
         _ => anyhow::bail!("unexpected number of responses"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use jmap_client::email::{Header, HeaderForm};
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::core::{
+        jmap::mock::{mock_server, Handler},
+        storage::fs_operator,
+    };
+
+    #[test]
+    fn test_email_properties() {
+        let names = ["subject", "size", "header:List-Id:asText", "header:X-Mailer", "subject"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+
+        let properties = email_properties(&names).unwrap();
+        assert_eq!(&properties[..4], &REQUIRED_PROPERTIES);
+        assert_eq!(
+            &properties[4..],
+            &[
+                Property::Subject,
+                Property::Size,
+                Property::Header(Header { name: "List-Id".to_string(), form: HeaderForm::Text, all: false }),
+                Property::Header(Header { name: "X-Mailer".to_string(), form: HeaderForm::Raw, all: false }),
+            ]
+        );
+
+        assert!(email_properties(&conf::Backup::default().properties).is_ok());
+        assert!(email_properties(&["headers".to_string()]).is_err());
+        assert!(email_properties(&["bodyValues".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_blob_skips_stored_blobs() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        let downloads = Arc::new(AtomicUsize::new(0));
+
+        let handler: Handler = {
+            let downloads = downloads.clone();
+            Arc::new(move |_, mut socket| {
+                downloads.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    let body = "Subject: Hello\r\n\r\nHello\r\n";
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                })
+            })
+        };
+        let connection = mock_server(handler).await;
+        let client = connection.client().await.unwrap();
+        let streamer = BlobStreamer::new(&client, 1024 * 1024, None).unwrap();
+
+        operator.write(&layout::blob_path("Gstored"), "Subject: Stored\r\n\r\n").await.unwrap();
+        let mut stored_ids = StoredIds::default();
+        stored_ids.load(&operator, &["Gstored", "Gnew"]).await;
+
+        assert!(process_blob("Gstored", &streamer, &operator, &stored_ids).await.unwrap());
+        assert_eq!(downloads.load(Ordering::SeqCst), 0);
+        assert!(!process_blob("Gnew", &streamer, &operator, &stored_ids).await.unwrap());
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert!(operator.is_exist(&layout::blob_path("Gnew")).await.unwrap());
+
+        // Stored by an earlier page now
+        stored_ids.insert("Gnew");
+        assert!(process_blob("Gnew", &streamer, &operator, &stored_ids).await.unwrap());
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,291 @@
+// Text of attachments for indexing.
+// PDF, Word and OpenDocument text, spreadsheets, plain text, CSV and HTML are supported, detected by
+// content type or file extension. Parsers run on their own thread with a deadline, so an attachment
+// that is too large, broken or slow to parse is skipped instead of stalling indexing.
+use std::{
+    io::{Cursor, Read},
+    sync::mpsc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use calamine::Reader;
+use log::warn;
+use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
+use quick_xml::events::Event;
+
+use super::text::html_to_text;
+
+/// Attachments larger than this, after decoding, are only indexed by name
+const MAX_ATTACHMENT_SIZE: usize = 20 * 1024 * 1024;
+/// Parsing an attachment taking longer than this is abandoned
+const EXTRACT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Text beyond this many bytes of an attachment is left out of the index
+const MAX_TEXT_SIZE: usize = 1024 * 1024;
+/// Documents inside zip files are read up to this size, guarding against zip bombs
+const MAX_XML_SIZE: u64 = 64 * 1024 * 1024;
+/// Attachments of forwarded messages nested deeper than this are left out
+const MAX_NESTED_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Pdf,
+    Docx,
+    Odt,
+    Spreadsheet,
+    Html,
+    Text,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AttachmentText {
+    pub name: Option<String>,
+    /// None if the format is not supported or the text could not be extracted
+    pub text: Option<String>,
+}
+
+/**
+ * Extract the name and text of every attachment of the message, including those of forwarded messages.
+ */
+pub fn attachment_texts(message: &Message) -> Vec<AttachmentText> {
+    let mut texts = vec![];
+    push_attachment_texts(message, &mut texts, 0);
+    texts
+}
+
+fn push_attachment_texts(message: &Message, texts: &mut Vec<AttachmentText>, depth: usize) {
+    for part in message.attachments.iter().filter_map(|id| message.parts.get(*id)) {
+        if let Some(nested) = part.message() {
+            if depth < MAX_NESTED_DEPTH {
+                push_attachment_texts(nested, texts, depth + 1);
+            }
+            continue;
+        }
+
+        let name = part.attachment_name().map(String::from);
+        let text = detect_format(part).and_then(|format| extract_part(part, format, name.as_deref()));
+
+        texts.push(AttachmentText { name, text });
+    }
+}
+
+fn extract_part(part: &MessagePart, format: Format, name: Option<&str>) -> Option<String> {
+    let name = name.unwrap_or("unnamed attachment");
+
+    let text = match (&part.body, format) {
+        (PartType::Text(text), Format::Text) => text.to_string(),
+        (PartType::Text(html) | PartType::Html(html), Format::Html) => html_to_text(html),
+        (_, Format::Text) => String::from_utf8_lossy(part.contents()).into_owned(),
+        (_, Format::Html) => html_to_text(&String::from_utf8_lossy(part.contents())),
+        _ if part.len() > MAX_ATTACHMENT_SIZE => {
+            warn!("Not extracting text of {}, it is larger than {} bytes", name, MAX_ATTACHMENT_SIZE);
+            return None;
+        }
+        _ => extract_with_timeout(format, part.contents().to_vec(), name)?,
+    };
+
+    Some(truncate(text))
+}
+
+/// Parse a document on its own thread, giving up on it after `EXTRACT_TIMEOUT`.
+/// A parser that hangs keeps its thread busy until it finishes, but indexing carries on
+fn extract_with_timeout(format: Format, data: Vec<u8>, name: &str) -> Option<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let _ = sender.send(extract_document(format, &data));
+    });
+
+    match receiver.recv_timeout(EXTRACT_TIMEOUT) {
+        Ok(Ok(text)) => Some(text),
+        Ok(Err(e)) => {
+            warn!("Error extracting text of {}. {:#}", name, e);
+            None
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            warn!("Gave up extracting text of {} after {} seconds", name, EXTRACT_TIMEOUT.as_secs());
+            None
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            warn!("Parser crashed extracting text of {}", name);
+            None
+        }
+    }
+}
+
+fn extract_document(format: Format, data: &[u8]) -> anyhow::Result<String> {
+    match format {
+        Format::Pdf => pdf_extract::extract_text_from_mem(data).with_context(|| "Error parsing PDF"),
+        Format::Docx => zipped_xml_text(data, "word/document.xml", b"w:p"),
+        Format::Odt => zipped_xml_text(data, "content.xml", b"text:p"),
+        Format::Spreadsheet => spreadsheet_text(data),
+        Format::Html | Format::Text => Ok(String::from_utf8_lossy(data).into_owned()),
+    }
+}
+
+/// Text of an XML document inside a zip file, with a line per paragraph element
+fn zipped_xml_text(data: &[u8], path: &str, paragraph: &[u8]) -> anyhow::Result<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).with_context(|| "Error opening zip file")?;
+    let mut xml = String::new();
+    archive
+        .by_name(path)
+        .with_context(|| format!("Error finding {} in zip file", path))?
+        .take(MAX_XML_SIZE)
+        .read_to_string(&mut xml)
+        .with_context(|| format!("Error reading {} from zip file", path))?;
+
+    let mut reader = quick_xml::Reader::from_str(&xml);
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event().with_context(|| format!("Error parsing {}", path))? {
+            Event::Text(content) => text.push_str(&content.unescape().unwrap_or_default()),
+            Event::End(element) if element.name().as_ref() == paragraph => text.push('\n'),
+            Event::Empty(element) if element.name().as_ref().ends_with(b":tab") => text.push('\t'),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(text)
+}
+
+/// Text of every sheet of an xlsx, xls or ods file, with a line per row and tabs between cells
+fn spreadsheet_text(data: &[u8]) -> anyhow::Result<String> {
+    let mut workbook = calamine::open_workbook_auto_from_rs(Cursor::new(data))
+        .with_context(|| "Error opening spreadsheet")?;
+    let mut text = String::new();
+
+    for sheet in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&sheet)
+            .with_context(|| format!("Error reading sheet {}", sheet))?;
+
+        text.push_str(&sheet);
+        text.push('\n');
+        for row in range.rows() {
+            let cells = row.iter().map(|cell| cell.to_string()).collect::<Vec<_>>();
+            text.push_str(cells.join("\t").trim_end());
+            text.push('\n');
+        }
+    }
+
+    Ok(text)
+}
+
+/// Detect the format of an attachment, by its content type or, for generic types, by its file extension
+fn detect_format(part: &MessagePart) -> Option<Format> {
+    let content_type = part
+        .content_type()
+        .map(|ct| format!("{}/{}", ct.ctype(), ct.subtype().unwrap_or_default()).to_ascii_lowercase());
+
+    let by_type = match content_type.as_deref() {
+        Some("application/pdf") => Some(Format::Pdf),
+        Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document") => Some(Format::Docx),
+        Some("application/vnd.oasis.opendocument.text") => Some(Format::Odt),
+        Some(
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            | "application/vnd.ms-excel"
+            | "application/vnd.oasis.opendocument.spreadsheet",
+        ) => Some(Format::Spreadsheet),
+        Some("text/html") => Some(Format::Html),
+        Some(ct) if ct.starts_with("text/") => Some(Format::Text),
+        _ => None,
+    };
+
+    by_type.or_else(|| {
+        let extension = part.attachment_name()?.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(Format::Pdf),
+            "docx" => Some(Format::Docx),
+            "odt" => Some(Format::Odt),
+            "xlsx" | "xls" | "ods" => Some(Format::Spreadsheet),
+            "html" | "htm" => Some(Format::Html),
+            "txt" | "csv" | "tsv" | "md" | "log" => Some(Format::Text),
+            _ => None,
+        }
+    })
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_TEXT_SIZE {
+        let mut end = MAX_TEXT_SIZE;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    fn docx(document_xml: &str) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("word/document.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(document_xml.as_bytes()).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_attachment_texts() {
+        let document = docx(
+            r#"<w:document><w:body><w:p><w:r><w:t>Kontrakt</w:t></w:r></w:p><w:p><w:r><w:t>Pris:</w:t><w:tab/><w:t>100 &amp; mer</w:t></w:r></w:p></w:body></w:document>"#,
+        );
+
+        let raw = format!(
+            concat!(
+                "Subject: Kontrakt\r\n",
+                "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n",
+                "--b\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "Se vedlegg\r\n",
+                "--b\r\n",
+                "Content-Type: application/octet-stream; name=\"avtale.docx\"\r\n",
+                "Content-Disposition: attachment; filename=\"avtale.docx\"\r\n",
+                "Content-Transfer-Encoding: base64\r\n\r\n",
+                "{}\r\n",
+                "--b\r\n",
+                "Content-Type: text/csv; charset=utf-8\r\n",
+                "Content-Disposition: attachment; filename=\"priser.csv\"\r\n\r\n",
+                "vare;pris\r\nkaffe;50\r\n",
+                "--b\r\n",
+                "Content-Type: image/png\r\n",
+                "Content-Disposition: attachment; filename=\"logo.png\"\r\n",
+                "Content-Transfer-Encoding: base64\r\n\r\n",
+                "iVBORw0KGgo=\r\n",
+                "--b--\r\n",
+            ),
+            STANDARD.encode(document)
+        );
+
+        let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        let texts = attachment_texts(&message);
+
+        assert_eq!(
+            texts,
+            vec![
+                AttachmentText { name: Some("avtale.docx".to_string()), text: Some("Kontrakt\nPris:\t100 & mer\n".to_string()) },
+                AttachmentText { name: Some("priser.csv".to_string()), text: Some("vare;pris\r\nkaffe;50".to_string()) },
+                AttachmentText { name: Some("logo.png".to_string()), text: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_broken_documents_are_skipped() {
+        assert!(extract_with_timeout(Format::Docx, b"not a zip file".to_vec(), "broken.docx").is_none());
+        assert!(extract_with_timeout(Format::Pdf, b"%PDF-1.4 truncated".to_vec(), "broken.pdf").is_none());
+
+        let long = "ø".repeat(MAX_TEXT_SIZE);
+        assert_eq!(truncate(long).len(), MAX_TEXT_SIZE);
+    }
+}
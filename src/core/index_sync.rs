@@ -0,0 +1,366 @@
+// Copies of the search index in the storage, under /index/, so the index survives the machine running
+// backups and other machines can search the archive.
+// Tantivy never changes a segment file once written, so segments are copied only when missing or of
+// another size. meta.json lists the segments of the index and is copied last, so a copy never refers
+// to segments it does not have yet. Each side records the meta.json it last copied, which tells a
+// newer index in the storage, even a rebuilt one starting over, from local commits never copied.
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::Context;
+use futures::TryStreamExt;
+use log::{info, warn};
+use opendal::{Metakey, Operator};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{layout, search::VERSION_FILE};
+
+const META_FILE: &str = "meta.json";
+
+/// Written next to the local index, recording the meta.json last copied to or from the storage
+const SYNC_FILE: &str = "postkasse-sync.json";
+
+/// Files of a segment, named by the segment id, besides the file of deleted documents
+const SEGMENT_EXTENSIONS: [&str; 6] = ["idx", "pos", "term", "store", "fast", "fieldnorm"];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub copied: u64,
+    pub removed: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncState {
+    /// SHA-256 of the meta.json last copied
+    meta_hash: String,
+}
+
+// The parts of tantivy's meta.json naming the files of the index
+#[derive(Deserialize)]
+struct IndexMeta {
+    segments: Vec<SegmentMeta>,
+}
+
+#[derive(Deserialize)]
+struct SegmentMeta {
+    segment_id: String,
+    deletes: Option<DeleteMeta>,
+}
+
+#[derive(Deserialize)]
+struct DeleteMeta {
+    opstamp: u64,
+}
+
+/**
+ * Copy the index in the folder to the storage, uploading only the segments the storage does not have
+ * and removing the ones the copied meta.json no longer refers to.
+ */
+pub async fn push_index(operator: &Operator, folder: &str) -> anyhow::Result<SyncReport> {
+    let mut report = SyncReport::default();
+
+    // The segments are taken from meta.json rather than the folder, where merges add and remove segments
+    let meta = std::fs::read(Path::new(folder).join(META_FILE))
+        .with_context(|| format!("Error reading {} of the index in {}", META_FILE, folder))?;
+    let mut files = segment_files(&meta)?;
+    files.insert(VERSION_FILE.to_string());
+    let remote = remote_files(operator).await?;
+
+    for name in &files {
+        let path = Path::new(folder).join(name);
+        // A merge may have removed a segment since meta.json was read, leaving the copy as it was until the next commit
+        let size = std::fs::metadata(&path)
+            .with_context(|| format!("Error reading {} of the index in {}, it may have been merged away", name, folder))?
+            .len();
+        if name != VERSION_FILE && remote.get(name) == Some(&size) {
+            continue;
+        }
+
+        let content = std::fs::read(&path)
+            .with_context(|| format!("Error reading {} of the index in {}, it may have been merged away", name, folder))?;
+        operator
+            .write(&layout::index_path(name), content)
+            .await
+            .with_context(|| format!("Error writing {}", layout::index_path(name)))?;
+        report.copied += 1;
+    }
+
+    operator
+        .write(&layout::index_path(META_FILE), meta.clone())
+        .await
+        .with_context(|| format!("Error writing {}", layout::index_path(META_FILE)))?;
+    report.copied += 1;
+
+    for name in remote.keys().filter(|name| *name != META_FILE && !files.contains(*name)) {
+        operator
+            .delete(&layout::index_path(name))
+            .await
+            .with_context(|| format!("Error removing {}", layout::index_path(name)))?;
+        report.removed += 1;
+    }
+
+    write_sync_state(folder, &meta)?;
+    info!("Copied {} index files to the storage, removed {}", report.copied, report.removed);
+
+    Ok(report)
+}
+
+/**
+ * Update the index in the folder from the copy in the storage, downloading only the segments it does not have.
+ * Returns None if the storage has no index, the local index is the same, or the local index has commits that
+ * were never copied to the storage, which are kept rather than overwritten.
+ */
+pub async fn pull_index(operator: &Operator, folder: &str) -> anyhow::Result<Option<SyncReport>> {
+    let mut report = SyncReport::default();
+    let meta_path = layout::index_path(META_FILE);
+
+    if !operator.is_exist(&meta_path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let meta = operator
+        .read(&meta_path)
+        .await
+        .with_context(|| format!("Error reading {}", meta_path))?;
+
+    let synced = read_sync_state(folder).map(|state| state.meta_hash);
+    if synced.as_deref() == Some(hash(&meta).as_str()) {
+        return Ok(None);
+    }
+
+    if let Ok(local_meta) = std::fs::read(Path::new(folder).join(META_FILE)) {
+        if synced.as_deref() != Some(hash(&local_meta).as_str()) {
+            warn!("The search index in {} has commits that were never copied to the storage, keeping it", folder);
+            return Ok(None);
+        }
+    }
+
+    std::fs::create_dir_all(folder).with_context(|| format!("Error creating folder {}", folder))?;
+    let local = local_files(folder)?;
+    let remote = remote_files(operator).await?;
+
+    for (name, size) in &remote {
+        if name == META_FILE || (name != VERSION_FILE && local.get(name) == Some(size)) {
+            continue;
+        }
+
+        let content = operator
+            .read(&layout::index_path(name))
+            .await
+            .with_context(|| format!("Error reading {}", layout::index_path(name)))?;
+        std::fs::write(Path::new(folder).join(name), content)
+            .with_context(|| format!("Error writing {} of the index in {}", name, folder))?;
+        report.copied += 1;
+    }
+
+    std::fs::write(Path::new(folder).join(META_FILE), &meta)
+        .with_context(|| format!("Error writing {} of the index in {}", META_FILE, folder))?;
+    report.copied += 1;
+
+    for name in local.keys().filter(|name| *name != META_FILE && !remote.contains_key(*name)) {
+        std::fs::remove_file(Path::new(folder).join(name))
+            .with_context(|| format!("Error removing {} of the index in {}", name, folder))?;
+        report.removed += 1;
+    }
+
+    write_sync_state(folder, &meta)?;
+    info!("Copied {} index files from the storage, removed {}", report.copied, report.removed);
+
+    Ok(Some(report))
+}
+
+/// Names of the segment files meta.json refers to
+fn segment_files(meta: &[u8]) -> anyhow::Result<HashSet<String>> {
+    let meta: IndexMeta = serde_json::from_slice(meta).with_context(|| format!("Error deserializing {}", META_FILE))?;
+    let mut files = HashSet::new();
+
+    for segment in meta.segments {
+        // Files are named by the segment id without dashes
+        let id = segment.segment_id.replace('-', "").to_lowercase();
+        files.extend(SEGMENT_EXTENSIONS.iter().map(|extension| format!("{}.{}", id, extension)));
+        if let Some(deletes) = segment.deletes {
+            files.insert(format!("{}.{}.del", id, deletes.opstamp));
+        }
+    }
+
+    Ok(files)
+}
+
+fn hash(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn read_sync_state(folder: &str) -> Option<SyncState> {
+    let state = std::fs::read(Path::new(folder).join(SYNC_FILE)).ok()?;
+    serde_json::from_slice(&state).ok()
+}
+
+fn write_sync_state(folder: &str, meta: &[u8]) -> anyhow::Result<()> {
+    let path = Path::new(folder).join(SYNC_FILE);
+    let state_json = serde_json::to_string_pretty(&SyncState { meta_hash: hash(meta) })
+        .with_context(|| "Error serializing index sync state")?;
+
+    std::fs::write(&path, state_json).with_context(|| format!("Error writing {}", path.display()))
+}
+
+/// Names and sizes of the files of the index, leaving out tantivy's own bookkeeping like lock files,
+/// which start with a dot, and the record of the last copy
+fn local_files(folder: &str) -> anyhow::Result<HashMap<String, u64>> {
+    let mut files = HashMap::new();
+
+    for entry in std::fs::read_dir(folder).with_context(|| format!("Error listing {}", folder))? {
+        let entry = entry.with_context(|| format!("Error listing {}", folder))?;
+        let metadata = entry.metadata().with_context(|| format!("Error reading {}", entry.path().display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if metadata.is_file() && !name.starts_with('.') && name != SYNC_FILE {
+            files.insert(name, metadata.len());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Names and sizes of the files of the index in the storage
+async fn remote_files(operator: &Operator) -> anyhow::Result<HashMap<String, u64>> {
+    if !operator.is_exist(layout::INDEX_FOLDER).await.unwrap_or(false) {
+        return Ok(HashMap::new());
+    }
+
+    let lister = operator
+        .lister_with(layout::INDEX_FOLDER)
+        .metakey(Metakey::Mode | Metakey::ContentLength)
+        .await
+        .with_context(|| format!("Error listing {}", layout::INDEX_FOLDER))?;
+
+    let files = lister
+        .try_filter(|entry| futures::future::ready(entry.metadata().is_file()))
+        .map_ok(|entry| (entry.name().to_string(), entry.metadata().content_length()))
+        .try_collect::<HashMap<_, _>>()
+        .await
+        .with_context(|| format!("Error listing {}", layout::INDEX_FOLDER))?;
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_client::email::Email;
+    use mail_parser::Message;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        conf,
+        core::{
+            mailboxes::MailboxNames,
+            search::{create_indexer, create_rebuild_indexer, finish_rebuild, search, write_document, Indexer, SearchOptions},
+            storage::fs_operator,
+        },
+    };
+
+    fn search_conf(dir: &TempDir) -> conf::Search {
+        conf::Search {
+            enable: true,
+            folder: dir.path().join("search").to_str().unwrap().to_string(),
+            languages: vec![],
+            sync: true,
+        }
+    }
+
+    fn write(indexer: &mut Indexer, id: &str, subject: &str) {
+        let email = serde_json::from_str::<Email>(&format!(r#"{{"id": "{}", "blobId": "B{}", "subject": "{}"}}"#, id, id, subject)).unwrap();
+        write_document(indexer, &email, &Message::default(), &MailboxNames::new()).unwrap();
+        indexer.commit().unwrap();
+    }
+
+    fn count(conf: &conf::Search, query: &str) -> usize {
+        search(conf, query.to_string(), &SearchOptions::default()).unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn test_push_and_pull_index() {
+        let archive = TempDir::new().unwrap();
+        let operator = fs_operator(&archive);
+        let backup_machine = TempDir::new().unwrap();
+        let other_machine = TempDir::new().unwrap();
+        let backup_conf = search_conf(&backup_machine);
+        let other_conf = search_conf(&other_machine);
+
+        assert_eq!(pull_index(&operator, &other_conf.folder).await.unwrap(), None);
+
+        let mut indexer = create_indexer(&backup_conf).unwrap();
+        write(&mut indexer, "M1", "Invoice");
+        push_index(&operator, indexer.sync_folder().unwrap()).await.unwrap();
+        assert!(pull_index(&operator, &other_conf.folder).await.unwrap().is_some());
+        assert_eq!(count(&other_conf, "invoice"), 1);
+
+        // Later commits only copy the new segments
+        write(&mut indexer, "M2", "Receipt");
+        let pushed = push_index(&operator, &backup_conf.folder).await.unwrap();
+        assert!(pushed.copied < local_files(&backup_conf.folder).unwrap().len() as u64);
+        pull_index(&operator, &other_conf.folder).await.unwrap().unwrap();
+        assert_eq!(count(&other_conf, "invoice OR receipt"), 2);
+
+        // Nothing to pull when the local index is up to date
+        assert_eq!(pull_index(&operator, &other_conf.folder).await.unwrap(), None);
+        assert_eq!(pull_index(&operator, &backup_conf.folder).await.unwrap(), None);
+        drop(indexer);
+
+        // A rebuilt index starts over with fewer commits, but is still newer than the copies
+        let mut rebuild = create_rebuild_indexer(&backup_conf).unwrap();
+        write(&mut rebuild, "M3", "Contract");
+        finish_rebuild(&backup_conf, rebuild).unwrap();
+        push_index(&operator, &backup_conf.folder).await.unwrap();
+        pull_index(&operator, &other_conf.folder).await.unwrap().unwrap();
+        assert_eq!(count(&other_conf, "invoice OR receipt"), 0);
+        assert_eq!(count(&other_conf, "contract"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pull_keeps_commits_never_pushed() {
+        let archive = TempDir::new().unwrap();
+        let operator = fs_operator(&archive);
+        let backup_machine = TempDir::new().unwrap();
+        let other_machine = TempDir::new().unwrap();
+        let backup_conf = search_conf(&backup_machine);
+        let other_conf = search_conf(&other_machine);
+
+        let mut indexer = create_indexer(&backup_conf).unwrap();
+        write(&mut indexer, "M1", "Invoice");
+        push_index(&operator, &backup_conf.folder).await.unwrap();
+
+        let mut other_indexer = create_indexer(&other_conf).unwrap();
+        write(&mut other_indexer, "M2", "Receipt");
+
+        assert_eq!(pull_index(&operator, &other_conf.folder).await.unwrap(), None);
+        assert_eq!(count(&other_conf, "receipt"), 1);
+        assert_eq!(count(&other_conf, "invoice"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_push_missing_segment() {
+        let archive = TempDir::new().unwrap();
+        let operator = fs_operator(&archive);
+        let machine = TempDir::new().unwrap();
+        let conf = search_conf(&machine);
+
+        let mut indexer = create_indexer(&conf).unwrap();
+        write(&mut indexer, "M1", "Invoice");
+        push_index(&operator, &conf.folder).await.unwrap();
+        let pushed_meta = operator.read(&layout::index_path(META_FILE)).await.unwrap();
+
+        // As if a merge removed a segment of the meta.json being pushed
+        write(&mut indexer, "M2", "Receipt");
+        let meta = std::fs::read(Path::new(&conf.folder).join(META_FILE)).unwrap();
+        let remote = remote_files(&operator).await.unwrap();
+        let new_segment = segment_files(&meta).unwrap().into_iter().find(|name| !remote.contains_key(name)).unwrap();
+        std::fs::remove_file(Path::new(&conf.folder).join(new_segment)).unwrap();
+
+        assert!(push_index(&operator, &conf.folder).await.is_err());
+        assert_eq!(operator.read(&layout::index_path(META_FILE)).await.unwrap(), pushed_meta);
+    }
+}
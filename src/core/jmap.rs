@@ -1,25 +1,35 @@
+use std::sync::Arc;
+
 use anyhow::Context;
 use jmap_client::client::{Client, Credentials};
+use tokio::sync::Mutex;
 
 use crate::conf::{self, AuthMode};
 
+use super::oauth::TokenSource;
+
 /**
  * Create a JMAP client with the given configuration
  * Return error if the client cannot be created
  */
-pub async fn create_client(jmap_conf: conf::Jmap) -> anyhow::Result<Client> {
-    let username = jmap_conf.username.unwrap_or_default();
+pub async fn create_client(jmap_conf: &conf::Jmap) -> anyhow::Result<Client> {
+    let username = jmap_conf.username.clone().unwrap_or_default();
     let secret = jmap_conf
         .secret
+        .as_ref()
         .with_context(|| {
             "No secret found for JMAP client"
         })?;
 
     let credentials = match jmap_conf.auth_mode {
-        AuthMode::Basic => Credentials::basic(&username, &secret),
-        AuthMode::Token => Credentials::bearer(&secret),
+        AuthMode::Basic => Credentials::basic(&username, secret),
+        AuthMode::Token | AuthMode::OAuth => Credentials::bearer(secret),
     };
 
+    connect(jmap_conf, credentials).await
+}
+
+async fn connect(jmap_conf: &conf::Jmap, credentials: Credentials) -> anyhow::Result<Client> {
     let client: Client = Client::new()
         .credentials(credentials)
         // Takes iterator of hosts to trust
@@ -32,3 +42,136 @@ pub async fn create_client(jmap_conf: conf::Jmap) -> anyhow::Result<Client> {
 
     Ok(client)
 }
+
+/// A JMAP client that is reconnected with a fresh access token when an OAuth token is about to expire
+pub struct JmapConnection {
+    conf: conf::Jmap,
+    tokens: Option<Mutex<TokenSource>>,
+    client: Mutex<(Arc<Client>, String)>,
+}
+
+impl JmapConnection {
+    /**
+     * Connect to the JMAP server. With OAuth this logs in if there is no refresh token in the keyring yet.
+     * Other auth modes need the secret to be set in the config, see `Conf::set_jmap_secret`.
+     */
+    pub async fn new(jmap_conf: &conf::Jmap, name: &str) -> anyhow::Result<Self> {
+        if jmap_conf.auth_mode != AuthMode::OAuth {
+            let client = create_client(jmap_conf).await?;
+            return Ok(JmapConnection {
+                conf: jmap_conf.clone(),
+                tokens: None,
+                client: Mutex::new((Arc::new(client), String::new())),
+            });
+        }
+
+        let oauth = jmap_conf
+            .oauth
+            .as_ref()
+            .context("The oauth auth mode needs a [jmap.oauth] section with at least a client_id")?;
+        let tokens = TokenSource::login_or_restore(oauth, &jmap_conf.host, name).await?;
+        Self::with_tokens(jmap_conf, tokens).await
+    }
+
+    /// Connect with OAuth tokens obtained elsewhere
+    pub async fn with_tokens(jmap_conf: &conf::Jmap, mut tokens: TokenSource) -> anyhow::Result<Self> {
+        let access_token = tokens.access_token().await?;
+        let client = connect(jmap_conf, Credentials::bearer(&access_token)).await?;
+
+        Ok(JmapConnection {
+            conf: jmap_conf.clone(),
+            tokens: Some(Mutex::new(tokens)),
+            client: Mutex::new((Arc::new(client), access_token)),
+        })
+    }
+
+    /**
+     * The client to make the next requests with.
+     * Callers should get the client again for every batch of requests, rather than keeping it for a whole backup.
+     */
+    pub async fn client(&self) -> anyhow::Result<Arc<Client>> {
+        let mut client = self.client.lock().await;
+
+        if let Some(tokens) = &self.tokens {
+            let access_token = tokens.lock().await.access_token().await?;
+            if access_token != client.1 {
+                let reconnected = connect(&self.conf, Credentials::bearer(&access_token)).await?;
+                *client = (Arc::new(reconnected), access_token);
+            }
+        }
+
+        Ok(client.0.clone())
+    }
+}
+
+/// A JMAP server on localhost for tests, serving a session and handing every other request to the handler
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{future::Future, pin::Pin, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::conf::SecretSource;
+
+    /// Answers a request given its path, writing the whole response so bodies can be streamed
+    pub type Handler = Arc<dyn Fn(String, TcpStream) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+    pub async fn mock_server(handler: Handler) -> JmapConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let session = format!(
+            r#"{{"capabilities": {{}}, "accounts": {{"A1": {{"name": "test", "isPersonal": true, "isReadOnly": false, "accountCapabilities": {{}}}}}},
+            "primaryAccounts": {{"urn:ietf:params:jmap:mail": "A1"}}, "username": "test", "apiUrl": "{base}/api",
+            "downloadUrl": "{base}/download/{{accountId}}/{{blobId}}/{{name}}?type={{type}}", "uploadUrl": "{base}/upload/{{accountId}}",
+            "eventSourceUrl": "{base}/events?types={{types}}&closeafter={{closeafter}}&ping={{ping}}", "state": "S1"}}"#
+        );
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (handler, session) = (handler.clone(), session.clone());
+
+                tokio::spawn(async move {
+                    // Only GET requests are expected, so the request ends with its headers
+                    let mut request = vec![];
+                    let mut buffer = [0; 4096];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = socket.read(&mut buffer).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                    }
+
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    if path == "/.well-known/jmap" {
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            session.len(),
+                            session
+                        );
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                    } else {
+                        handler(path, socket).await;
+                    }
+                });
+            }
+        });
+
+        let jmap_conf = conf::Jmap {
+            host: base,
+            auth_mode: AuthMode::Token,
+            username: None,
+            secret: Some("token".to_string()),
+            secret_source: SecretSource::default(),
+            oauth: None,
+        };
+
+        JmapConnection::new(&jmap_conf, "test").await.unwrap()
+    }
+}
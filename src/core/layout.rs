@@ -0,0 +1,269 @@
+// The on-storage layout of an archive.
+// Every path postkasse reads or writes is built here, and the layout version is recorded in
+// a format descriptor at the root of the archive so future layout changes can be migrated.
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::info;
+use opendal::Operator;
+use serde::{Deserialize, Serialize};
+
+/// Version of the layout written by this version of postkasse.
+/// Messages with split out attachments are read alongside whole ones, so they did not need a new version
+pub const LAYOUT_VERSION: u32 = 1;
+
+pub const DESCRIPTOR_PATH: &str = "/postkasse.json";
+pub const MAILBOXES_FOLDER: &str = "/mailboxes/";
+pub const EMAILS_FOLDER: &str = "/emails/";
+pub const THREADS_FOLDER: &str = "/threads/";
+pub const BLOBS_FOLDER: &str = "/blobs/";
+pub const ATTACHMENTS_FOLDER: &str = "/attachments/";
+pub const SKELETONS_FOLDER: &str = "/skeletons/";
+pub const PROGRESS_FOLDER: &str = "/progress/";
+/// Copy of the search index, derived from the archive so it is not one of the data folders
+pub const INDEX_FOLDER: &str = "/index/";
+
+/// Folders holding archived data, as opposed to bookkeeping like the lock and descriptor
+pub const DATA_FOLDERS: [&str; 7] = [
+    MAILBOXES_FOLDER,
+    EMAILS_FOLDER,
+    THREADS_FOLDER,
+    BLOBS_FOLDER,
+    ATTACHMENTS_FOLDER,
+    SKELETONS_FOLDER,
+    PROGRESS_FOLDER,
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArchiveFormat {
+    pub layout_version: u32,
+    pub created_at: DateTime<Utc>,
+    /// Version of postkasse that last wrote the descriptor
+    pub written_by: String,
+}
+
+impl ArchiveFormat {
+    fn current() -> Self {
+        ArchiveFormat {
+            layout_version: LAYOUT_VERSION,
+            created_at: Utc::now(),
+            written_by: format!("postkasse {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+// Blobs are split into folders on the first two characters of the blob id
+pub fn blob_path(blob_id: &str) -> String {
+    format!("{}{}/{}", BLOBS_FOLDER, &blob_id[..2], blob_id)
+}
+
+// Attachments are stored by the hex SHA-256 of their body and split on the first two characters
+pub fn attachment_path(hash: &str) -> String {
+    format!("{}{}/{}", ATTACHMENTS_FOLDER, &hash[..2], hash)
+}
+
+// Skeletons of messages with split out attachments, split like blobs
+pub fn skeleton_path(blob_id: &str) -> String {
+    format!("{}{}/{}.json", SKELETONS_FOLDER, &blob_id[..2], blob_id)
+}
+
+// Split the emails into folders based on the first three characters of the id
+// Based on the assumption that the ids are random enough to be evenly distributed
+// Fastmail uses same initial character for all emails, so we use the first 3 characters
+// Worst case scenario is that we have 16^3 = 4096 folders
+pub fn email_path(id: &str) -> String {
+    format!("{}{}/{}.json", EMAILS_FOLDER, &id[..3], id)
+}
+
+// Threads are split like emails, as there can be almost as many of them
+pub fn thread_path(id: &str) -> String {
+    format!("{}{}/{}.json", THREADS_FOLDER, &id[..3], id)
+}
+
+// No need to split into subdirectories since we don't expect many mailboxes
+pub fn mailbox_path(id: &str) -> String {
+    format!("{}{}.json", MAILBOXES_FOLDER, id)
+}
+
+pub fn progress_path(file: &str) -> String {
+    format!("{}{}", PROGRESS_FOLDER, file)
+}
+
+// The index is a single folder of files, as tantivy writes it
+pub fn index_path(file: &str) -> String {
+    format!("{}{}", INDEX_FOLDER, file)
+}
+
+/**
+ * Check that the archive uses the layout this version of postkasse understands.
+ * An empty archive gets a descriptor for the current layout when `create` is set.
+ * Archives written before layouts were versioned have no descriptor and are reported as version 0.
+ */
+pub async fn open_archive(operator: &Operator, create: bool) -> anyhow::Result<ArchiveFormat> {
+    let format = match read_format(operator).await? {
+        Some(format) => format,
+        None if create && is_empty(operator).await? => {
+            let format = ArchiveFormat::current();
+            write_format(operator, &format).await?;
+            return Ok(format);
+        }
+        None if is_empty(operator).await? => {
+            anyhow::bail!("Archive is empty, run a backup first")
+        }
+        None => anyhow::bail!(
+            "Archive was created by an older version of postkasse. Run `postkasse upgrade-archive` to upgrade it"
+        ),
+    };
+
+    if format.layout_version > LAYOUT_VERSION {
+        anyhow::bail!(
+            "Archive uses layout version {} which is newer than the supported version {}. Upgrade postkasse",
+            format.layout_version,
+            LAYOUT_VERSION
+        );
+    }
+
+    if format.layout_version < LAYOUT_VERSION {
+        anyhow::bail!(
+            "Archive uses layout version {}, the current version is {}. Run `postkasse upgrade-archive` to upgrade it",
+            format.layout_version,
+            LAYOUT_VERSION
+        );
+    }
+
+    Ok(format)
+}
+
+/**
+ * Layout version of the archive, 0 for archives written before layouts were versioned.
+ * None if the archive is empty.
+ */
+pub async fn layout_version(operator: &Operator) -> anyhow::Result<Option<u32>> {
+    match read_format(operator).await? {
+        Some(format) => Ok(Some(format.layout_version)),
+        None if is_empty(operator).await? => Ok(None),
+        None => Ok(Some(0)),
+    }
+}
+
+/**
+ * Upgrade the archive in place to the current layout, one version at a time.
+ * Returns the layout version the archive was upgraded from.
+ */
+pub async fn upgrade_archive(operator: &Operator) -> anyhow::Result<u32> {
+    let from = match read_format(operator).await? {
+        Some(format) => format.layout_version,
+        None => 0,
+    };
+
+    if from > LAYOUT_VERSION {
+        anyhow::bail!("Archive uses layout version {} which is newer than this version of postkasse", from);
+    }
+
+    for version in from..LAYOUT_VERSION {
+        info!("Upgrading archive from layout version {} to {}", version, version + 1);
+        match version {
+            // Version 0 archives have the version 1 layout, only the descriptor is missing
+            0 => {}
+            _ => unreachable!("No migration from layout version {}", version),
+        }
+    }
+
+    if from < LAYOUT_VERSION {
+        let mut format = read_format(operator).await?.unwrap_or_else(ArchiveFormat::current);
+        format.layout_version = LAYOUT_VERSION;
+        format.written_by = ArchiveFormat::current().written_by;
+        write_format(operator, &format).await?;
+    }
+
+    Ok(from)
+}
+
+async fn read_format(operator: &Operator) -> anyhow::Result<Option<ArchiveFormat>> {
+    let exists = operator
+        .is_exist(DESCRIPTOR_PATH)
+        .await
+        .with_context(|| format!("Error checking if {} exists", DESCRIPTOR_PATH))?;
+
+    if !exists {
+        return Ok(None);
+    }
+
+    let descriptor = operator
+        .read(DESCRIPTOR_PATH)
+        .await
+        .with_context(|| format!("Error reading {}", DESCRIPTOR_PATH))?;
+
+    let format = serde_json::from_slice(&descriptor)
+        .with_context(|| format!("Error deserializing {}", DESCRIPTOR_PATH))?;
+
+    Ok(Some(format))
+}
+
+async fn write_format(operator: &Operator, format: &ArchiveFormat) -> anyhow::Result<()> {
+    let format_json = serde_json::to_string_pretty(format)
+        .with_context(|| "Error serializing archive format")?;
+
+    operator
+        .write(DESCRIPTOR_PATH, format_json)
+        .await
+        .with_context(|| format!("Error writing {}", DESCRIPTOR_PATH))
+}
+
+async fn is_empty(operator: &Operator) -> anyhow::Result<bool> {
+    for folder in DATA_FOLDERS {
+        let exists = operator
+            .is_exist(folder)
+            .await
+            .with_context(|| format!("Error checking if {} exists", folder))?;
+
+        if exists {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::fs_operator;
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(blob_path("Gabcdef"), "/blobs/Ga/Gabcdef");
+        assert_eq!(email_path("Mabcdef"), "/emails/Mab/Mabcdef.json");
+        assert_eq!(thread_path("Tabcdef"), "/threads/Tab/Tabcdef.json");
+        assert_eq!(mailbox_path("P1"), "/mailboxes/P1.json");
+        assert_eq!(progress_path("email.json"), "/progress/email.json");
+        assert_eq!(index_path("meta.json"), "/index/meta.json");
+        assert_eq!(attachment_path("ab12cd"), "/attachments/ab/ab12cd");
+        assert_eq!(skeleton_path("Gabcdef"), "/skeletons/Ga/Gabcdef.json");
+    }
+
+    #[tokio::test]
+    async fn test_new_archive_gets_descriptor() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        assert!(open_archive(&operator, false).await.is_err());
+        let format = open_archive(&operator, true).await.unwrap();
+        assert_eq!(format.layout_version, LAYOUT_VERSION);
+        assert_eq!(open_archive(&operator, false).await.unwrap(), format);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_unversioned_archive() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+        operator.write(&email_path("Mabcdef"), "{}").await.unwrap();
+
+        // Existing archives without a descriptor must be upgraded explicitly
+        assert!(open_archive(&operator, true).await.is_err());
+        assert_eq!(upgrade_archive(&operator).await.unwrap(), 0);
+        assert_eq!(open_archive(&operator, false).await.unwrap().layout_version, LAYOUT_VERSION);
+        assert_eq!(upgrade_archive(&operator).await.unwrap(), LAYOUT_VERSION);
+    }
+}
@@ -0,0 +1,277 @@
+// Lease based lock stored in the storage backend to keep several processes from writing the same archive.
+// Storage backends have no compare-and-swap we can rely on across every scheme, so the lock is
+// written, then read back to check that no other process won the race.
+use std::{future::Future, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use console::style;
+use log::{error, warn};
+use opendal::Operator;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinHandle};
+
+const LOCK_PATH: &str = "/lock.json";
+/// How long a lock is valid without being renewed, after which it is considered stale
+const LEASE_SECONDS: i64 = 10 * 60;
+/// How often the lock is renewed while held
+const HEARTBEAT_SECONDS: u64 = 60;
+/// Time to let a competing writer finish before reading the lock back
+const SETTLE_MILLIS: u64 = 500;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LockInfo {
+    /// Unique id of this lock, used to tell our lock apart from another process' lock
+    pub id: String,
+    pub owner: String,
+    pub host: String,
+    pub pid: u32,
+    pub command: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl LockInfo {
+    fn new(command: &str) -> Self {
+        let now = Utc::now();
+        let pid = std::process::id();
+
+        LockInfo {
+            id: format!("{}-{}", pid, now.timestamp_nanos_opt().unwrap_or_default()),
+            owner: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_else(|_| "unknown".to_string()),
+            host: gethostname::gethostname().to_string_lossy().to_string(),
+            pid,
+            command: command.to_string(),
+            acquired_at: now,
+            expires_at: now + chrono::Duration::seconds(LEASE_SECONDS),
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at < now
+    }
+}
+
+/// A held lock, renewed in the background until released
+pub struct StorageLock {
+    operator: Operator,
+    info: LockInfo,
+    heartbeat: JoinHandle<()>,
+    /// Set by the heartbeat to the lock of the process that took the lock over
+    taken_over: watch::Receiver<Option<LockInfo>>,
+}
+
+/**
+ * Take the lock on the archive.
+ * Fails if another process holds a lock that has not expired, unless `break_lock` is set.
+ * Stale locks left behind by crashed processes are taken over with a warning.
+ */
+pub async fn acquire(operator: &Operator, command: &str, break_lock: bool) -> anyhow::Result<StorageLock> {
+    if let Some(existing) = read_lock(operator).await? {
+        if break_lock {
+            warn!("Breaking lock held by {}", describe(&existing));
+        } else if existing.is_expired(Utc::now()) {
+            warn!("Taking over stale lock held by {}", describe(&existing));
+        } else {
+            anyhow::bail!(
+                "Archive is locked by {} until {}. If you are sure no other process is running use --break-lock",
+                describe(&existing),
+                existing.expires_at
+            );
+        }
+    }
+
+    let info = LockInfo::new(command);
+    write_lock(operator, &info).await?;
+
+    // Another process may have written its lock at the same time, the last writer wins
+    tokio::time::sleep(Duration::from_millis(SETTLE_MILLIS)).await;
+    match read_lock(operator).await? {
+        Some(current) if current.id == info.id => {}
+        Some(current) => anyhow::bail!("Lost the race for the archive lock to {}", describe(&current)),
+        None => anyhow::bail!("Archive lock disappeared right after it was written"),
+    }
+
+    let (taken_over_sender, taken_over) = watch::channel(None);
+    let heartbeat = tokio::spawn(heartbeat(operator.clone(), info.clone(), taken_over_sender));
+
+    Ok(StorageLock {
+        operator: operator.clone(),
+        info,
+        heartbeat,
+        taken_over,
+    })
+}
+
+impl StorageLock {
+    /**
+     * Run a task while holding the lock, then release it.
+     * The task is cancelled if another process takes the lock over, as both would be writing the archive.
+     * An error of the task is returned rather than an error releasing the lock, which is logged instead.
+     */
+    pub async fn hold<T>(self, task: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        let mut taken_over = self.taken_over.clone();
+
+        let result = tokio::select! {
+            result = task => result,
+            Ok(current) = taken_over.wait_for(Option::is_some) => {
+                let current = current.clone().unwrap_or_else(|| self.info.clone());
+                Err(anyhow::anyhow!("Stopped as the archive lock was taken over by {}", describe(&current)))
+            }
+        };
+
+        match (result, self.release().await) {
+            (Ok(value), released) => released.map(|_| value),
+            (Err(e), Err(release_error)) => {
+                warn!("Error releasing archive lock. {:#}", release_error);
+                Err(e)
+            }
+            (Err(e), Ok(())) => Err(e),
+        }
+    }
+
+    /// Stop renewing the lock and remove it, unless another process has taken it over in the meantime
+    pub async fn release(self) -> anyhow::Result<()> {
+        self.heartbeat.abort();
+
+        if let Some(current) = read_lock(&self.operator).await? {
+            if current.id == self.info.id {
+                self.operator
+                    .delete(LOCK_PATH)
+                    .await
+                    .with_context(|| format!("Error removing lock {}", LOCK_PATH))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn heartbeat(operator: Operator, mut info: LockInfo, taken_over: watch::Sender<Option<LockInfo>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(HEARTBEAT_SECONDS));
+    interval.tick().await; // The first tick completes immediately
+
+    loop {
+        interval.tick().await;
+
+        match read_lock(&operator).await {
+            Ok(Some(current)) if current.id != info.id => {
+                let err = format!("Archive lock was taken over by {}", describe(&current));
+                error!("{}", style(err).red().bold());
+                let _ = taken_over.send(Some(current));
+                return;
+            }
+            Err(e) => {
+                warn!("Error reading archive lock, renewing anyway. {}", e);
+            }
+            _ => {}
+        }
+
+        info.expires_at = Utc::now() + chrono::Duration::seconds(LEASE_SECONDS);
+        if let Err(e) = write_lock(&operator, &info).await {
+            warn!("Error renewing archive lock. {}", e);
+        }
+    }
+}
+
+async fn read_lock(operator: &Operator) -> anyhow::Result<Option<LockInfo>> {
+    let exists = operator
+        .is_exist(LOCK_PATH)
+        .await
+        .with_context(|| "Error checking if lock exists")?;
+
+    if !exists {
+        return Ok(None);
+    }
+
+    let lock = operator
+        .read(LOCK_PATH)
+        .await
+        .with_context(|| format!("Error reading lock {}", LOCK_PATH))?;
+
+    // A lock we cannot make sense of is treated as stale rather than blocking backups forever
+    match serde_json::from_slice(&lock) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) => {
+            warn!("Ignoring unreadable lock {}. {}", LOCK_PATH, e);
+            Ok(None)
+        }
+    }
+}
+
+async fn write_lock(operator: &Operator, info: &LockInfo) -> anyhow::Result<()> {
+    let lock_json = serde_json::to_string_pretty(info)
+        .with_context(|| "Error serializing lock")?;
+
+    operator
+        .write(LOCK_PATH, lock_json)
+        .await
+        .with_context(|| format!("Error writing lock {}", LOCK_PATH))
+}
+
+fn describe(info: &LockInfo) -> String {
+    format!("{}@{} (pid {}, {})", info.owner, info.host, info.pid, info.command)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::fs_operator;
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        let lock = acquire(&operator, "backup", false).await.unwrap();
+        assert!(acquire(&operator, "backup", false).await.is_err());
+
+        lock.release().await.unwrap();
+        assert!(!operator.is_exist(LOCK_PATH).await.unwrap());
+
+        let lock = acquire(&operator, "backup", false).await.unwrap();
+        lock.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_and_broken_locks() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        let mut stale = LockInfo::new("backup");
+        stale.expires_at = Utc::now() - chrono::Duration::seconds(1);
+        write_lock(&operator, &stale).await.unwrap();
+
+        // Stale locks are taken over
+        let lock = acquire(&operator, "backup", false).await.unwrap();
+
+        // Live locks can only be broken explicitly, and the previous holder does not remove the new lock
+        let broken = acquire(&operator, "migrate", true).await.unwrap();
+        lock.release().await.unwrap();
+        assert_eq!(read_lock(&operator).await.unwrap().unwrap().command, "migrate");
+        broken.release().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_takeover_stops_task() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        let lock = acquire(&operator, "backup", false).await.unwrap();
+        assert_eq!(lock.hold(async { Ok(1) }).await.unwrap(), 1);
+        assert!(!operator.is_exist(LOCK_PATH).await.unwrap());
+
+        // Another process breaks the lock in the middle of the task, which is stopped on the next heartbeat
+        let lock = acquire(&operator, "backup", false).await.unwrap();
+        let other = LockInfo::new("migrate");
+        write_lock(&operator, &other).await.unwrap();
+        let result = lock.hold(futures::future::pending::<anyhow::Result<()>>()).await;
+
+        assert!(result.unwrap_err().to_string().contains("taken over"));
+        assert_eq!(read_lock(&operator).await.unwrap(), Some(other));
+    }
+}
@@ -1,9 +1,11 @@
+use std::collections::HashMap;
+
 use anyhow::Context;
 use futures::{stream, StreamExt};
 use jmap_client::{client::Client, mailbox::Mailbox};
 use opendal::Operator;
 
-use super::progress::Progressable;
+use super::{layout, progress::Progressable};
 
 pub(crate) async fn mailboxes(
     client: &Client,
@@ -12,18 +14,18 @@ pub(crate) async fn mailboxes(
     pb: &dyn Progressable,
 ) -> anyhow::Result<()> {
 
-    let total = fetch_total_count(&client).await?;
+    let total = fetch_total_count(client).await?;
     pb.set_length(total.try_into().unwrap());
 
     loop {
-        let mailboxes_res = fetch_mailboxes(0, max_objects, &client).await?;
+        let mailboxes_res = fetch_mailboxes(0, max_objects, client).await?;
         let length = mailboxes_res.len();
 
         // Iterate with stream over mailboxes and process them
         stream::iter(
             mailboxes_res
                 .iter()
-                .map(|mailbox| process_mailbox(mailbox, &operator)),
+                .map(|mailbox| process_mailbox(mailbox, operator)),
         )
         .buffer_unordered(50)
         .collect::<Vec<_>>()
@@ -91,7 +93,7 @@ async fn fetch_mailboxes(
 
 async fn process_mailbox(mailbox: &Mailbox, operator: &Operator) -> anyhow::Result<()> {
     let id = mailbox.id().unwrap();
-    let path = format!("/mailboxes/{}.json", id); // No need to split into subdirectories since we don't expect many mailboxes
+    let path = layout::mailbox_path(id);
     let mailbox_json = serde_json::to_string(&mailbox)
         .with_context(|| format!("Error serializing mailbox {}", id))?;
 
@@ -101,3 +103,83 @@ async fn process_mailbox(mailbox: &Mailbox, operator: &Operator) -> anyhow::Resu
         .await
         .with_context(|| format!("Error writing mailbox {}", id))
 }
+
+/// Full name of each mailbox by id, from the top level mailbox down, e.g. `["Inbox", "Receipts"]`
+pub type MailboxNames = HashMap<String, Vec<String>>;
+
+/**
+ * Read the names of the mailboxes in the archive, used to index emails by mailbox name.
+ * Mailboxes are backed up before emails, so every mailbox an email is in should be known.
+ */
+pub async fn read_mailbox_names(operator: &Operator) -> anyhow::Result<MailboxNames> {
+    if !operator.is_exist(layout::MAILBOXES_FOLDER).await.unwrap_or(false) {
+        return Ok(MailboxNames::new());
+    }
+
+    let entries = operator
+        .list(layout::MAILBOXES_FOLDER)
+        .await
+        .with_context(|| format!("Error listing {}", layout::MAILBOXES_FOLDER))?;
+
+    let mut mailboxes = HashMap::new();
+    for entry in entries.iter().filter(|entry| entry.path().ends_with(".json")) {
+        let mailbox_json = operator
+            .read(entry.path())
+            .await
+            .with_context(|| format!("Error reading mailbox {}", entry.path()))?;
+        let mailbox: Mailbox = serde_json::from_slice(&mailbox_json)
+            .with_context(|| format!("Error deserializing mailbox {}", entry.path()))?;
+
+        if let Some(id) = mailbox.id() {
+            mailboxes.insert(id.to_string(), mailbox);
+        }
+    }
+
+    Ok(mailboxes
+        .keys()
+        .map(|id| (id.clone(), mailbox_name(&mailboxes, id)))
+        .collect())
+}
+
+fn mailbox_name(mailboxes: &HashMap<String, Mailbox>, id: &str) -> Vec<String> {
+    let mut name = vec![];
+    let mut current = mailboxes.get(id);
+
+    // Bounded by the number of mailboxes in case the server reported a cycle of parents
+    while let Some(mailbox) = current.filter(|_| name.len() < mailboxes.len()) {
+        name.push(mailbox.name().unwrap_or_default().to_string());
+        current = mailbox.parent_id().and_then(|parent_id| mailboxes.get(parent_id));
+    }
+
+    name.reverse();
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::fs_operator;
+
+    #[tokio::test]
+    async fn test_read_mailbox_names() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        assert!(read_mailbox_names(&operator).await.unwrap().is_empty());
+
+        operator
+            .write(&layout::mailbox_path("P1"), r#"{"id":"P1","name":"Inbox","parentId":null}"#)
+            .await
+            .unwrap();
+        operator
+            .write(&layout::mailbox_path("P2"), r#"{"id":"P2","name":"Receipts","parentId":"P1"}"#)
+            .await
+            .unwrap();
+
+        let names = read_mailbox_names(&operator).await.unwrap();
+        assert_eq!(names["P1"], vec!["Inbox"]);
+        assert_eq!(names["P2"], vec!["Inbox", "Receipts"]);
+    }
+}
@@ -0,0 +1,414 @@
+use anyhow::Context;
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+use opendal::{Metakey, Operator};
+use sha2::{Digest, Sha256};
+
+use super::{blob, layout, progress::Progressable};
+
+#[derive(Debug, Default)]
+pub struct MigrateReport {
+    pub copied: u64,
+    pub skipped: u64,
+    pub bytes: u64,
+    pub failed: Vec<String>,
+    /// Layout version of the source, when it was older than the layout written to the target
+    pub upgraded_from: Option<u32>,
+}
+
+/// How messages are converted on their way to the target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Convert {
+    /// Copy messages in the form they are stored in
+    None,
+    /// Split attachments of at least `threshold` bytes out of messages stored whole
+    Split { threshold: usize },
+    /// Reassemble messages with split out attachments into whole messages
+    Join,
+}
+
+enum Outcome {
+    Copied(u64),
+    Skipped,
+}
+
+/**
+ * Copy an archive from one storage backend to another.
+ * Files already present in the target with the same size are skipped, so an interrupted
+ * migration can simply be restarted. When `verify` is set every copied file is read back
+ * from the target and compared by SHA-256 hash with the source, and skipped files are
+ * compared too and copied again if they differ.
+ * Archives with an older layout are upgraded in the target, leaving the source as it is,
+ * and messages are split or joined on the way as `convert` asks for.
+ * Postkasse does not compress archives, so there is no compression to convert.
+ */
+pub async fn migrate(
+    source: &Operator,
+    target: &Operator,
+    pb: &dyn Progressable,
+    concurrency: usize,
+    verify: bool,
+    convert: Convert,
+) -> anyhow::Result<MigrateReport> {
+    let mut report = MigrateReport::default();
+
+    // The descriptor goes first so a partially migrated target is recognised as an archive
+    match layout::layout_version(source).await? {
+        None => anyhow::bail!("Archive is empty, there is nothing to migrate"),
+        Some(version) if version > layout::LAYOUT_VERSION => anyhow::bail!(
+            "Archive uses layout version {} which is newer than the supported version {}. Upgrade postkasse",
+            version,
+            layout::LAYOUT_VERSION
+        ),
+        Some(layout::LAYOUT_VERSION) => {
+            let descriptor = source
+                .stat(layout::DESCRIPTOR_PATH)
+                .await
+                .with_context(|| format!("Error reading {}", layout::DESCRIPTOR_PATH))?;
+            match copy_file(source, target, layout::DESCRIPTOR_PATH, descriptor.content_length(), verify).await? {
+                Outcome::Copied(bytes) => {
+                    report.copied += 1;
+                    report.bytes += bytes;
+                }
+                Outcome::Skipped => report.skipped += 1,
+            }
+        }
+        // Upgrades only add the descriptor so far, the files themselves are copied as they are
+        Some(version) => {
+            info!("Upgrading the copy from layout version {} to {}", version, layout::LAYOUT_VERSION);
+            layout::upgrade_archive(target)
+                .await
+                .with_context(|| "Error writing the layout of the target")?;
+            report.upgraded_from = Some(version);
+        }
+    }
+
+    for folder in layout::DATA_FOLDERS {
+        // Joined messages are written whole, so the attachments they were split into are not needed
+        if convert == Convert::Join && folder == layout::ATTACHMENTS_FOLDER {
+            continue;
+        }
+
+        // Progress is copied last so the target never claims to be further along than the data it holds
+        if folder == layout::PROGRESS_FOLDER && !report.failed.is_empty() {
+            warn!("Not migrating {} as {} files failed to migrate", folder, report.failed.len());
+            continue;
+        }
+
+        let files = list_files(source, folder).await?;
+        info!("Migrating {} files from {}", files.len(), folder);
+        pb.set_length(pb.position() + files.len() as u64);
+
+        let mut results = stream::iter(files.iter().map(|(path, size)| async move {
+            (path, migrate_file(source, target, path, *size, verify, convert).await)
+        }))
+        .buffer_unordered(concurrency.max(1));
+
+        while let Some((path, result)) = results.next().await {
+            match result {
+                Ok(Outcome::Copied(bytes)) => {
+                    report.copied += 1;
+                    report.bytes += bytes;
+                }
+                Ok(Outcome::Skipped) => report.skipped += 1,
+                Err(e) => {
+                    warn!("Error migrating {}. {:#}", path, e);
+                    report.failed.push(path.clone());
+                }
+            }
+            pb.inc(1);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Copy a file, or the message it holds in the form `convert` asks for
+async fn migrate_file(
+    source: &Operator,
+    target: &Operator,
+    path: &str,
+    size: u64,
+    verify: bool,
+    convert: Convert,
+) -> anyhow::Result<Outcome> {
+    let blob_id = path.rsplit('/').next().unwrap_or_default();
+
+    match convert {
+        Convert::Split { .. } if path.starts_with(layout::BLOBS_FOLDER) => {
+            convert_message(source, target, blob_id, verify, convert).await
+        }
+        Convert::Join if path.starts_with(layout::SKELETONS_FOLDER) => {
+            convert_message(source, target, blob_id.trim_end_matches(".json"), verify, convert).await
+        }
+        _ => copy_file(source, target, path, size, verify).await,
+    }
+}
+
+/**
+ * Copy a message, splitting or joining it on the way.
+ * Messages already in the target with the same size, in either form, are skipped unless they differ when verifying.
+ */
+async fn convert_message(
+    source: &Operator,
+    target: &Operator,
+    blob_id: &str,
+    verify: bool,
+    convert: Convert,
+) -> anyhow::Result<Outcome> {
+    let size = blob::stored_size(source, blob_id).await?;
+    let existing = blob::stored_size(target, blob_id).await.ok();
+    if existing == Some(size) && !verify {
+        return Ok(Outcome::Skipped);
+    }
+
+    let message = blob::read_message(source, blob_id)
+        .await
+        .with_context(|| format!("Error reading message {} from source", blob_id))?;
+    let hash = Sha256::digest(&message);
+
+    if existing == Some(size) {
+        let stored = blob::read_message(target, blob_id)
+            .await
+            .with_context(|| format!("Error reading message {} from target", blob_id))?;
+
+        if Sha256::digest(&stored) == hash {
+            return Ok(Outcome::Skipped);
+        }
+        warn!("Copying message {} again as it differs from the source", blob_id);
+    }
+
+    let split = match convert {
+        Convert::Split { threshold } => blob::split_message(&message, threshold),
+        _ => None,
+    };
+    let blob_path = layout::blob_path(blob_id);
+    match split {
+        Some((skeleton, attachments)) => {
+            blob::write_skeleton(target, blob_id, &skeleton, &attachments).await?;
+
+            // Messages stored both ways are read whole, so a whole copy from an earlier run has to go
+            if existing.is_some() {
+                target
+                    .delete(&blob_path)
+                    .await
+                    .with_context(|| format!("Error removing blob {} from target", blob_path))?;
+            }
+        }
+        None => target
+            .write(&blob_path, message.clone())
+            .await
+            .with_context(|| format!("Error writing {} to target", blob_path))?,
+    }
+
+    if verify {
+        let written = blob::read_message(target, blob_id)
+            .await
+            .with_context(|| format!("Error reading back message {} from target", blob_id))?;
+
+        if Sha256::digest(&written) != hash {
+            anyhow::bail!("Hash mismatch for message {} after copying", blob_id);
+        }
+    }
+
+    Ok(Outcome::Copied(message.len() as u64))
+}
+
+/// List every file below the folder along with its size, an empty list if the folder does not exist
+async fn list_files(operator: &Operator, folder: &str) -> anyhow::Result<Vec<(String, u64)>> {
+    if !operator.is_exist(folder).await.unwrap_or(false) {
+        return Ok(vec![]);
+    }
+
+    let lister = operator
+        .lister_with(folder)
+        .recursive(true)
+        .metakey(Metakey::Mode | Metakey::ContentLength)
+        .await
+        .with_context(|| format!("Error listing {}", folder))?;
+
+    let entries = lister
+        .try_filter(|entry| futures::future::ready(entry.metadata().is_file()))
+        .map_ok(|entry| {
+            let size = entry.metadata().content_length();
+            (format!("/{}", entry.path().trim_start_matches('/')), size)
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .with_context(|| format!("Error listing {}", folder))?;
+
+    Ok(entries)
+}
+
+async fn copy_file(
+    source: &Operator,
+    target: &Operator,
+    path: &str,
+    size: u64,
+    verify: bool,
+) -> anyhow::Result<Outcome> {
+    // Progress changes between runs, so it is always copied
+    let resumable = !path.starts_with(layout::PROGRESS_FOLDER);
+
+    let existing = match target.stat(path).await {
+        Ok(existing) if resumable => Some(existing.content_length()),
+        _ => None,
+    };
+    if existing == Some(size) && !verify {
+        return Ok(Outcome::Skipped);
+    }
+
+    let content = source
+        .read(path)
+        .await
+        .with_context(|| format!("Error reading {} from source", path))?;
+
+    // Files of the same size are only trusted without verification, a mismatch is copied again
+    if existing == Some(size) {
+        let stored = target
+            .read(path)
+            .await
+            .with_context(|| format!("Error reading {} from target", path))?;
+
+        if Sha256::digest(&stored) == Sha256::digest(&content) {
+            return Ok(Outcome::Skipped);
+        }
+        warn!("Copying {} again as it differs from the source", path);
+    }
+
+    if content.len() as u64 != size && resumable {
+        anyhow::bail!("Size of {} changed while reading, expected {} got {}", path, size, content.len());
+    }
+
+    let bytes = content.len() as u64;
+    let hash = verify.then(|| Sha256::digest(&content));
+
+    target
+        .write(path, content)
+        .await
+        .with_context(|| format!("Error writing {} to target", path))?;
+
+    if let Some(hash) = hash {
+        let written = target
+            .read(path)
+            .await
+            .with_context(|| format!("Error reading back {} from target", path))?;
+
+        if Sha256::digest(&written) != hash {
+            anyhow::bail!("Hash mismatch for {} after copying", path);
+        }
+    }
+
+    Ok(Outcome::Copied(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::fs_operator;
+
+    struct Counter(std::sync::atomic::AtomicU64);
+
+    impl Progressable for Counter {
+        fn position(&self) -> u64 {
+            self.0.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn set_position(&self, position: u64) {
+            self.0.store(position, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn set_length(&self, _total: u64) {}
+    }
+
+    #[tokio::test]
+    async fn test_migrate_is_resumable() {
+        let (source_dir, target_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (source, target) = (fs_operator(&source_dir), fs_operator(&target_dir));
+
+        layout::open_archive(&source, true).await.unwrap();
+        source.write("/emails/abc/abc1.json", "{}").await.unwrap();
+        source.write("/blobs/ab/ab1", "From: a@example.com").await.unwrap();
+        source.write("/progress/email.json", "{}").await.unwrap();
+
+        let counter = Counter(0.into());
+        let report = migrate(&source, &target, &counter, 4, true, Convert::None).await.unwrap();
+        assert_eq!(report.copied, 4);
+        assert_eq!(report.skipped, 0);
+        assert!(report.failed.is_empty());
+        assert_eq!(target.read("/blobs/ab/ab1").await.unwrap(), b"From: a@example.com");
+
+        // Running again only copies the progress file
+        let counter = Counter(0.into());
+        let report = migrate(&source, &target, &counter, 4, false, Convert::None).await.unwrap();
+        assert_eq!(report.copied, 1);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(counter.position(), 3);
+
+        // A corrupted file of the same size is only noticed, and repaired, when verifying
+        target.write("/blobs/ab/ab1", "From: b@example.com").await.unwrap();
+        let report = migrate(&source, &target, &Counter(0.into()), 4, false, Convert::None).await.unwrap();
+        assert_eq!(report.skipped, 3);
+        let report = migrate(&source, &target, &Counter(0.into()), 4, true, Convert::None).await.unwrap();
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(target.read("/blobs/ab/ab1").await.unwrap(), b"From: a@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_failed_files_are_reported_and_hold_back_progress() {
+        let (source_dir, target_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (source, target) = (fs_operator(&source_dir), fs_operator(&target_dir));
+
+        layout::open_archive(&source, true).await.unwrap();
+        for i in 0..20 {
+            source.write(&format!("/blobs/ab/ab{}", i), format!("From: {}@example.com", i)).await.unwrap();
+        }
+        source.write("/progress/email.json", "{}").await.unwrap();
+        // A folder in the way of a file makes copying it fail
+        target.write("/blobs/ab/ab7/in-the-way", "").await.unwrap();
+
+        let counter = Counter(0.into());
+        let report = migrate(&source, &target, &counter, 8, false, Convert::None).await.unwrap();
+        assert_eq!(report.failed, ["/blobs/ab/ab7"]);
+        assert_eq!(report.copied, 20);
+        assert_eq!(counter.position(), 20);
+        assert!(!target.is_exist("/progress/email.json").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_converts_layout_and_messages() {
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let [source, split, joined] = dirs.each_ref().map(fs_operator);
+        let message = blob::message_with_attachment("Hello");
+
+        // Archives written before layouts were versioned have no descriptor
+        source.write("/emails/abc/abc1.json", "{}").await.unwrap();
+        source.write(&layout::blob_path("Gabc1"), message.clone()).await.unwrap();
+
+        let report = migrate(&source, &split, &Counter(0.into()), 4, true, Convert::Split { threshold: 1000 })
+            .await
+            .unwrap();
+        assert_eq!(report.upgraded_from, Some(0));
+        assert!(report.failed.is_empty());
+        assert!(!source.is_exist(layout::DESCRIPTOR_PATH).await.unwrap());
+        layout::open_archive(&split, false).await.unwrap();
+        assert!(!split.is_exist(&layout::blob_path("Gabc1")).await.unwrap());
+        assert!(split.is_exist(&layout::skeleton_path("Gabc1")).await.unwrap());
+        assert_eq!(blob::read_message(&split, "Gabc1").await.unwrap(), message);
+
+        // Split messages are skipped when migrating again, as long as they read back the same
+        let report = migrate(&source, &split, &Counter(0.into()), 4, true, Convert::Split { threshold: 1000 })
+            .await
+            .unwrap();
+        assert_eq!((report.copied, report.skipped), (0, 2));
+
+        let report = migrate(&split, &joined, &Counter(0.into()), 4, true, Convert::Join).await.unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(joined.read(&layout::blob_path("Gabc1")).await.unwrap(), message);
+        assert!(!joined.is_exist(layout::ATTACHMENTS_FOLDER).await.unwrap());
+        assert!(!joined.is_exist(layout::SKELETONS_FOLDER).await.unwrap());
+    }
+}
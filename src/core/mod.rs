@@ -1,7 +1,19 @@
+pub mod blob;
 pub mod helpers;
 pub mod search;
+pub mod index_sync;
 pub mod email;
+pub mod extract;
 pub mod progress;
 pub mod mailboxes;
 pub mod storage;
-pub mod jmap;
\ No newline at end of file
+pub mod jmap;
+pub mod layout;
+pub mod lock;
+pub mod migrate;
+pub mod reindex;
+pub mod threads;
+pub mod oauth;
+pub mod text;
+pub mod query;
+
@@ -0,0 +1,636 @@
+// OAuth 2.0 for JMAP servers issuing short lived access tokens.
+// Logging in uses either the device authorization grant (RFC 8628) or the authorization code grant
+// with PKCE (RFC 7636) and a loopback redirect (RFC 8252). The refresh token is kept in the keyring,
+// and access tokens are refreshed shortly before they expire so long backups keep working.
+use std::time::Duration;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::Instant,
+};
+
+use crate::conf::{self, keyring_entry, OAuthFlow};
+
+/// Access tokens are refreshed when they expire within this margin
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Lifetime assumed for access tokens when the server does not say
+const DEFAULT_EXPIRES_IN: u64 = 60 * 60;
+/// How long to wait for the user to log in
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Name of the refresh token in the keyring
+pub const REFRESH_TOKEN_SECRET: &str = "jmap_oauth_refresh_token";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Endpoints {
+    pub authorization_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceAuthorization {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Instant,
+}
+
+/**
+ * Find the endpoints of the authorization server from its metadata (RFC 8414),
+ * unless every endpoint needed is given in the config.
+ */
+pub async fn discover(http: &reqwest::Client, conf: &conf::OAuth, host: &str) -> anyhow::Result<Endpoints> {
+    if let Some(token_endpoint) = &conf.token_endpoint {
+        if conf.authorization_endpoint.is_some() || conf.device_authorization_endpoint.is_some() {
+            return Ok(Endpoints {
+                authorization_endpoint: conf.authorization_endpoint.clone(),
+                device_authorization_endpoint: conf.device_authorization_endpoint.clone(),
+                token_endpoint: token_endpoint.clone(),
+            });
+        }
+    }
+
+    let issuer = conf.issuer.as_deref().unwrap_or(host).trim_end_matches('/');
+    let mut last_error = None;
+
+    for well_known in ["oauth-authorization-server", "openid-configuration"] {
+        let url = format!("{}/.well-known/{}", issuer, well_known);
+        match get_json::<Endpoints>(http, &url).await {
+            Ok(discovered) => {
+                return Ok(Endpoints {
+                    authorization_endpoint: conf.authorization_endpoint.clone().or(discovered.authorization_endpoint),
+                    device_authorization_endpoint: conf
+                        .device_authorization_endpoint
+                        .clone()
+                        .or(discovered.device_authorization_endpoint),
+                    token_endpoint: conf.token_endpoint.clone().unwrap_or(discovered.token_endpoint),
+                });
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap()).with_context(|| format!("Error discovering OAuth endpoints of {}", issuer))
+}
+
+/**
+ * Log in with the device authorization grant.
+ * `show` is called with the code the user has to enter at the verification URI.
+ */
+pub async fn device_flow(
+    http: &reqwest::Client,
+    conf: &conf::OAuth,
+    endpoints: &Endpoints,
+    show: impl Fn(&DeviceAuthorization),
+) -> anyhow::Result<Tokens> {
+    let device_endpoint = endpoints
+        .device_authorization_endpoint
+        .as_deref()
+        .context("The authorization server does not support the device flow, use flow = \"pkce\"")?;
+
+    let scope = conf.scopes.join(" ");
+    let mut form = vec![("client_id", conf.client_id.as_str())];
+    if !scope.is_empty() {
+        form.push(("scope", &scope));
+    }
+
+    let authorization: DeviceAuthorization = post_form(http, device_endpoint, &form)
+        .await
+        .with_context(|| "Error starting device authorization")?;
+    show(&authorization);
+
+    let deadline = Instant::now() + Duration::from_secs(authorization.expires_in).min(LOGIN_TIMEOUT);
+    let mut interval = Duration::from_secs(authorization.interval.unwrap_or(5));
+
+    loop {
+        if Instant::now() >= deadline {
+            anyhow::bail!("Timed out waiting for the device to be authorized");
+        }
+        tokio::time::sleep(interval).await;
+
+        let form = [
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", authorization.device_code.as_str()),
+            ("client_id", conf.client_id.as_str()),
+        ];
+
+        match post_form::<TokenResponse>(http, &endpoints.token_endpoint, &form).await {
+            Ok(response) => return Ok(response.into()),
+            Err(e) => match e.downcast_ref::<ErrorResponse>().map(|e| e.error.as_str()) {
+                Some("authorization_pending") => continue,
+                Some("slow_down") => interval += Duration::from_secs(5),
+                _ => return Err(e).with_context(|| "Error authorizing device"),
+            },
+        }
+    }
+}
+
+/**
+ * Log in with the authorization code grant and PKCE.
+ * `open` is called with the URL to log in at, the server redirects back to a listener on the loopback interface.
+ */
+pub async fn pkce_flow(
+    http: &reqwest::Client,
+    conf: &conf::OAuth,
+    endpoints: &Endpoints,
+    open: impl Fn(&str),
+) -> anyhow::Result<Tokens> {
+    let authorization_endpoint = endpoints
+        .authorization_endpoint
+        .as_deref()
+        .context("The authorization server does not support the authorization code flow, use flow = \"device\"")?;
+
+    let listener = TcpListener::bind(("127.0.0.1", conf.redirect_port))
+        .await
+        .with_context(|| format!("Error listening for the OAuth redirect on port {}", conf.redirect_port))?;
+    let redirect_uri = format!("http://127.0.0.1:{}/callback", listener.local_addr()?.port());
+
+    let verifier = random_string(64);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_string(32);
+
+    let mut url = Url::parse(authorization_endpoint)
+        .with_context(|| format!("Invalid authorization endpoint {}", authorization_endpoint))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &conf.client_id)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+    if !conf.scopes.is_empty() {
+        url.query_pairs_mut().append_pair("scope", &conf.scopes.join(" "));
+    }
+    open(url.as_str());
+
+    let code = tokio::time::timeout(LOGIN_TIMEOUT, receive_code(&listener, &state))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for the OAuth redirect"))??;
+
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", conf.client_id.as_str()),
+        ("code_verifier", verifier.as_str()),
+    ];
+    let response: TokenResponse = post_form(http, &endpoints.token_endpoint, &form)
+        .await
+        .with_context(|| "Error exchanging the authorization code")?;
+
+    Ok(response.into())
+}
+
+/// Get a new access token with the refresh token
+pub async fn refresh(
+    http: &reqwest::Client,
+    conf: &conf::OAuth,
+    endpoints: &Endpoints,
+    refresh_token: &str,
+) -> anyhow::Result<Tokens> {
+    let form = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", conf.client_id.as_str()),
+    ];
+    let response: TokenResponse = post_form(http, &endpoints.token_endpoint, &form).await?;
+
+    Ok(response.into())
+}
+
+/// Keeps a valid access token around, refreshing it and storing rotated refresh tokens
+pub struct TokenSource {
+    http: reqwest::Client,
+    conf: conf::OAuth,
+    endpoints: Endpoints,
+    refresh_token: String,
+    tokens: Option<Tokens>,
+    /// Profile whose keyring entry holds the refresh token, None to not store it
+    profile: Option<String>,
+}
+
+impl TokenSource {
+    /**
+     * Use the refresh token in the keyring of the profile, logging in with the configured flow if there is none.
+     */
+    pub async fn login_or_restore(conf: &conf::OAuth, host: &str, profile: &str) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let endpoints = discover(&http, conf, host).await?;
+        let entry = keyring_entry(profile, REFRESH_TOKEN_SECRET)?;
+
+        let (refresh_token, tokens) = match entry.get_password() {
+            Ok(refresh_token) => (refresh_token, None),
+            Err(keyring::Error::NoEntry) => {
+                let tokens = login(&http, conf, &endpoints).await?;
+                let refresh_token = tokens.refresh_token.clone().unwrap_or_default();
+                entry
+                    .set_password(&refresh_token)
+                    .with_context(|| "Error storing OAuth refresh token in keyring")?;
+                info!("Logged in, refresh token stored in keyring");
+
+                (refresh_token, Some(tokens))
+            }
+            Err(e) => return Err(e).with_context(|| "Error reading OAuth refresh token from keyring"),
+        };
+
+        Ok(TokenSource { http, conf: conf.clone(), endpoints, refresh_token, tokens, profile: Some(profile.to_string()) })
+    }
+
+    /**
+     * Log in with the configured flow without touching the keyring, e.g. to check a new login before storing it.
+     */
+    pub async fn login(conf: &conf::OAuth, host: &str) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let endpoints = discover(&http, conf, host).await?;
+        let tokens = login(&http, conf, &endpoints).await?;
+        let refresh_token = tokens.refresh_token.clone().unwrap_or_default();
+
+        Ok(TokenSource { http, conf: conf.clone(), endpoints, refresh_token, tokens: Some(tokens), profile: None })
+    }
+
+    /// The refresh token to store in the keyring
+    pub fn refresh_token(&self) -> &str {
+        &self.refresh_token
+    }
+
+    /// An access token valid for at least `REFRESH_MARGIN`, refreshing it if needed
+    pub async fn access_token(&mut self) -> anyhow::Result<String> {
+        if let Some(tokens) = &self.tokens {
+            if tokens.expires_at > Instant::now() + REFRESH_MARGIN {
+                return Ok(tokens.access_token.clone());
+            }
+        }
+
+        info!("Refreshing OAuth access token");
+        let tokens = match refresh(&self.http, &self.conf, &self.endpoints, &self.refresh_token).await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                // A revoked refresh token is useless, forget it so the next run logs in again
+                if matches!(e.downcast_ref::<ErrorResponse>(), Some(e) if e.error == "invalid_grant") {
+                    if let Some(profile) = &self.profile {
+                        let _ = keyring_entry(profile, REFRESH_TOKEN_SECRET).and_then(|entry| Ok(entry.delete_password()?));
+                    }
+                    return Err(e).with_context(|| "OAuth refresh token was rejected, run the command again to log in");
+                }
+                return Err(e).with_context(|| "Error refreshing OAuth access token");
+            }
+        };
+
+        // Servers rotating refresh tokens invalidate the old one, so the new one must be kept
+        if let Some(refresh_token) = &tokens.refresh_token {
+            if *refresh_token != self.refresh_token {
+                if let Some(profile) = &self.profile {
+                    if let Err(e) = keyring_entry(profile, REFRESH_TOKEN_SECRET).and_then(|entry| Ok(entry.set_password(refresh_token)?)) {
+                        warn!("Error storing rotated OAuth refresh token in keyring. {}", e);
+                    }
+                }
+                self.refresh_token = refresh_token.clone();
+            }
+        }
+
+        let access_token = tokens.access_token.clone();
+        self.tokens = Some(tokens);
+
+        Ok(access_token)
+    }
+}
+
+/// Log in with the configured flow, the server must issue a refresh token for backups to keep working
+async fn login(http: &reqwest::Client, conf: &conf::OAuth, endpoints: &Endpoints) -> anyhow::Result<Tokens> {
+    let tokens = match conf.flow {
+        OAuthFlow::Device => device_flow(http, conf, endpoints, show_device_code).await?,
+        OAuthFlow::Pkce => pkce_flow(http, conf, endpoints, open_browser).await?,
+    };
+
+    if tokens.refresh_token.is_none() {
+        anyhow::bail!("The authorization server did not issue a refresh token, try adding the offline_access scope");
+    }
+
+    Ok(tokens)
+}
+
+fn show_device_code(authorization: &DeviceAuthorization) {
+    match &authorization.verification_uri_complete {
+        Some(uri) => println!("To log in, open {} and check that it shows the code {}", uri, authorization.user_code),
+        None => println!("To log in, open {} and enter the code {}", authorization.verification_uri, authorization.user_code),
+    }
+}
+
+fn open_browser(url: &str) {
+    println!("Opening {} in your browser to log in", url);
+    if let Err(e) = open::that(url) {
+        warn!("Could not open a browser, open the URL manually. {}", e);
+    }
+}
+
+/// Wait for the redirect carrying the authorization code, ignoring unrelated requests such as favicons
+async fn receive_code(listener: &TcpListener, state: &str) -> anyhow::Result<String> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let mut request = vec![0; 8192];
+        let read = socket.read(&mut request).await?;
+        let request = String::from_utf8_lossy(&request[..read]);
+
+        let target = request.lines().next().and_then(|line| line.split_whitespace().nth(1)).unwrap_or("/");
+        let url = Url::parse(&format!("http://127.0.0.1{}", target))?;
+        if url.path() != "/callback" {
+            let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+            continue;
+        }
+
+        let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.to_string());
+        let body = "You are logged in to postkasse and can close this window.";
+        let _ = socket
+            .write_all(format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes())
+            .await;
+
+        if let Some(error) = param("error") {
+            anyhow::bail!("Authorization failed. {}", param("error_description").unwrap_or(error));
+        }
+        if param("state").as_deref() != Some(state) {
+            anyhow::bail!("Authorization failed, the redirect did not carry the expected state");
+        }
+
+        return param("code").context("Authorization failed, the redirect did not carry a code");
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(http: &reqwest::Client, url: &str) -> anyhow::Result<T> {
+    let response = http.get(url).send().await?.error_for_status()?;
+    let body = response.bytes().await?;
+
+    serde_json::from_slice(&body).with_context(|| format!("Error deserializing response from {}", url))
+}
+
+/// Post a form, turning OAuth error responses into an `ErrorResponse` error
+async fn post_form<T: serde::de::DeserializeOwned>(
+    http: &reqwest::Client,
+    url: &str,
+    form: &[(&str, &str)],
+) -> anyhow::Result<T> {
+    let response = http.post(url).form(form).send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+
+    if !status.is_success() {
+        return match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) => Err(error.into()),
+            Err(_) => Err(anyhow::anyhow!("{} responded with {}", url, status)),
+        };
+    }
+
+    serde_json::from_slice(&body).with_context(|| format!("Error deserializing response from {}", url))
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+impl From<TokenResponse> for Tokens {
+    fn from(response: TokenResponse) -> Self {
+        Tokens {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in.unwrap_or(DEFAULT_EXPIRES_IN)),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{}: {}", self.error, description),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    type Handler = Arc<dyn Fn(&str, &str) -> (u16, String) + Send + Sync>;
+
+    /// A tiny HTTP server answering every request with the handler, given the path and body
+    async fn mock_server(handler: Handler) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let handler = handler.clone();
+
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buffer = [0; 4096];
+                    let (head, body) = loop {
+                        let read = socket.read(&mut buffer).await.unwrap();
+                        request.extend_from_slice(&buffer[..read]);
+                        let text = String::from_utf8_lossy(&request).to_string();
+
+                        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                            let length = head
+                                .lines()
+                                .find_map(|line| line.to_lowercase().strip_prefix("content-length: ").map(|l| l.parse::<usize>().unwrap()))
+                                .unwrap_or(0);
+                            if body.len() >= length || read == 0 {
+                                break (head.to_string(), body.to_string());
+                            }
+                        }
+                    };
+
+                    let path = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let (status, response) = handler(&path, &body);
+                    let response = format!(
+                        "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        response.len(),
+                        response
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        base
+    }
+
+    fn oauth_conf() -> conf::OAuth {
+        conf::OAuth {
+            client_id: "postkasse".to_string(),
+            issuer: None,
+            authorization_endpoint: None,
+            device_authorization_endpoint: None,
+            token_endpoint: None,
+            scopes: vec!["offline_access".to_string()],
+            flow: OAuthFlow::Device,
+            redirect_port: 0,
+        }
+    }
+
+    fn metadata(base: &str) -> String {
+        format!(
+            r#"{{"issuer":"{base}","authorization_endpoint":"{base}/authorize","device_authorization_endpoint":"{base}/device","token_endpoint":"{base}/token"}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_device_flow() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let base = Arc::new(std::sync::OnceLock::<String>::new());
+
+        let handler: Handler = {
+            let (polls, base) = (polls.clone(), base.clone());
+            Arc::new(move |path, body| match path {
+                "/.well-known/oauth-authorization-server" => (200, metadata(base.get().unwrap())),
+                "/device" => {
+                    assert!(body.contains("client_id=postkasse") && body.contains("scope=offline_access"));
+                    (200, r#"{"device_code":"dev","user_code":"ABCD-EFGH","verification_uri":"https://example.com/device","expires_in":600,"interval":0}"#.to_string())
+                }
+                "/token" if polls.fetch_add(1, Ordering::SeqCst) == 0 => (400, r#"{"error":"authorization_pending"}"#.to_string()),
+                "/token" => {
+                    assert!(body.contains("device_code=dev"));
+                    (200, r#"{"access_token":"access","refresh_token":"refresh","expires_in":3600}"#.to_string())
+                }
+                _ => (404, "{}".to_string()),
+            })
+        };
+        base.set(mock_server(handler).await).unwrap();
+
+        let http = reqwest::Client::new();
+        let endpoints = discover(&http, &oauth_conf(), base.get().unwrap()).await.unwrap();
+        let shown = std::sync::Mutex::new(None);
+        let tokens = device_flow(&http, &oauth_conf(), &endpoints, |auth| {
+            *shown.lock().unwrap() = Some(auth.user_code.clone());
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(shown.into_inner().unwrap().as_deref(), Some("ABCD-EFGH"));
+        assert_eq!(tokens.access_token, "access");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pkce_flow() {
+        let challenge = Arc::new(std::sync::Mutex::new(String::new()));
+
+        let handler: Handler = {
+            let challenge = challenge.clone();
+            Arc::new(move |path, body| match path {
+                "/token" => {
+                    let form = Url::parse(&format!("http://localhost/?{}", body)).unwrap();
+                    let param = |name: &str| form.query_pairs().find(|(k, _)| k == name).unwrap().1.to_string();
+                    assert_eq!(param("code"), "the-code");
+                    let verifier = param("code_verifier");
+                    assert_eq!(URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())), *challenge.lock().unwrap());
+                    (200, r#"{"access_token":"access","refresh_token":"refresh"}"#.to_string())
+                }
+                _ => (404, "{}".to_string()),
+            })
+        };
+        let base = mock_server(handler).await;
+
+        let mut conf = oauth_conf();
+        conf.flow = OAuthFlow::Pkce;
+        conf.authorization_endpoint = Some(format!("{}/authorize", base));
+        conf.token_endpoint = Some(format!("{}/token", base));
+
+        let http = reqwest::Client::new();
+        let endpoints = discover(&http, &conf, &base).await.unwrap();
+
+        // Play the browser, logging in and following the redirect back to postkasse
+        let tokens = pkce_flow(&http, &conf, &endpoints, |url| {
+            let url = Url::parse(url).unwrap();
+            let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).unwrap().1.to_string();
+            assert_eq!(param("code_challenge_method"), "S256");
+            *challenge.lock().unwrap() = param("code_challenge");
+
+            let redirect = format!("{}?code=the-code&state={}", param("redirect_uri"), param("state"));
+            tokio::spawn(async move { reqwest::get(redirect).await.unwrap() });
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(tokens.access_token, "access");
+    }
+
+    #[tokio::test]
+    async fn test_access_token_is_refreshed_before_expiry() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+
+        let handler: Handler = {
+            let refreshes = refreshes.clone();
+            Arc::new(move |path, body| match path {
+                "/token" => {
+                    let n = refreshes.fetch_add(1, Ordering::SeqCst);
+                    assert!(body.contains(&format!("refresh_token=refresh{}", n)));
+                    (200, format!(r#"{{"access_token":"access{}","refresh_token":"refresh{}","expires_in":3600}}"#, n + 1, n + 1))
+                }
+                _ => (404, "{}".to_string()),
+            })
+        };
+        let base = mock_server(handler).await;
+
+        let mut conf = oauth_conf();
+        conf.token_endpoint = Some(format!("{}/token", base));
+        conf.device_authorization_endpoint = Some(format!("{}/device", base));
+
+        let http = reqwest::Client::new();
+        let endpoints = discover(&http, &conf, &base).await.unwrap();
+        let mut source = TokenSource {
+            http,
+            conf,
+            endpoints,
+            refresh_token: "refresh0".to_string(),
+            // About to expire, so it is refreshed before use
+            tokens: Some(Tokens { access_token: "access0".to_string(), refresh_token: None, expires_at: Instant::now() + Duration::from_secs(60) }),
+            profile: None,
+        };
+
+        assert_eq!(source.access_token().await.unwrap(), "access1");
+        assert_eq!(source.refresh_token, "refresh1");
+        // Still valid for an hour, so not refreshed again
+        assert_eq!(source.access_token().await.unwrap(), "access1");
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,493 @@
+// Search query language, modelled on the Gmail search operators.
+// Words and "quoted phrases" are searched in the text of emails and attachments, operators like
+// from:, in:, is:unread, after: or larger: filter on the other fields of the index.
+// Terms are combined with AND, and can be negated with `-`, combined with OR and grouped in parentheses.
+use std::ops::Bound;
+
+use anyhow::Context;
+use chrono::{DateTime, Days, Months, NaiveDate, Utc};
+use tantivy::{
+    query::{AllQuery, BooleanQuery, EmptyQuery, Occur, PhraseQuery, Query, RangeQuery, TermQuery},
+    schema::{Facet, Field, IndexRecordOption},
+    tokenizer::Language,
+    Index, Searcher, Term,
+};
+
+use super::search::EMAIL_SCHEMA;
+
+/// Text fields that can be searched with an operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextField {
+    From,
+    To,
+    Cc,
+    Subject,
+    Filename,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A word or phrase searched in the subject, body and attachments
+    Text(String),
+    Field(TextField, String),
+    /// Mailbox name or id
+    In(String),
+    /// Keyword the email must have, or must not have when negated, e.g. is:unread
+    Keyword(&'static str, bool),
+    HasAttachment,
+    ReceivedBefore(DateTime<Utc>),
+    ReceivedAfter(DateTime<Utc>),
+    Larger(u64),
+    Smaller(u64),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+const OPERATORS: &str = "from:, to:, cc:, subject:, filename:, in:, is:, has:, before:, after:, older_than:, newer_than:, larger:, smaller:";
+
+/**
+ * Parse a query. Relative dates like older_than:2w are relative to `now`.
+ */
+pub fn parse(input: &str, now: DateTime<Utc>) -> anyhow::Result<Expr> {
+    let mut parser = Parser { input, pos: 0, now };
+    let expr = parser.parse_and()?;
+
+    parser.skip_whitespace();
+    if parser.pos < input.len() {
+        anyhow::bail!("Unexpected ) at position {} of the query", parser.pos + 1);
+    }
+
+    match &expr {
+        Expr::And(terms) if terms.is_empty() => anyhow::bail!("The search query is empty"),
+        _ => Ok(expr),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    now: DateTime<Utc>,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    /// Terms up to the end of the query or the closing parenthesis of a group
+    fn parse_and(&mut self) -> anyhow::Result<Expr> {
+        let mut terms = vec![];
+
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(')') => break,
+                Some(_) => terms.push(self.parse_or()?),
+            }
+        }
+
+        match terms.len() {
+            1 => Ok(terms.remove(0)),
+            _ => Ok(Expr::And(terms)),
+        }
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        let mut alternatives = vec![self.parse_unary()?];
+
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            let is_or = rest.starts_with("OR") && matches!(rest[2..].chars().next(), None | Some(' ' | '\t' | '\n' | '(' | ')' | '"'));
+            if !is_or {
+                break;
+            }
+
+            self.pos += 2;
+            self.skip_whitespace();
+            if matches!(self.peek(), None | Some(')')) {
+                anyhow::bail!("OR at the end of the query must be followed by another term");
+            }
+            alternatives.push(self.parse_unary()?);
+        }
+
+        match alternatives.len() {
+            1 => Ok(alternatives.remove(0)),
+            _ => Ok(Expr::Or(alternatives)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<Expr> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                if matches!(self.peek(), None | Some(' ') | Some(')')) {
+                    anyhow::bail!("- at position {} must be followed by the term to leave out", self.pos);
+                }
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some('(') => {
+                let start = self.pos;
+                self.pos += 1;
+                let expr = self.parse_and()?;
+                if self.peek() != Some(')') {
+                    anyhow::bail!("Missing ) for the ( at position {} of the query", start + 1);
+                }
+                self.pos += 1;
+
+                match expr {
+                    Expr::And(terms) if terms.is_empty() => anyhow::bail!("Empty () at position {} of the query", start + 1),
+                    expr => Ok(expr),
+                }
+            }
+            _ => self.parse_term(),
+        }
+    }
+
+    fn parse_term(&mut self) -> anyhow::Result<Expr> {
+        if self.peek() == Some('"') {
+            return Ok(Expr::Text(self.parse_quoted()?));
+        }
+
+        // An operator is a name made of letters and underscores followed by a colon, anything else is a word
+        let name_length = self.rest().find(|c: char| !(c.is_ascii_alphabetic() || c == '_')).unwrap_or(self.rest().len());
+        if name_length > 0 && self.rest()[name_length..].starts_with(':') {
+            let operator = self.rest()[..name_length].to_ascii_lowercase();
+            self.pos += name_length + 1;
+
+            let value = match self.peek() {
+                Some('"') => self.parse_quoted()?,
+                _ => self.parse_word(),
+            };
+            if value.is_empty() {
+                anyhow::bail!("Missing value after {}:", operator);
+            }
+
+            return self.operator(&operator, value);
+        }
+
+        Ok(Expr::Text(self.parse_word()))
+    }
+
+    fn parse_word(&mut self) -> String {
+        let length = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+            .unwrap_or(self.rest().len());
+        let word = self.rest()[..length].to_string();
+        self.pos += length;
+        word
+    }
+
+    fn parse_quoted(&mut self) -> anyhow::Result<String> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let Some(length) = self.rest().find('"') else {
+            anyhow::bail!("Missing closing quote for the quote at position {} of the query", start + 1);
+        };
+        let phrase = self.rest()[..length].to_string();
+        self.pos += length + 1;
+
+        Ok(phrase)
+    }
+
+    fn operator(&self, operator: &str, value: String) -> anyhow::Result<Expr> {
+        let expr = match operator {
+            "from" => Expr::Field(TextField::From, value),
+            "to" => Expr::Field(TextField::To, value),
+            "cc" => Expr::Field(TextField::Cc, value),
+            "subject" => Expr::Field(TextField::Subject, value),
+            "filename" => Expr::Field(TextField::Filename, value),
+            "in" => Expr::In(value),
+            "is" => match value.to_ascii_lowercase().as_str() {
+                "unread" => Expr::Keyword("$seen", false),
+                "read" => Expr::Keyword("$seen", true),
+                "flagged" | "starred" => Expr::Keyword("$flagged", true),
+                "unflagged" | "unstarred" => Expr::Keyword("$flagged", false),
+                "answered" => Expr::Keyword("$answered", true),
+                "draft" => Expr::Keyword("$draft", true),
+                _ => anyhow::bail!("Unknown is:{}, use one of unread, read, flagged, unflagged, answered or draft", value),
+            },
+            "has" => match value.to_ascii_lowercase().as_str() {
+                "attachment" => Expr::HasAttachment,
+                _ => anyhow::bail!("Unknown has:{}, only has:attachment is supported", value),
+            },
+            "before" => Expr::ReceivedBefore(parse_date(&value)?),
+            "after" => Expr::ReceivedAfter(parse_date(&value)?),
+            "older_than" => Expr::ReceivedBefore(self.parse_age(&value)?),
+            "newer_than" => Expr::ReceivedAfter(self.parse_age(&value)?),
+            "larger" => Expr::Larger(parse_size(&value)?),
+            "smaller" => Expr::Smaller(parse_size(&value)?),
+            _ => anyhow::bail!("Unknown search operator {}:, use one of {}, or quote the word to search for it", operator, OPERATORS),
+        };
+
+        Ok(expr)
+    }
+
+    /// The time an age like 3d, 2w, 6m or 1y ago
+    fn parse_age(&self, value: &str) -> anyhow::Result<DateTime<Utc>> {
+        let invalid = || anyhow::anyhow!("Invalid age {}, use a number followed by d, w, m or y, e.g. 2w", value);
+
+        // The unit is the last character, which need not be ASCII in a mistyped query
+        let (unit_start, unit) = value.char_indices().last().ok_or_else(invalid)?;
+        let count: u32 = value[..unit_start].parse().map_err(|_| invalid())?;
+
+        let time = match unit.to_ascii_lowercase() {
+            'd' => self.now.checked_sub_days(Days::new(count.into())),
+            'w' => self.now.checked_sub_days(Days::new(u64::from(count) * 7)),
+            'm' => self.now.checked_sub_months(Months::new(count)),
+            'y' => self.now.checked_sub_months(Months::new(count.saturating_mul(12))),
+            _ => return Err(invalid()),
+        };
+
+        time.ok_or_else(invalid)
+    }
+}
+
+/// Midnight UTC of a date written as 2024-01-31 or 2024/01/31
+fn parse_date(value: &str) -> anyhow::Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y/%m/%d"))
+        .with_context(|| format!("Invalid date {}, use a date like 2024-01-31", value))?;
+
+    Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+}
+
+/// Size in bytes of a value like 500, 200K, 10M or 1G
+fn parse_size(value: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("Invalid size {}, use a number of bytes optionally followed by K, M or G, e.g. 10M", value);
+
+    let upper = value.to_ascii_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &upper[digits.len()..] {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+
+    let count: u64 = digits.parse().map_err(|_| invalid())?;
+    count.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/**
+ * Translate a parsed query into a tantivy query against the email schema.
+ * Words are searched in the stemmed fields of the given languages as well.
+ */
+pub fn to_query(expr: &Expr, index: &Index, searcher: &Searcher, languages: &[Language]) -> anyhow::Result<Box<dyn Query>> {
+    let fields = &EMAIL_SCHEMA.fields;
+
+    let query: Box<dyn Query> = match expr {
+        Expr::Text(text) => {
+            let mut text_fields = vec![fields["subject"], fields["body"], fields["attachment_name"], fields["attachment_text"]];
+            for (language, subject, body) in &EMAIL_SCHEMA.language_fields {
+                if languages.contains(language) {
+                    text_fields.extend([*subject, *body]);
+                }
+            }
+            text_query(index, &text_fields, text)?
+        }
+        Expr::Field(field, text) => {
+            let mut text_fields = match field {
+                TextField::From => vec![fields["from_name"], fields["from_email"]],
+                TextField::To => vec![fields["to_name"], fields["to_email"]],
+                TextField::Cc => vec![fields["cc_name"], fields["cc_email"]],
+                TextField::Subject => vec![fields["subject"]],
+                TextField::Filename => vec![fields["attachment_name"]],
+            };
+            if *field == TextField::Subject {
+                for (language, subject, _) in &EMAIL_SCHEMA.language_fields {
+                    if languages.contains(language) {
+                        text_fields.push(*subject);
+                    }
+                }
+            }
+            text_query(index, &text_fields, text)?
+        }
+        Expr::In(mailbox) => mailbox_query(searcher, mailbox)?,
+        Expr::Keyword(keyword, present) => {
+            let term = TermQuery::new(Term::from_field_text(fields["keywords"], keyword), IndexRecordOption::Basic);
+            match present {
+                true => Box::new(term),
+                false => Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, Box::new(AllQuery)),
+                    (Occur::MustNot, Box::new(term)),
+                ])),
+            }
+        }
+        Expr::HasAttachment => Box::new(TermQuery::new(
+            Term::from_field_bool(fields["has_attachment"], true),
+            IndexRecordOption::Basic,
+        )),
+        Expr::ReceivedBefore(time) => Box::new(RangeQuery::new_date_bounds(
+            "received_at".to_string(),
+            Bound::Unbounded,
+            Bound::Excluded(tantivy::DateTime::from_timestamp_secs(time.timestamp())),
+        )),
+        Expr::ReceivedAfter(time) => Box::new(RangeQuery::new_date_bounds(
+            "received_at".to_string(),
+            Bound::Included(tantivy::DateTime::from_timestamp_secs(time.timestamp())),
+            Bound::Unbounded,
+        )),
+        Expr::Larger(size) => Box::new(RangeQuery::new_u64_bounds("size".to_string(), Bound::Excluded(*size), Bound::Unbounded)),
+        Expr::Smaller(size) => Box::new(RangeQuery::new_u64_bounds("size".to_string(), Bound::Unbounded, Bound::Excluded(*size))),
+        Expr::Not(inner) => Box::new(BooleanQuery::new(vec![
+            (Occur::Must, Box::new(AllQuery)),
+            (Occur::MustNot, to_query(inner, index, searcher, languages)?),
+        ])),
+        Expr::And(terms) => Box::new(BooleanQuery::new(
+            terms
+                .iter()
+                .map(|term| Ok((Occur::Must, to_query(term, index, searcher, languages)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )),
+        Expr::Or(alternatives) => Box::new(BooleanQuery::new(
+            alternatives
+                .iter()
+                .map(|alternative| Ok((Occur::Should, to_query(alternative, index, searcher, languages)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )),
+    };
+
+    Ok(query)
+}
+
+/// Match the text in any of the fields, as a phrase if the text has several words
+fn text_query(index: &Index, fields: &[Field], text: &str) -> anyhow::Result<Box<dyn Query>> {
+    let mut alternatives: Vec<(Occur, Box<dyn Query>)> = vec![];
+
+    for field in fields {
+        let mut tokenizer = index.tokenizer_for_field(*field)?;
+        let mut stream = tokenizer.token_stream(text);
+        let mut terms = vec![];
+        while stream.advance() {
+            let token = stream.token();
+            terms.push((token.position, Term::from_field_text(*field, &token.text)));
+        }
+
+        let query: Box<dyn Query> = match terms.len() {
+            0 => continue,
+            1 => Box::new(TermQuery::new(terms.remove(0).1, IndexRecordOption::WithFreqs)),
+            _ => Box::new(PhraseQuery::new_with_offset(terms)),
+        };
+        alternatives.push((Occur::Should, query));
+    }
+
+    match alternatives.is_empty() {
+        // Only punctuation, nothing to search for
+        true => Ok(Box::new(EmptyQuery)),
+        false => Ok(Box::new(BooleanQuery::new(alternatives))),
+    }
+}
+
+/// Match emails in the mailbox with the id, or with the name regardless of case, including its child mailboxes
+fn mailbox_query(searcher: &Searcher, mailbox: &str) -> anyhow::Result<Box<dyn Query>> {
+    let fields = &EMAIL_SCHEMA.fields;
+    let path = format!("/{}", mailbox.trim_matches('/')).to_lowercase();
+
+    let mut alternatives: Vec<(Occur, Box<dyn Query>)> = vec![(
+        Occur::Should,
+        Box::new(TermQuery::new(Term::from_field_text(fields["mailbox_ids"], mailbox), IndexRecordOption::Basic)),
+    )];
+
+    let mut facets = vec![];
+    for segment in searcher.segment_readers() {
+        let inverted_index = segment.inverted_index(fields["mailboxes"])?;
+        let mut terms = inverted_index.terms().stream()?;
+        while terms.advance() {
+            let Ok(facet) = Facet::from_encoded(terms.key().to_vec()) else {
+                continue;
+            };
+            if facet.to_path_string().to_lowercase() == path && !facets.contains(&facet) {
+                facets.push(facet);
+            }
+        }
+    }
+
+    for facet in facets {
+        alternatives.push((
+            Occur::Should,
+            Box::new(TermQuery::new(Term::from_facet(fields["mailboxes"], &facet), IndexRecordOption::Basic)),
+        ));
+    }
+
+    Ok(Box::new(BooleanQuery::new(alternatives)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-06-15T12:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    fn text(text: &str) -> Expr {
+        Expr::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(r#"from:alice@example.com subject:"weekly report" -in:Spam budget"#, now()).unwrap(),
+            Expr::And(vec![
+                Expr::Field(TextField::From, "alice@example.com".to_string()),
+                Expr::Field(TextField::Subject, "weekly report".to_string()),
+                Expr::Not(Box::new(Expr::In("Spam".to_string()))),
+                text("budget"),
+            ])
+        );
+
+        assert_eq!(
+            parse(r#"(invoice OR "purchase order") is:unread has:attachment larger:10M"#, now()).unwrap(),
+            Expr::And(vec![
+                Expr::Or(vec![text("invoice"), text("purchase order")]),
+                Expr::Keyword("$seen", false),
+                Expr::HasAttachment,
+                Expr::Larger(10 * 1024 * 1024),
+            ])
+        );
+
+        assert_eq!(
+            parse("after:2024/01/31 older_than:2w 10:30", now()).unwrap(),
+            Expr::And(vec![
+                Expr::ReceivedAfter(DateTime::parse_from_rfc3339("2024-01-31T00:00:00Z").unwrap().with_timezone(&Utc)),
+                Expr::ReceivedBefore(DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap().with_timezone(&Utc)),
+                text("10:30"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |query: &str| parse(query, now()).err().map(|e| e.to_string()).unwrap_or_default();
+
+        assert!(error("").contains("empty"));
+        assert!(error(r#"subject:"unterminated"#).contains("Missing closing quote"));
+        assert!(error("(invoice OR receipt").contains("Missing )"));
+        assert!(error("invoice)").contains("Unexpected )"));
+        assert!(error("invoice OR").contains("OR at the end"));
+        assert!(error("frm:alice").contains("Unknown search operator frm:"));
+        assert!(error("is:important").contains("Unknown is:important"));
+        assert!(error("before:31.01.2024").contains("Invalid date"));
+        assert!(error("larger:10X").contains("Invalid size"));
+        assert!(error("older_than:soon").contains("Invalid age"));
+        assert!(error("older_than:2ø").contains("Invalid age"));
+        assert!(error("newer_than:å").contains("Invalid age"));
+        assert!(error("from:").contains("Missing value"));
+    }
+}
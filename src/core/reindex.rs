@@ -0,0 +1,377 @@
+// Rebuild the search index from the archive alone, without the JMAP server, or repair parts of it.
+// Emails are read from /emails/ and their messages from /blobs/ through the storage operator,
+// so any configured storage scheme works. The new index is written next to the current one
+// and only replaces it once every email has been indexed.
+use anyhow::Context;
+use futures::{stream, StreamExt, TryStreamExt};
+use jmap_client::email::Email;
+use log::{info, warn};
+use mail_parser::{Message, MessageParser};
+use opendal::{Metakey, Operator};
+use rayon::prelude::*;
+
+use crate::conf;
+
+use super::{
+    blob,
+    layout,
+    mailboxes::{read_mailbox_names, MailboxNames},
+    progress::Progressable,
+    search::{
+        create_indexer, create_rebuild_indexer, duplicate_ids, finish_rebuild, schema_version, write_document, Indexer,
+        SCHEMA_VERSION,
+    },
+};
+
+/// Most emails read from storage before they are parsed and indexed together, batches are bounded by size too
+const BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    pub indexed: u64,
+    /// Emails indexed without their text, as their message could not be read
+    pub without_message: Vec<String>,
+    /// Email files that could not be read at all
+    pub failed: Vec<String>,
+}
+
+/**
+ * Rebuild the search index from the emails and messages in the archive.
+ * Emails whose message is missing are still indexed by their subject, addresses and other properties.
+ * Emails are indexed in batches holding up to `memory_limit` bytes of messages, besides the ones being read.
+ */
+pub async fn reindex(
+    operator: &Operator,
+    search_conf: &conf::Search,
+    pb: &dyn Progressable,
+    concurrency: usize,
+    memory_limit: usize,
+) -> anyhow::Result<ReindexReport> {
+    let mut report = ReindexReport::default();
+    let message_parser = MessageParser::default();
+    let mailbox_names = read_mailbox_names(operator)
+        .await
+        .with_context(|| "Error reading mailbox names")?;
+
+    let paths = list_emails(operator).await?;
+    info!("Reindexing {} emails", paths.len());
+    pb.set_length(paths.len() as u64);
+
+    let indexer = create_rebuild_indexer(search_conf).with_context(|| "Error creating indexer")?;
+
+    // Batches are closed once their messages reach the memory limit, besides them only the messages being read are held
+    let mut emails = stream::iter(paths.iter().map(|path| async move { (path, read_email_and_message(operator, path).await) }))
+        .buffered(concurrency.max(1));
+    let mut batch = vec![];
+    let mut batch_size = 0;
+
+    while let Some((path, result)) = emails.next().await {
+        match result {
+            Ok((email, message)) => {
+                batch_size += message.as_ref().map_or(0, Vec::len);
+                batch.push((email, message));
+            }
+            Err(e) => {
+                warn!("Error reading {}. {:#}", path, e);
+                report.failed.push(path.clone());
+                pb.inc(1);
+            }
+        }
+
+        if batch.len() >= BATCH_SIZE || batch_size >= memory_limit {
+            index_batch(&indexer, &message_parser, &mailbox_names, std::mem::take(&mut batch), &mut report, pb);
+            batch_size = 0;
+        }
+    }
+    index_batch(&indexer, &message_parser, &mailbox_names, batch, &mut report, pb);
+
+    finish_rebuild(search_conf, indexer).with_context(|| "Error replacing the search index")?;
+
+    Ok(report)
+}
+
+/// Parse and index a batch of emails read from the archive
+fn index_batch(
+    indexer: &Indexer,
+    message_parser: &MessageParser,
+    mailbox_names: &MailboxNames,
+    batch: Vec<(Email, Option<Vec<u8>>)>,
+    report: &mut ReindexReport,
+    pb: &dyn Progressable,
+) {
+    // Parsing messages and extracting attachments is the heavy part, so it is spread over all cores
+    let results = batch
+        .par_iter()
+        .map(|(email, raw)| {
+            let message = raw
+                .as_ref()
+                .and_then(|raw| message_parser.parse(raw))
+                .unwrap_or_else(Message::default);
+            write_document(indexer, email, &message, mailbox_names)
+        })
+        .collect::<Vec<_>>();
+
+    for (result, (email, raw)) in results.into_iter().zip(&batch) {
+        let id = email.id().unwrap_or_default().to_string();
+        match result {
+            Ok(_) if raw.is_none() => {
+                report.indexed += 1;
+                report.without_message.push(id);
+            }
+            Ok(_) => report.indexed += 1,
+            Err(e) => {
+                warn!("Error indexing email {}. {:#}", id, e);
+                report.failed.push(layout::email_path(&id));
+            }
+        }
+    }
+
+    pb.inc(batch.len() as u64);
+}
+
+#[derive(Debug, Default)]
+pub struct DedupeReport {
+    /// Emails whose duplicate documents were replaced by one
+    pub deduplicated: u64,
+    /// Emails left with duplicates, as they could not be read from the archive
+    pub failed: Vec<String>,
+    /// Set when the index had an older schema and was rebuilt from the archive instead
+    pub rebuilt: Option<ReindexReport>,
+}
+
+/**
+ * Replace the documents of emails indexed more than once with a single document written from the archive.
+ * Emails whose message cannot be read keep their documents rather than losing their text.
+ * Indexes with an older schema cannot have single documents replaced, as their ids were split into words,
+ * so they are rebuilt from the archive, see `reindex`.
+ */
+pub async fn dedupe_index(
+    operator: &Operator,
+    search_conf: &conf::Search,
+    pb: &dyn Progressable,
+    concurrency: usize,
+    memory_limit: usize,
+) -> anyhow::Result<DedupeReport> {
+    let mut report = DedupeReport::default();
+    let message_parser = MessageParser::default();
+
+    if matches!(schema_version(&search_conf.folder)?, Some(version) if version < SCHEMA_VERSION) {
+        info!("Search index uses an older schema, rebuilding it from the archive");
+        report.rebuilt = Some(reindex(operator, search_conf, pb, concurrency, memory_limit).await?);
+        return Ok(report);
+    }
+
+    let ids = duplicate_ids(search_conf).with_context(|| "Error finding duplicates")?;
+    info!("Found {} emails indexed more than once", ids.len());
+    pb.set_length(ids.len() as u64);
+    if ids.is_empty() {
+        return Ok(report);
+    }
+
+    let mailbox_names = read_mailbox_names(operator)
+        .await
+        .with_context(|| "Error reading mailbox names")?;
+    let mut indexer = create_indexer(search_conf).with_context(|| "Error creating indexer")?;
+
+    for id in ids {
+        // Emails are stored by the first characters of their id, so shorter ids cannot be in the archive
+        if id.len() < 3 {
+            report.failed.push(id);
+            pb.inc(1);
+            continue;
+        }
+
+        let path = layout::email_path(&id);
+        let (email, message) = match read_email_and_message(operator, &path).await {
+            Ok((email, Some(message))) => (email, message),
+            Ok((_, None)) => {
+                report.failed.push(id);
+                pb.inc(1);
+                continue;
+            }
+            Err(e) => {
+                warn!("Error reading {}. {:#}", path, e);
+                report.failed.push(id);
+                pb.inc(1);
+                continue;
+            }
+        };
+
+        let message = message_parser.parse(&message).unwrap_or_default();
+        match write_document(&indexer, &email, &message, &mailbox_names) {
+            Ok(_) => report.deduplicated += 1,
+            Err(e) => {
+                warn!("Error indexing email {}. {:#}", id, e);
+                report.failed.push(id);
+            }
+        }
+        pb.inc(1);
+    }
+
+    indexer.commit()?;
+
+    Ok(report)
+}
+
+/// Paths of every email in the archive
+async fn list_emails(operator: &Operator) -> anyhow::Result<Vec<String>> {
+    if !operator.is_exist(layout::EMAILS_FOLDER).await.unwrap_or(false) {
+        return Ok(vec![]);
+    }
+
+    let lister = operator
+        .lister_with(layout::EMAILS_FOLDER)
+        .recursive(true)
+        .metakey(Metakey::Mode)
+        .await
+        .with_context(|| format!("Error listing {}", layout::EMAILS_FOLDER))?;
+
+    let paths = lister
+        .try_filter(|entry| futures::future::ready(entry.metadata().is_file() && entry.path().ends_with(".json")))
+        .map_ok(|entry| format!("/{}", entry.path().trim_start_matches('/')))
+        .try_collect::<Vec<_>>()
+        .await
+        .with_context(|| format!("Error listing {}", layout::EMAILS_FOLDER))?;
+
+    Ok(paths)
+}
+
+/// Read an email and its message, None for the message if it is missing or unreadable
+async fn read_email_and_message(operator: &Operator, path: &str) -> anyhow::Result<(Email, Option<Vec<u8>>)> {
+    let email_json = operator.read(path).await.with_context(|| format!("Error reading {}", path))?;
+    let email: Email = serde_json::from_slice(&email_json).with_context(|| format!("Error deserializing {}", path))?;
+
+    if email.id().is_none() {
+        anyhow::bail!("Email in {} has no id", path);
+    }
+
+    let message = match email.blob_id() {
+        Some(blob_id) => match blob::read_message(operator, blob_id).await {
+            Ok(message) => Some(message),
+            Err(e) => {
+                warn!("Error reading message of {}. {:#}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    Ok((email, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::{
+        search::{search, SearchOptions},
+        storage::fs_operator,
+    };
+
+    struct Counter(std::sync::atomic::AtomicU64);
+
+    impl Progressable for Counter {
+        fn position(&self) -> u64 {
+            self.0.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn set_position(&self, position: u64) {
+            self.0.store(position, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn set_length(&self, _total: u64) {}
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let archive = TempDir::new().unwrap();
+        let index = TempDir::new().unwrap();
+        let operator = fs_operator(&archive);
+        let search_conf = conf::Search {
+            enable: true,
+            folder: index.path().join("search").to_str().unwrap().to_string(),
+            languages: vec![],
+            sync: false,
+        };
+
+        operator
+            .write(
+                &layout::email_path("Mabc1"),
+                r#"{"id": "Mabc1", "blobId": "Gabc1", "subject": "Invoice", "receivedAt": "2024-03-01T12:00:00Z"}"#,
+            )
+            .await
+            .unwrap();
+        operator
+            .write(&layout::blob_path("Gabc1"), "Subject: Invoice\r\n\r\nPlease pay by friday.\r\n")
+            .await
+            .unwrap();
+        // The message of this email was never stored
+        operator
+            .write(&layout::email_path("Mabc2"), r#"{"id": "Mabc2", "blobId": "Gabc2", "subject": "Receipt"}"#)
+            .await
+            .unwrap();
+        operator.write(&layout::email_path("Mabc3"), "not json").await.unwrap();
+
+        let pb = Counter(Default::default());
+        // A limit below the size of any message indexes one email at a time
+        let report = reindex(&operator, &search_conf, &pb, 4, 1).await.unwrap();
+
+        assert_eq!(report.indexed, 2);
+        assert_eq!(report.without_message, ["Mabc2"]);
+        assert_eq!(report.failed, [layout::email_path("Mabc3")]);
+        assert_eq!(pb.position(), 3);
+
+        let count = |query: &str| search(&search_conf, query.to_string(), &SearchOptions::default()).unwrap().len();
+        assert_eq!(count("friday"), 1);
+        assert_eq!(count("receipt"), 1);
+
+        // Reindexing again replaces the index rather than adding to it
+        reindex(&operator, &search_conf, &pb, 4, 1024 * 1024).await.unwrap();
+        assert_eq!(count("subject:invoice OR subject:receipt"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_rebuilds_unversioned_index() {
+        let archive = TempDir::new().unwrap();
+        let index = TempDir::new().unwrap();
+        let operator = fs_operator(&archive);
+        let search_conf = conf::Search {
+            enable: true,
+            folder: index.path().join("search").to_str().unwrap().to_string(),
+            languages: vec![],
+            sync: false,
+        };
+
+        operator
+            .write(&layout::email_path("Mabc1"), r#"{"id": "Mabc1", "blobId": "Gabc1", "subject": "Invoice"}"#)
+            .await
+            .unwrap();
+        operator
+            .write(&layout::blob_path("Gabc1"), "Subject: Invoice\r\n\r\nPlease pay by friday.\r\n")
+            .await
+            .unwrap();
+
+        // Indexes written before the schema was versioned tokenized the id and have no version file
+        let mut builder = tantivy::schema::Schema::builder();
+        let id = builder.add_text_field("id", tantivy::schema::TEXT | tantivy::schema::STORED);
+        let subject = builder.add_text_field("subject", tantivy::schema::TEXT | tantivy::schema::STORED);
+        std::fs::create_dir_all(&search_conf.folder).unwrap();
+        let legacy = tantivy::Index::create_in_dir(&search_conf.folder, builder.build()).unwrap();
+        let mut writer = legacy.writer(15_000_000).unwrap();
+        for _ in 0..2 {
+            writer.add_document(tantivy::doc!(id => "Mabc1", subject => "Invoice")).unwrap();
+        }
+        writer.commit().unwrap();
+        assert_eq!(schema_version(&search_conf.folder).unwrap(), Some(1));
+
+        let pb = Counter(Default::default());
+        let report = dedupe_index(&operator, &search_conf, &pb, 4, 1024 * 1024).await.unwrap();
+
+        assert_eq!(report.rebuilt.unwrap().indexed, 1);
+        assert_eq!(schema_version(&search_conf.folder).unwrap(), Some(SCHEMA_VERSION));
+        assert!(duplicate_ids(&search_conf).unwrap().is_empty());
+        let results = search(&search_conf, "friday".to_string(), &SearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
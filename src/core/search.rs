@@ -1,53 +1,295 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, path::Path};
 
 use anyhow::Context;
 use jmap_client::email::Email;
 use mail_parser::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tantivy::{
     collector::TopDocs,
     directory::MmapDirectory,
-    query::QueryParser,
-    schema::{Field, Schema, STORED, TEXT},
-    Document, Index, IndexWriter,
+    schema::{
+        Facet, FacetOptions, Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, FAST, INDEXED, STORED,
+        STRING, TEXT,
+    },
+    tokenizer::{AsciiFoldingFilter, Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer},
+    DateTime, DocAddress, Document, Index, IndexWriter, Order, SnippetGenerator, Term,
 };
 
-struct EmailSchema<'a> {
+use crate::conf;
+
+use super::{extract::attachment_texts, mailboxes::MailboxNames, query, text::message_text};
+
+/// Version of the index schema, bumped whenever fields change so an outdated index is rebuilt rather than misread.
+/// Indexes written before the schema was versioned are version 1
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Languages that can be set in `search.languages`, by ISO 639-1 code, each with its own stemmed fields
+pub const LANGUAGES: [(&str, Language); 18] = [
+    ("ar", Language::Arabic),
+    ("da", Language::Danish),
+    ("de", Language::German),
+    ("el", Language::Greek),
+    ("en", Language::English),
+    ("es", Language::Spanish),
+    ("fi", Language::Finnish),
+    ("fr", Language::French),
+    ("hu", Language::Hungarian),
+    ("it", Language::Italian),
+    ("nl", Language::Dutch),
+    ("no", Language::Norwegian),
+    ("pt", Language::Portuguese),
+    ("ro", Language::Romanian),
+    ("ru", Language::Russian),
+    ("sv", Language::Swedish),
+    ("ta", Language::Tamil),
+    ("tr", Language::Turkish),
+];
+
+/// Lower cases and folds accents, so "Café" and "cafe" match, used for the subject and body
+const FOLDED_TOKENIZER: &str = "folded";
+
+/// Written next to the tantivy files, recording the schema version of the index
+pub const VERSION_FILE: &str = "postkasse-index.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexFormat {
+    schema_version: u32,
+}
+
+pub(super) struct EmailSchema<'a> {
     // Map of string field names to field types
-    fields: HashMap<&'a str, Field>,
+    pub(super) fields: HashMap<&'a str, Field>,
+    // Stemmed subject and body fields of each language
+    pub(super) language_fields: Vec<(Language, Field, Field)>,
     schema: Schema,
 }
 
+/// Writes emails to the index, in the languages configured for search
+pub struct Indexer {
+    writer: IndexWriter,
+    languages: Vec<Language>,
+    /// Folder of the index, if it is copied to the storage after every commit
+    sync_folder: Option<String>,
+}
+
+impl Indexer {
+    pub fn sync_folder(&self) -> Option<&str> {
+        self.sync_folder.as_deref()
+    }
+
+    pub fn commit(&mut self) -> anyhow::Result<u64> {
+        self.writer
+            .commit()
+            .with_context(|| "Error committing indexer")
+    }
+}
+
+/// Characters of the body shown in the snippet of a search result
+const SNIPPET_LENGTH: usize = 150;
+
+/// Names of the fields of search results, as selected for display
+pub const RESULT_FIELDS: [&str; 17] = [
+    "id",
+    "blob_id",
+    "thread_id",
+    "date",
+    "subject",
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "mailboxes",
+    "mailbox_ids",
+    "keywords",
+    "size",
+    "has_attachment",
+    "attachments",
+    "score",
+    "snippet",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum SearchSort {
+    /// Best matches first
+    #[default]
+    Relevance,
+    /// Newest emails first
+    Date,
+}
+
+pub struct SearchOptions {
+    pub limit: usize,
+    /// Number of results to skip, for paging through results
+    pub offset: usize,
+    pub sort: SearchSort,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions { limit: 100, offset: 0, sort: SearchSort::Relevance }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Address {
+    pub name: String,
+    pub email: String,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name.is_empty() {
+            true => write!(f, "{}", self.email),
+            false => write!(f, "{} <{}>", self.name, self.email),
+        }
+    }
+}
+
+/// Part of the body of a search result, with the byte ranges of the matched words
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snippet {
+    pub text: String,
+    pub highlighted: Vec<Range<usize>>,
+}
+
 // Used for search results
+#[derive(Debug, Clone)]
 pub struct SearchResult {
     pub id: String,
     pub blob_id: String,
+    pub thread_id: String,
+    pub received_at: Option<chrono::DateTime<chrono::Utc>>,
     pub subject: String,
+    pub from: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<String>,
+    /// Paths of the mailboxes, e.g. Inbox/Receipts
+    pub mailboxes: Vec<String>,
+    pub mailbox_ids: Vec<String>,
+    pub keywords: Vec<String>,
+    pub size: u64,
+    pub has_attachment: bool,
+    pub attachments: Vec<String>,
+    /// Relevance of the result, only known when sorting by relevance
+    pub score: Option<f32>,
+    pub snippet: Snippet,
+}
+
+impl SearchResult {
+    /**
+     * Text of one of the `RESULT_FIELDS`, with lists separated by commas, or None for unknown fields.
+     */
+    pub fn field(&self, name: &str) -> Option<String> {
+        let join = |values: &[String]| values.join(", ");
+        let join_addresses = |addresses: &[Address]| addresses.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ");
+
+        let value = match name {
+            "id" => self.id.clone(),
+            "blob_id" => self.blob_id.clone(),
+            "thread_id" => self.thread_id.clone(),
+            "date" => self.received_at.map(|date| date.to_rfc3339()).unwrap_or_default(),
+            "subject" => self.subject.clone(),
+            "from" => join_addresses(&self.from),
+            "to" => join_addresses(&self.to),
+            "cc" => join_addresses(&self.cc),
+            "bcc" => join(&self.bcc),
+            "mailboxes" => join(&self.mailboxes),
+            "mailbox_ids" => join(&self.mailbox_ids),
+            "keywords" => join(&self.keywords),
+            "size" => self.size.to_string(),
+            "has_attachment" => self.has_attachment.to_string(),
+            "attachments" => join(&self.attachments),
+            "score" => self.score.map(|score| score.to_string()).unwrap_or_default(),
+            "snippet" => self.snippet.text.clone(),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /**
+     * JSON value of one of the `RESULT_FIELDS`, keeping lists, numbers and booleans typed, or None for unknown fields.
+     */
+    pub fn json(&self, name: &str) -> Option<serde_json::Value> {
+        let value = match name {
+            "date" => json!(self.received_at.map(|date| date.to_rfc3339())),
+            "from" => json!(self.from),
+            "to" => json!(self.to),
+            "cc" => json!(self.cc),
+            "bcc" => json!(self.bcc),
+            "mailboxes" => json!(self.mailboxes),
+            "mailbox_ids" => json!(self.mailbox_ids),
+            "keywords" => json!(self.keywords),
+            "size" => json!(self.size),
+            "has_attachment" => json!(self.has_attachment),
+            "attachments" => json!(self.attachments),
+            "score" => json!(self.score),
+            name => json!(self.field(name)?),
+        };
+
+        Some(value)
+    }
 }
 
 // We use lazy_static to ensure that the schema is only built once
 // and then reused for all operations. This is a performance optimization.
 lazy_static! {
-    static ref EMAIL_SCHEMA: EmailSchema<'static> = schema_builder();
+    pub(super) static ref EMAIL_SCHEMA: EmailSchema<'static> = schema_builder();
 }
 
 fn schema_builder() -> EmailSchema<'static> {
     let mut schema_builder = Schema::builder();
-    let id = schema_builder.add_text_field("id", TEXT | STORED);
-    let blob_id = schema_builder.add_text_field("blob_id", TEXT | STORED);
-    let subject = schema_builder.add_text_field("subject", TEXT | STORED);
+    // Ids are matched exactly, never tokenized
+    let id = schema_builder.add_text_field("id", STRING | STORED | FAST);
+    let blob_id = schema_builder.add_text_field("blob_id", STRING | STORED);
+    let thread_id = schema_builder.add_text_field("thread_id", STRING | STORED | FAST);
+    let folded = TextOptions::default().set_stored().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(FOLDED_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+    let subject = schema_builder.add_text_field("subject", folded.clone());
     let from_name = schema_builder.add_text_field("from_name", TEXT | STORED);
     let from_email = schema_builder.add_text_field("from_email", TEXT | STORED);
     let to_name = schema_builder.add_text_field("to_name", TEXT | STORED);
     let to_email = schema_builder.add_text_field("to_email", TEXT | STORED);
-    let cc_name = schema_builder.add_text_field("cc_name", TEXT);
-    let cc_email = schema_builder.add_text_field("cc_email", TEXT);
-    let bcc = schema_builder.add_text_field("bcc", TEXT);
-    let body = schema_builder.add_text_field("body", TEXT);
+    let cc_name = schema_builder.add_text_field("cc_name", TEXT | STORED);
+    let cc_email = schema_builder.add_text_field("cc_email", TEXT | STORED);
+    let bcc = schema_builder.add_text_field("bcc", TEXT | STORED);
+    let body = schema_builder.add_text_field("body", folded);
+    let attachment_name = schema_builder.add_text_field("attachment_name", TEXT | STORED);
+    // Attachments can be large, so their text is only indexed
+    let attachment_text = schema_builder.add_text_field("attachment_text", TEXT);
+    let received_at = schema_builder.add_date_field("received_at", INDEXED | STORED | FAST);
+    let mailbox_ids = schema_builder.add_text_field("mailbox_ids", STRING | STORED | FAST);
+    // Mailbox names as paths from the top level mailbox, e.g. /Inbox/Receipts
+    let mailboxes = schema_builder.add_facet_field("mailboxes", FacetOptions::default().set_stored());
+    let keywords = schema_builder.add_text_field("keywords", STRING | STORED | FAST);
+    let size = schema_builder.add_u64_field("size", INDEXED | STORED | FAST);
+    let has_attachment = schema_builder.add_bool_field("has_attachment", INDEXED | STORED | FAST);
+
+    // Fields of languages not configured are left empty, which costs nothing
+    let language_fields = LANGUAGES
+        .iter()
+        .map(|(code, language)| {
+            let stemmed = TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(&stemmed_tokenizer(code))
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            );
+            let subject = schema_builder.add_text_field(&format!("subject_{}", code), stemmed.clone());
+            let body = schema_builder.add_text_field(&format!("body_{}", code), stemmed);
+            (*language, subject, body)
+        })
+        .collect();
 
     EmailSchema {
         fields: vec![
             ("id", id),
             ("blob_id", blob_id),
+            ("thread_id", thread_id),
             ("subject", subject),
             ("from_name", from_name),
             ("from_email", from_email),
@@ -57,35 +299,196 @@ fn schema_builder() -> EmailSchema<'static> {
             ("cc_email", cc_email),
             ("bcc", bcc),
             ("body", body),
+            ("attachment_name", attachment_name),
+            ("attachment_text", attachment_text),
+            ("received_at", received_at),
+            ("mailbox_ids", mailbox_ids),
+            ("mailboxes", mailboxes),
+            ("keywords", keywords),
+            ("size", size),
+            ("has_attachment", has_attachment),
         ]
         .into_iter()
         .collect(),
+        language_fields,
         schema: schema_builder.build(),
     }
 }
 
+fn stemmed_tokenizer(code: &str) -> String {
+    format!("stemmed_{}", code)
+}
+
+/// Register the analyzers the schema refers to, needed every time an index is opened
+fn register_tokenizers(index: &Index) {
+    let folded = || {
+        TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .filter(AsciiFoldingFilter)
+    };
+
+    index.tokenizers().register(FOLDED_TOKENIZER, folded().build());
+    for (code, language) in LANGUAGES {
+        index
+            .tokenizers()
+            .register(&stemmed_tokenizer(code), folded().filter(Stemmer::new(language)).build());
+    }
+}
+
+/**
+ * Parse the configured search language codes, refusing languages there is no stemmer for
+ */
+pub fn search_languages(codes: &[String]) -> anyhow::Result<Vec<Language>> {
+    codes
+        .iter()
+        .map(|code| {
+            LANGUAGES
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(code))
+                .map(|(_, language)| *language)
+                .with_context(|| {
+                    let known = LANGUAGES.map(|(known, _)| known).join(", ");
+                    format!("Unsupported search language {}, use one of {}", code, known)
+                })
+        })
+        .collect()
+}
+
+
+pub fn create_indexer(search_conf: &conf::Search) -> anyhow::Result<Indexer> {
+    let mut indexer = create_indexer_in(&search_conf.folder, &search_conf.languages)?;
+    indexer.sync_folder = search_conf.sync.then(|| search_conf.folder.clone());
+    Ok(indexer)
+}
+
+fn create_indexer_in(folder: &str, language_codes: &[String]) -> anyhow::Result<Indexer> {
+    let languages = search_languages(language_codes)?;
 
-pub fn create_indexer(folder: String) -> anyhow::Result<IndexWriter> {
     // Ensure folder exists, if not create it
-    std::fs::create_dir_all(&folder)
+    std::fs::create_dir_all(folder)
         .with_context(|| format!("Error creating folder {}", folder))?;
 
+    check_schema_version(folder, true)?;
+
     let schema = EMAIL_SCHEMA.schema.clone();
     let directory = MmapDirectory::open(folder)?;
     let index = Index::open_or_create(directory, schema.clone())?;
-    let indexer = index.writer(50_000_000)?;
-    return Ok(indexer);
+    register_tokenizers(&index);
+    let writer = index.writer(50_000_000)?;
+    Ok(Indexer { writer, languages, sync_folder: None })
+}
+
+fn rebuild_folder(search_conf: &conf::Search) -> String {
+    format!("{}.rebuild", search_conf.folder.trim_end_matches('/'))
+}
+
+/**
+ * Create an indexer writing a new, empty index next to the configured one.
+ * Searches keep using the current index until the new one replaces it with `finish_rebuild`.
+ */
+pub fn create_rebuild_indexer(search_conf: &conf::Search) -> anyhow::Result<Indexer> {
+    let folder = rebuild_folder(search_conf);
+
+    // Left behind by an interrupted rebuild
+    if Path::new(&folder).exists() {
+        std::fs::remove_dir_all(&folder).with_context(|| format!("Error removing folder {}", folder))?;
+    }
+
+    create_indexer_in(&folder, &search_conf.languages)
+}
+
+/**
+ * Replace the configured index with the one written by the indexer from `create_rebuild_indexer`.
+ */
+pub fn finish_rebuild(search_conf: &conf::Search, mut indexer: Indexer) -> anyhow::Result<()> {
+    indexer.commit()?;
+    indexer
+        .writer
+        .wait_merging_threads()
+        .with_context(|| "Error waiting for the index to be merged")?;
+
+    let folder = search_conf.folder.trim_end_matches('/');
+    let old_folder = format!("{}.old", folder);
+    if Path::new(folder).exists() {
+        if Path::new(&old_folder).exists() {
+            std::fs::remove_dir_all(&old_folder).with_context(|| format!("Error removing folder {}", old_folder))?;
+        }
+        std::fs::rename(folder, &old_folder).with_context(|| format!("Error moving {} to {}", folder, old_folder))?;
+    }
+
+    let rebuilt = rebuild_folder(search_conf);
+    std::fs::rename(&rebuilt, folder).with_context(|| format!("Error moving {} to {}", rebuilt, folder))?;
+
+    if Path::new(&old_folder).exists() {
+        std::fs::remove_dir_all(&old_folder).with_context(|| format!("Error removing folder {}", old_folder))?;
+    }
+
+    Ok(())
 }
 
 /**
- * Write a document to the index.
+ * Check that the index in the folder uses the current schema.
+ * A folder without an index gets the current version recorded when `create` is set.
+ */
+fn check_schema_version(folder: &str, create: bool) -> anyhow::Result<()> {
+    let version = match schema_version(folder)? {
+        Some(version) => version,
+        None if create => {
+            let version_path = Path::new(folder).join(VERSION_FILE);
+            let format_json = serde_json::to_string_pretty(&IndexFormat { schema_version: SCHEMA_VERSION })
+                .with_context(|| "Error serializing index format")?;
+            std::fs::write(&version_path, format_json)
+                .with_context(|| format!("Error writing {}", version_path.display()))?;
+            return Ok(());
+        }
+        None => anyhow::bail!("There is no search index in {}, run a backup or `postkasse reindex` first", folder),
+    };
+
+    if version != SCHEMA_VERSION {
+        anyhow::bail!(
+            "Search index in {} uses schema version {}, this version of postkasse uses {}. \
+            Run `postkasse reindex` to rebuild it from the archive",
+            folder,
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+/**
+ * Schema version of the index in the folder, None if there is no index yet.
+ * Indexes written before the schema was versioned have no version file and are version 1.
+ */
+pub fn schema_version(folder: &str) -> anyhow::Result<Option<u32>> {
+    let version_path = Path::new(folder).join(VERSION_FILE);
+    let has_index = Path::new(folder).join("meta.json").exists();
+
+    match std::fs::read(&version_path) {
+        Ok(format_json) => {
+            let format: IndexFormat = serde_json::from_slice(&format_json)
+                .with_context(|| format!("Error deserializing {}", version_path.display()))?;
+            Ok(Some(format.schema_version))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && has_index => Ok(Some(1)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Error reading {}", version_path.display())),
+    }
+}
+
+/**
+ * Write a document to the index, replacing any document of the same email.
  * The document is created using both the JMAP response and the parsed email message.
- * This way the full body of the email message is indexed.
+ * This way the full body of the email message is indexed, and writing an email again, as backups do
+ * for the emails they overlap with, updates its keywords and mailboxes instead of adding a duplicate.
  */
 pub fn write_document(
-    indexer: &IndexWriter,
+    indexer: &Indexer,
     email: &Email,
     message: &Message,
+    mailbox_names: &MailboxNames,
 ) -> anyhow::Result<u64> {
     let fields = &EMAIL_SCHEMA.fields;
     let mut doc = Document::new();
@@ -94,6 +497,28 @@ pub fn write_document(
     doc.add_text(fields["blob_id"], email.blob_id().unwrap());
     doc.add_text(fields["subject"], email.subject().unwrap_or_default());
 
+    if let Some(thread_id) = email.thread_id() {
+        doc.add_text(fields["thread_id"], thread_id);
+    }
+
+    if let Some(received_at) = email.received_at() {
+        doc.add_date(fields["received_at"], DateTime::from_timestamp_secs(received_at));
+    }
+
+    for mailbox_id in email.mailbox_ids() {
+        doc.add_text(fields["mailbox_ids"], mailbox_id);
+        if let Some(name) = mailbox_names.get(mailbox_id) {
+            doc.add_facet(fields["mailboxes"], Facet::from_path(name));
+        }
+    }
+
+    for keyword in email.keywords() {
+        doc.add_text(fields["keywords"], keyword);
+    }
+
+    doc.add_u64(fields["size"], email.size() as u64);
+    doc.add_bool(fields["has_attachment"], email.has_attachment());
+
     for from in email.from().unwrap_or_default() {
         doc.add_text(fields["from_name"], from.name().unwrap_or_default());
         doc.add_text(fields["from_email"], from.email());
@@ -109,59 +534,172 @@ pub fn write_document(
         doc.add_text(fields["cc_name"], cc.name().unwrap_or_default());
     }
 
-    let body_text = message.body_html(0).unwrap_or_default();
-    
-    doc.add_text(fields["body"], body_text);
+    for bcc in email.bcc().unwrap_or_default() {
+        doc.add_text(fields["bcc"], bcc.email());
+    }
 
+    let body = message_text(message);
+    for (language, subject_field, body_field) in &EMAIL_SCHEMA.language_fields {
+        if indexer.languages.contains(language) {
+            doc.add_text(*subject_field, email.subject().unwrap_or_default());
+            doc.add_text(*body_field, &body);
+        }
+    }
+    doc.add_text(fields["body"], body);
+
+    for attachment in attachment_texts(message) {
+        if let Some(name) = attachment.name {
+            doc.add_text(fields["attachment_name"], name);
+        }
+        if let Some(text) = attachment.text {
+            doc.add_text(fields["attachment_text"], text);
+        }
+    }
+
+    // Deletes only apply to documents added before them, so this leaves the new document alone
+    indexer
+        .writer
+        .delete_term(Term::from_field_text(fields["id"], email.id().unwrap()));
     indexer
+        .writer
         .add_document(doc)
-        .with_context(|| format!("Error adding document to index"))
+        .with_context(|| "Error adding document to index")
+}
+
+/**
+ * Ids of the emails with more than one document in the index,
+ * left by versions of postkasse that added emails again rather than replacing them.
+ */
+pub fn duplicate_ids(search_conf: &conf::Search) -> anyhow::Result<Vec<String>> {
+    check_schema_version(&search_conf.folder, false)?;
+    let index = Index::open_in_dir(&search_conf.folder)?;
+    let searcher = index.reader()?.searcher();
+    let mut counts: HashMap<String, u32> = HashMap::new();
+
+    // Ids are read from the fast field, which is much cheaper than loading every stored document
+    for segment in searcher.segment_readers() {
+        let Some(ids) = segment.fast_fields().str("id")? else {
+            continue;
+        };
+
+        let mut id = String::new();
+        for doc in segment.doc_ids_alive() {
+            for ord in ids.term_ords(doc) {
+                id.clear();
+                ids.ord_to_str(ord, &mut id)?;
+                *counts.entry(id.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    let mut duplicates = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    duplicates.sort();
+
+    Ok(duplicates)
 }
 
 /**
- * Search the index for the given query, searching in the subject and body fields.
- * Limit to 100 results by default, but allow the limit to be set.
- * Return a vector of search results to be displayed, each result containing the jmap id, blob_id and subject.
+ * Search the index with a query in the search query language, see `query::parse`.
+ * Words are searched in the subject, body and attachments, and the stemmed subject and body
+ * in the configured languages.
+ * Return a page of results, by relevance or newest first, each with a snippet of the body showing the matched words.
  */
-pub fn search(folder: String, query: String, limit: Option<usize>) -> anyhow::Result<Vec<SearchResult>> {
-    let index = Index::open_in_dir(folder)?;
+pub fn search(search_conf: &conf::Search, query: String, options: &SearchOptions) -> anyhow::Result<Vec<SearchResult>> {
+    if options.limit == 0 {
+        anyhow::bail!("The limit must be at least 1");
+    }
+
+    let languages = search_languages(&search_conf.languages)?;
+    check_schema_version(&search_conf.folder, false)?;
+    let index = Index::open_in_dir(&search_conf.folder)?;
+    register_tokenizers(&index);
     let reader = index.reader()?;
     let searcher = reader.searcher();
-    let query_parser = QueryParser::for_index(
-        &index,
-        vec![EMAIL_SCHEMA.fields["subject"], EMAIL_SCHEMA.fields["body"]],
-    );
-    let query = query_parser.parse_query(&query)?;
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(limit.unwrap_or(100)))?;
-    let mut docs: Vec<SearchResult> = vec![];
 
-    for (_score, doc_address) in top_docs {
+    let expr = query::parse(&query, chrono::Utc::now())?;
+    let query = query::to_query(&expr, &index, &searcher, &languages)?;
+
+    let collector = TopDocs::with_limit(options.limit).and_offset(options.offset);
+    let hits: Vec<(Option<f32>, DocAddress)> = match options.sort {
+        SearchSort::Relevance => searcher
+            .search(&query, &collector)?
+            .into_iter()
+            .map(|(score, address)| (Some(score), address))
+            .collect(),
+        SearchSort::Date => searcher
+            .search(&query, &collector.order_by_fast_field::<DateTime>("received_at", Order::Desc))?
+            .into_iter()
+            .map(|(_, address)| (None, address))
+            .collect(),
+    };
+
+    let mut snippet_generator = SnippetGenerator::create(&searcher, &*query, EMAIL_SCHEMA.fields["body"])?;
+    snippet_generator.set_max_num_chars(SNIPPET_LENGTH);
+
+    let mut docs: Vec<SearchResult> = vec![];
+    for (score, doc_address) in hits {
         let doc = searcher.doc(doc_address)?;
-        let id = doc
-            .get_first(EMAIL_SCHEMA.fields["blob_id"])
-            .map(|val| val.as_text())
-            .unwrap_or_default()
-            .unwrap_or_default()
-            .to_string(); // Convert Option<&str> to String
-
-        let blob_id = doc
-            .get_first(EMAIL_SCHEMA.fields["blob_id"])
-            .map(|val| val.as_text())
-            .unwrap_or_default()
-            .unwrap_or_default()
-            .to_string(); // Convert Option<&str> to String
-
-        let subject = doc
-            .get_first(EMAIL_SCHEMA.fields["subject"])
-            .map(|val| val.as_text())
-            .unwrap_or_default()
-            .unwrap_or_default()
-            .to_string(); // Convert Option<&str> to String
-        
-        docs.push(SearchResult { id, blob_id, subject });
+        docs.push(read_result(&doc, score, &snippet_generator));
     }
 
-    return Ok(docs);
+    Ok(docs)
+}
+
+fn read_result(doc: &Document, score: Option<f32>, snippet_generator: &SnippetGenerator) -> SearchResult {
+    let fields = &EMAIL_SCHEMA.fields;
+    let text = |name: &str| doc.get_first(fields[name]).and_then(|value| value.as_text()).unwrap_or_default().to_string();
+    let texts = |name: &str| {
+        doc.get_all(fields[name])
+            .filter_map(|value| value.as_text())
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    // Names and addresses are written in pairs, with an empty name for addresses without one
+    let addresses = |name_field: &str, email_field: &str| {
+        texts(name_field)
+            .into_iter()
+            .zip(texts(email_field))
+            .map(|(name, email)| Address { name, email })
+            .collect::<Vec<_>>()
+    };
+
+    let snippet = snippet_generator.snippet_from_doc(doc);
+    let snippet = match snippet.is_empty() {
+        // Nothing matched in the body, e.g. for searches on other fields, so show its start instead
+        true => Snippet { text: text("body").chars().take(SNIPPET_LENGTH).collect(), highlighted: vec![] },
+        false => Snippet { text: snippet.fragment().to_string(), highlighted: snippet.highlighted().to_vec() },
+    };
+
+    SearchResult {
+        id: text("id"),
+        blob_id: text("blob_id"),
+        thread_id: text("thread_id"),
+        received_at: doc
+            .get_first(fields["received_at"])
+            .and_then(|value| value.as_date())
+            .and_then(|date| chrono::DateTime::from_timestamp(date.into_timestamp_secs(), 0)),
+        subject: text("subject"),
+        from: addresses("from_name", "from_email"),
+        to: addresses("to_name", "to_email"),
+        cc: addresses("cc_name", "cc_email"),
+        bcc: texts("bcc"),
+        mailboxes: doc
+            .get_all(fields["mailboxes"])
+            .filter_map(|value| value.as_facet())
+            .map(|facet| facet.to_path_string().trim_start_matches('/').to_string())
+            .collect(),
+        mailbox_ids: texts("mailbox_ids"),
+        keywords: texts("keywords"),
+        size: doc.get_first(fields["size"]).and_then(|value| value.as_u64()).unwrap_or_default(),
+        has_attachment: doc.get_first(fields["has_attachment"]).and_then(|value| value.as_bool()).unwrap_or_default(),
+        attachments: texts("attachment_name"),
+        score,
+        snippet,
+    }
 }
 
 
@@ -169,16 +707,25 @@ pub fn search(folder: String, query: String, limit: Option<usize>) -> anyhow::Re
 #[cfg(test)]
 mod tests {
     use mail_parser::MessageParser;
+    use tantivy::query::QueryParser;
     use tempfile::TempDir;
 
     use super::*;
 
+    fn search_conf(temp_dir: &TempDir, languages: &[&str]) -> conf::Search {
+        conf::Search {
+            enable: true,
+            folder: temp_dir.path().to_str().unwrap().to_string(),
+            languages: languages.iter().map(|code| code.to_string()).collect(),
+            sync: false,
+        }
+    }
+
     #[test]
     fn test_create_indexer() {
         // Create an indexer and index a document to a temporary directory
         let temp_dir = TempDir::new().unwrap();
-        let path: String = temp_dir.path().to_str().unwrap().to_string();
-        let indexer = create_indexer(path);
+        let indexer = create_indexer(&search_conf(&temp_dir, &[]));
         
         assert!(indexer.is_ok());
     }
@@ -187,9 +734,7 @@ mod tests {
     fn test_write_document() {
         // Create an indexer and index a document to a temporary directory
         let temp_dir = TempDir::new().unwrap();
-        let path: String = temp_dir.path().to_str().unwrap().to_string();
-        println!("Path: {}", path);
-        let mut indexer = create_indexer(path).unwrap();
+        let mut indexer = create_indexer(&search_conf(&temp_dir, &[])).unwrap();
 
         // Create email from a JSON string
         let email = serde_json::from_str::<Email>(
@@ -226,7 +771,7 @@ So, "Hello".
             "#
         );
 
-        let result = write_document(&indexer, &email.unwrap(), &message.unwrap());
+        let result = write_document(&indexer, &email.unwrap(), &message.unwrap(), &MailboxNames::new());
         indexer.commit().unwrap();
 
         assert!(result.is_ok());
@@ -236,12 +781,10 @@ So, "Hello".
     fn test_search() {
         // Create an indexer and index a document to a temporary directory
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path().to_path_buf();
-        let index_path: String = path.to_str().unwrap().to_string();
-        let search_path = path.to_str().unwrap().to_string();
+        let conf = search_conf(&temp_dir, &[]);
 
         // Create indexer
-        let mut indexer = create_indexer(index_path).unwrap();
+        let mut indexer = create_indexer(&conf).unwrap();
 
         // Create email from a JSON string
         let email = serde_json::from_str::<Email>(
@@ -281,10 +824,10 @@ So, "Hello".
 
         let message = MessageParser::default().parse(input);
 
-        write_document(&indexer, &email.unwrap(), &message.unwrap()).unwrap();
+        write_document(&indexer, &email.unwrap(), &message.unwrap(), &MailboxNames::new()).unwrap();
         indexer.commit().unwrap();
-        let results = search(search_path.clone(), "Hello".to_string(), Some(10));
-        let no_results = search(search_path.clone(), "Goodbye".to_string(), Some(10));
+        let results = search(&conf, "Hello".to_string(), &SearchOptions { limit: 10, ..Default::default() });
+        let no_results = search(&conf, "Goodbye".to_string(), &SearchOptions { limit: 10, ..Default::default() });
         
         assert!(results.is_ok());
         assert_eq!(results.unwrap().len(), 1);
@@ -293,4 +836,260 @@ So, "Hello".
         assert_eq!(no_results.unwrap().len(), 0);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_schema_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &[]);
+
+        assert!(search(&conf, "Hello".to_string(), &SearchOptions::default()).is_err());
+
+        create_indexer(&conf).unwrap();
+        assert!(check_schema_version(&conf.folder, false).is_ok());
+
+        // An index written before the schema was versioned has no version file
+        std::fs::remove_file(temp_dir.path().join(VERSION_FILE)).unwrap();
+        let err = create_indexer(&conf).err().unwrap();
+        assert!(err.to_string().contains("schema version 1"));
+    }
+
+    #[test]
+    fn test_exact_match_fields() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &[]);
+        let mut indexer = create_indexer(&conf).unwrap();
+
+        let email = serde_json::from_str::<Email>(
+            r#"{
+    "id": "M123-abc",
+    "blobId": "456",
+    "threadId": "T1",
+    "mailboxIds": {"P2": true},
+    "keywords": {"$flagged": true},
+    "size": 2048,
+    "hasAttachment": true,
+    "receivedAt": "2024-03-01T12:00:00Z",
+    "subject": "Invoice"
+}"#,
+        )
+        .unwrap();
+        let mailbox_names = MailboxNames::from([("P2".to_string(), vec!["Inbox".to_string(), "Receipts".to_string()])]);
+
+        write_document(&indexer, &email, &Message::default(), &mailbox_names).unwrap();
+        indexer.commit().unwrap();
+
+        let index = Index::open_in_dir(&conf.folder).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let fields = &EMAIL_SCHEMA.fields;
+        let query_parser = QueryParser::for_index(&index, vec![fields["subject"]]);
+        let count = |query: &str| searcher.search(&query_parser.parse_query(query).unwrap(), &tantivy::collector::Count).unwrap();
+
+        // Ids are not split into tokens
+        assert_eq!(count("id:\"M123-abc\""), 1);
+        assert_eq!(count("id:M123"), 0);
+        assert_eq!(count("keywords:$flagged"), 1);
+        assert_eq!(count("mailboxes:/Inbox"), 1);
+        assert_eq!(count("size:[1000 TO 3000]"), 1);
+        assert_eq!(count("has_attachment:true"), 1);
+        assert_eq!(count("received_at:[2024-01-01T00:00:00Z TO 2024-12-31T00:00:00Z]"), 1);
+    }
+
+    #[test]
+    fn test_stemming_and_folding() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &["no", "en"]);
+        let mut indexer = create_indexer(&conf).unwrap();
+
+        let email = serde_json::from_str::<Email>(r#"{"id": "M1", "blobId": "B1", "subject": "Regningene for mars"}"#).unwrap();
+        let message = MessageParser::default()
+            .parse("Subject: Regningene for mars\r\n\r\nThe invoices are attached. Betal innen fredag, takk. Café\r\n")
+            .unwrap();
+
+        write_document(&indexer, &email, &message, &MailboxNames::new()).unwrap();
+        indexer.commit().unwrap();
+
+        let count = |query: &str| search(&conf, query.to_string(), &SearchOptions::default()).unwrap().len();
+        assert_eq!(count("regning"), 1);
+        assert_eq!(count("invoice"), 1);
+        assert_eq!(count("cafe"), 1);
+        assert_eq!(count("faktura"), 0);
+
+        // Without the languages only the exact words match
+        let unstemmed = search_conf(&temp_dir, &[]);
+        assert_eq!(search(&unstemmed, "regning".to_string(), &SearchOptions::default()).unwrap().len(), 0);
+        assert_eq!(search(&unstemmed, "regningene".to_string(), &SearchOptions::default()).unwrap().len(), 1);
+
+        assert!(search_languages(&["no".to_string(), "xx".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_search_operators() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &[]);
+        let mut indexer = create_indexer(&conf).unwrap();
+
+        let invoice = serde_json::from_str::<Email>(
+            r#"{
+    "id": "M1",
+    "blobId": "B1",
+    "mailboxIds": {"P2": true},
+    "keywords": {"$flagged": true},
+    "size": 2048,
+    "hasAttachment": true,
+    "receivedAt": "2024-03-01T12:00:00Z",
+    "subject": "Invoice for March",
+    "from": [{"name": "Alice Hansen", "email": "alice@example.com"}]
+}"#,
+        )
+        .unwrap();
+        let newsletter = serde_json::from_str::<Email>(
+            r#"{
+    "id": "M2",
+    "blobId": "B2",
+    "mailboxIds": {"P1": true},
+    "keywords": {"$seen": true},
+    "size": 20480,
+    "hasAttachment": false,
+    "receivedAt": "2024-05-01T12:00:00Z",
+    "subject": "Weekly news",
+    "from": [{"name": "News", "email": "news@example.org"}]
+}"#,
+        )
+        .unwrap();
+        let mailbox_names = MailboxNames::from([
+            ("P1".to_string(), vec!["Inbox".to_string()]),
+            ("P2".to_string(), vec!["Inbox".to_string(), "Receipts".to_string()]),
+        ]);
+        let message = MessageParser::default()
+            .parse("Subject: Invoice\r\n\r\nPlease pay the attached invoice.\r\n")
+            .unwrap();
+
+        write_document(&indexer, &invoice, &message, &mailbox_names).unwrap();
+        write_document(&indexer, &newsletter, &Message::default(), &mailbox_names).unwrap();
+        indexer.commit().unwrap();
+
+        let ids = |query: &str| {
+            let mut ids = search(&conf, query.to_string(), &SearchOptions::default())
+                .unwrap()
+                .into_iter()
+                .map(|result| result.blob_id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids("from:alice"), ["B1"]);
+        assert_eq!(ids("from:news@example.org"), ["B2"]);
+        assert_eq!(ids(r#""pay the attached""#), ["B1"]);
+        assert_eq!(ids(r#""pay attached""#), Vec::<String>::new());
+        assert_eq!(ids("subject:weekly OR subject:march"), ["B1", "B2"]);
+        assert_eq!(ids("in:inbox/receipts"), ["B1"]);
+        assert_eq!(ids("in:P1"), ["B2"]);
+        assert_eq!(ids("is:unread"), ["B1"]);
+        assert_eq!(ids("is:flagged has:attachment"), ["B1"]);
+        assert_eq!(ids("-has:attachment"), ["B2"]);
+        assert_eq!(ids("after:2024-04-01"), ["B2"]);
+        assert_eq!(ids("before:2024-04-01 larger:1K smaller:10K"), ["B1"]);
+        assert!(search(&conf, "frm:alice".to_string(), &SearchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_search_results() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &[]);
+        let mut indexer = create_indexer(&conf).unwrap();
+        let mailbox_names = MailboxNames::from([("P2".to_string(), vec!["Inbox".to_string(), "Receipts".to_string()])]);
+
+        for (id, received_at, body) in [
+            ("M1", "2024-03-01T12:00:00Z", "The invoice for March is attached."),
+            ("M2", "2024-05-01T12:00:00Z", "Reminder: the invoice for March is overdue, please pay the invoice."),
+        ] {
+            let email = serde_json::from_str::<Email>(&format!(
+                r#"{{
+    "id": "{id}",
+    "blobId": "B{id}",
+    "threadId": "T1",
+    "mailboxIds": {{"P2": true}},
+    "receivedAt": "{received_at}",
+    "subject": "Invoice",
+    "from": [{{"name": "Alice Hansen", "email": "alice@example.com"}}],
+    "to": [{{"email": "bob@example.com"}}]
+}}"#
+            ))
+            .unwrap();
+            let raw = format!("Subject: Invoice\r\n\r\n{}\r\n", body);
+            let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+            write_document(&indexer, &email, &message, &mailbox_names).unwrap();
+        }
+        indexer.commit().unwrap();
+
+        let results = search(&conf, "overdue".to_string(), &SearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!((result.id.as_str(), result.blob_id.as_str()), ("M2", "BM2"));
+        assert_eq!(result.field("from").unwrap(), "Alice Hansen <alice@example.com>");
+        assert_eq!(result.field("to").unwrap(), "bob@example.com");
+        assert_eq!(result.field("date").unwrap(), "2024-05-01T12:00:00+00:00");
+        assert_eq!(result.field("mailboxes").unwrap(), "Inbox/Receipts");
+        assert!(result.score.is_some());
+        assert_eq!(
+            result.snippet.highlighted.iter().map(|range| &result.snippet.text[range.clone()]).collect::<Vec<_>>(),
+            ["overdue"]
+        );
+        assert_eq!(result.field("unknown"), None);
+
+        // Newest first, one page at a time
+        let page = |offset: usize| {
+            let options = SearchOptions { limit: 1, offset, sort: SearchSort::Date };
+            search(&conf, "invoice".to_string(), &options)
+                .unwrap()
+                .into_iter()
+                .map(|result| (result.id, result.score))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(page(0), [("M2".to_string(), None)]);
+        assert_eq!(page(1), [("M1".to_string(), None)]);
+        assert!(page(2).is_empty());
+
+        // Searches that match nothing in the body show its start
+        let results = search(&conf, "from:alice".to_string(), &SearchOptions::default()).unwrap();
+        assert!(results.iter().all(|result| result.snippet.highlighted.is_empty() && result.snippet.text.contains("invoice")));
+    }
+
+    #[test]
+    fn test_write_document_replaces_email() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf = search_conf(&temp_dir, &[]);
+        let mut indexer = create_indexer(&conf).unwrap();
+        let mailbox_names = MailboxNames::from([
+            ("P1".to_string(), vec!["Inbox".to_string()]),
+            ("P2".to_string(), vec!["Archive".to_string()]),
+        ]);
+
+        let unread = serde_json::from_str::<Email>(r#"{"id": "M1", "blobId": "B1", "subject": "Invoice", "mailboxIds": {"P1": true}}"#).unwrap();
+        write_document(&indexer, &unread, &Message::default(), &mailbox_names).unwrap();
+        indexer.commit().unwrap();
+
+        // Emails written again in the same commit as well as in a later one replace the earlier document
+        let read = serde_json::from_str::<Email>(
+            r#"{"id": "M1", "blobId": "B1", "subject": "Invoice", "mailboxIds": {"P2": true}, "keywords": {"$seen": true}}"#,
+        )
+        .unwrap();
+        write_document(&indexer, &unread, &Message::default(), &mailbox_names).unwrap();
+        write_document(&indexer, &read, &Message::default(), &mailbox_names).unwrap();
+        indexer.commit().unwrap();
+
+        let ids = |query: &str| search(&conf, query.to_string(), &SearchOptions::default()).unwrap().len();
+        assert_eq!(ids("invoice"), 1);
+        assert_eq!(ids("is:read in:archive"), 1);
+        assert_eq!(ids("in:inbox"), 0);
+        assert!(duplicate_ids(&conf).unwrap().is_empty());
+
+        // Older versions added the document again
+        let mut doc = Document::new();
+        doc.add_text(EMAIL_SCHEMA.fields["id"], "M1");
+        indexer.writer.add_document(doc).unwrap();
+        indexer.commit().unwrap();
+        assert_eq!(duplicate_ids(&conf).unwrap(), ["M1"]);
+    }
+}
@@ -18,3 +18,80 @@ pub fn create_storage_backend(scheme: Scheme, config: HashMap<String, String>) -
     Ok(retry_operator)
 }
 
+/// Storage backend in a temporary directory, shared by the tests of the modules writing the archive
+#[cfg(test)]
+pub(crate) fn fs_operator(dir: &tempfile::TempDir) -> Operator {
+    let config = HashMap::from([("root".to_string(), dir.path().to_str().unwrap().to_string())]);
+    create_storage_backend(Scheme::Fs, config).unwrap()
+}
+
+
+/**
+ * Check that the storage backend allows every operation a backup needs, using a small probe file.
+ * Returns the outcome of each operation in the order they were tried, stopping at the first failure.
+ */
+pub async fn check_permissions(operator: &Operator) -> Vec<(&'static str, anyhow::Result<()>)> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let path = format!("/postkasse-check-{}-{}", std::process::id(), nanos);
+    let probe = format!("postkasse {}", env!("CARGO_PKG_VERSION"));
+
+    let mut results = vec![];
+
+    let write = operator
+        .write(&path, probe.clone())
+        .await
+        .with_context(|| format!("Error writing {}", path));
+    results.push(("write", write));
+
+    let read = match operator.read(&path).await {
+        Ok(content) if content == probe.as_bytes() => Ok(()),
+        Ok(_) => Err(anyhow::anyhow!("Content of {} differs from what was written", path)),
+        Err(e) => Err(e).with_context(|| format!("Error reading {}", path)),
+    };
+    results.push(("read", read));
+
+    let list = match operator.list("/").await {
+        Ok(entries) if entries.iter().any(|entry| format!("/{}", entry.path()) == path) => Ok(()),
+        Ok(_) => Err(anyhow::anyhow!("{} is missing from the listing of the root", path)),
+        Err(e) => Err(e).with_context(|| "Error listing root"),
+    };
+    results.push(("list", list));
+
+    let delete = match operator.delete(&path).await {
+        Ok(()) => match operator.is_exist(&path).await {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(anyhow::anyhow!("{} still exists after deleting it", path)),
+            Err(e) => Err(e).with_context(|| format!("Error checking if {} exists", path)),
+        },
+        Err(e) => Err(e).with_context(|| format!("Error deleting {}", path)),
+    };
+    results.push(("delete", delete));
+
+    // Later operations depend on the earlier ones, so only report up to the first failure
+    let failed = results.iter().position(|(_, result)| result.is_err());
+    results.truncate(failed.map(|i| i + 1).unwrap_or(results.len()));
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_permissions() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        let results = check_permissions(&operator).await;
+        let operations = results.iter().map(|(operation, _)| *operation).collect::<Vec<_>>();
+        assert_eq!(operations, vec!["write", "read", "list", "delete"]);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert!(operator.list("/").await.unwrap().is_empty());
+    }
+}
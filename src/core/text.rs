@@ -0,0 +1,255 @@
+// Plain text of messages for indexing.
+// Every text body part is used, HTML parts without a plain text alternative are converted to text,
+// and forwarded messages attached as message/rfc822 contribute their text as well.
+// mail_parser has already decoded transfer encodings and charsets by the time parts get here.
+use mail_parser::{Message, PartType};
+
+/// Forwarded messages nested deeper than this are left out
+const MAX_NESTED_DEPTH: usize = 8;
+
+/// Elements whose content is never shown
+const HIDDEN_ELEMENTS: [&str; 6] = ["script", "style", "head", "title", "noscript", "template"];
+
+/// Elements that start on a new line when rendered
+const BLOCK_ELEMENTS: [&str; 27] = [
+    "address", "article", "aside", "blockquote", "br", "dd", "div", "dl", "dt", "footer", "h1", "h2", "h3", "h4", "h5",
+    "h6", "header", "hr", "li", "main", "nav", "ol", "p", "pre", "section", "table", "tr",
+];
+
+/**
+ * Extract the text of a message, with one part per paragraph.
+ */
+pub fn message_text(message: &Message) -> String {
+    let mut text = String::new();
+    push_message_text(message, &mut text, 0);
+    text
+}
+
+fn push_message_text(message: &Message, text: &mut String, depth: usize) {
+    for part in message.text_body.iter().filter_map(|id| message.parts.get(*id)) {
+        match &part.body {
+            PartType::Text(part_text) => push_paragraph(text, part_text),
+            PartType::Html(html) => push_paragraph(text, &html_to_text(html)),
+            _ => {}
+        }
+    }
+
+    if depth >= MAX_NESTED_DEPTH {
+        return;
+    }
+
+    for nested in message.attachments.iter().filter_map(|id| message.parts.get(*id)?.message()) {
+        push_paragraph(text, nested.subject().unwrap_or_default());
+        push_message_text(nested, text, depth + 1);
+    }
+}
+
+fn push_paragraph(text: &mut String, paragraph: &str) {
+    let paragraph = paragraph.trim();
+    if paragraph.is_empty() {
+        return;
+    }
+
+    if !text.is_empty() {
+        text.push_str("\n\n");
+    }
+    text.push_str(paragraph);
+}
+
+/**
+ * Convert HTML to readable text. Tags, comments, scripts and styles are dropped, entities are decoded,
+ * block elements start new lines and other whitespace is collapsed like a browser would.
+ */
+pub fn html_to_text(html: &str) -> String {
+    let mut raw = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map(|end| &comment[end + 3..]).unwrap_or_default();
+        } else if c == '<' && rest[1..].starts_with(|next: char| next.is_ascii_alphabetic() || next == '/' || next == '!') {
+            let end = tag_end(rest);
+            let (name, closing) = tag_name(rest.get(1..end).unwrap_or_default());
+            rest = rest.get(end + 1..).unwrap_or_default();
+
+            if !closing && HIDDEN_ELEMENTS.contains(&name.as_str()) {
+                rest = skip_element(rest, &name);
+            } else if BLOCK_ELEMENTS.contains(&name.as_str()) {
+                raw.push('\n');
+            } else if name == "td" || name == "th" {
+                raw.push(' ');
+            }
+        } else if c == '&' {
+            let (decoded, length) = decode_entity(rest);
+            raw.push_str(&decoded);
+            rest = &rest[length..];
+        } else {
+            // Line breaks in the source are just whitespace, only block elements break lines
+            raw.push(if c.is_whitespace() { ' ' } else { c });
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    // Collapse whitespace within lines and drop the empty lines left by nested block elements
+    raw.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Position of the `>` closing the tag at the start of `html`, skipping quoted attribute values
+fn tag_end(html: &str) -> usize {
+    let mut quote = None;
+
+    for (pos, c) in html.char_indices().skip(1) {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('>', None) => return pos,
+            _ => {}
+        }
+    }
+
+    html.len() - 1
+}
+
+/// Lower case name of a tag, and whether it closes an element
+fn tag_name(tag: &str) -> (String, bool) {
+    let closing = tag.starts_with('/');
+    let name = tag
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    (name, closing)
+}
+
+/// Skip past the end tag of a hidden element
+fn skip_element<'a>(html: &'a str, name: &str) -> &'a str {
+    let end_tag = format!("</{}", name);
+    let lower = html.to_ascii_lowercase();
+
+    match lower.find(&end_tag) {
+        Some(start) => {
+            let rest = &html[start..];
+            rest.get(tag_end(rest) + 1..).unwrap_or_default()
+        }
+        None => "",
+    }
+}
+
+/// Decode the entity at the start of `html`, returning the text and the number of bytes it took up.
+/// Anything that is not a known entity is kept as it is
+fn decode_entity(html: &str) -> (String, usize) {
+    let Some(end) = html.char_indices().take(12).find(|(_, c)| *c == ';').map(|(pos, _)| pos) else {
+        return ("&".to_string(), 1);
+    };
+
+    let entity = &html[1..end];
+    let decoded = match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "shy" | "zwnj" | "zwj" => Some('\u{200B}'),
+        "copy" => Some('©'),
+        "reg" => Some('®'),
+        "trade" => Some('™'),
+        "euro" => Some('€'),
+        "pound" => Some('£'),
+        "mdash" => Some('—'),
+        "ndash" => Some('–'),
+        "hellip" => Some('…'),
+        "laquo" => Some('«'),
+        "raquo" => Some('»'),
+        "lsquo" => Some('‘'),
+        "rsquo" => Some('’'),
+        "ldquo" => Some('“'),
+        "rdquo" => Some('”'),
+        "bull" => Some('•'),
+        "aelig" => Some('æ'),
+        "AElig" => Some('Æ'),
+        "oslash" => Some('ø'),
+        "Oslash" => Some('Ø'),
+        "aring" => Some('å'),
+        "Aring" => Some('Å'),
+        "auml" => Some('ä'),
+        "Auml" => Some('Ä'),
+        "ouml" => Some('ö'),
+        "Ouml" => Some('Ö'),
+        "uuml" => Some('ü'),
+        "Uuml" => Some('Ü'),
+        "eacute" => Some('é'),
+        "Eacute" => Some('É'),
+        "egrave" => Some('è'),
+        "szlig" => Some('ß'),
+        _ => match entity.strip_prefix('#') {
+            Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32),
+            Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+            None => None,
+        },
+    };
+
+    match decoded {
+        // Soft hyphens and zero width joiners are invisible and split no words, so they are dropped
+        Some('\u{200B}') => (String::new(), end + 1),
+        Some(c) => (c.to_string(), end + 1),
+        None => ("&".to_string(), 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    #[test]
+    fn test_html_to_text() {
+        let html = r#"<html><head><title>Newsletter</title><style>p { color: red; }</style></head>
+<body><!-- tracking --><script type="text/javascript">var a = "<p>";</script>
+<h1>Ukens   tilbud</h1><p>Rabatt p&aring; <b>alt</b> &amp; mer&nbsp;&#8211;&#x20AC;5</p>
+<table><tr><td>Pris</td><td>Antall</td></tr></table><a href="x>y">Avmeld</a></body></html>"#;
+
+        assert_eq!(html_to_text(html), "Ukens tilbud\nRabatt på alt & mer –€5\nPris Antall\nAvmeld");
+        assert_eq!(html_to_text("Fish &chips; &unknown; a < b"), "Fish &chips; &unknown; a < b");
+    }
+
+    #[test]
+    fn test_message_text() {
+        let raw = concat!(
+            "From: a@example.com\r\n",
+            "Subject: Fwd: Faktura\r\n",
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/alternative; boundary=\"alt\"\r\n\r\n",
+            "--alt\r\n",
+            "Content-Type: text/plain; charset=iso-8859-1\r\n",
+            "Content-Transfer-Encoding: quoted-printable\r\n\r\n",
+            "Her er fakturaen, f=E6rdig til betaling.\r\n",
+            "--alt\r\n",
+            "Content-Type: text/html\r\n\r\n",
+            "<p>Here is the invoice in HTML</p>\r\n",
+            "--alt--\r\n",
+            "--outer\r\n",
+            "Content-Type: message/rfc822\r\n\r\n",
+            "Subject: Faktura 42\r\n",
+            "Content-Type: text/html; charset=utf-8\r\n\r\n",
+            "<div>Bel&oslash;p: 100 kr</div><style>div {}</style>\r\n",
+            "--outer--\r\n",
+        );
+
+        let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+
+        // The HTML alternative of the plain text part is left out
+        assert_eq!(
+            message_text(&message),
+            "Her er fakturaen, færdig til betaling.\n\nFaktura 42\n\nBeløp: 100 kr"
+        );
+    }
+}
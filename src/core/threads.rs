@@ -0,0 +1,180 @@
+use anyhow::Context;
+use futures::{stream, StreamExt};
+use jmap_client::{client::Client, email::Email, thread::Thread};
+use log::{info, warn};
+use opendal::Operator;
+
+use super::layout;
+
+/**
+ * Back up the threads the given emails belong to.
+ * Threads grow as new mail arrives, so a thread is written again every time one of its emails is backed up.
+ */
+pub(crate) async fn threads(client: &Client, operator: &Operator, emails: &[Email]) -> anyhow::Result<()> {
+    let mut thread_ids = emails
+        .iter()
+        .filter_map(|email| email.thread_id())
+        .collect::<Vec<_>>();
+    thread_ids.sort();
+    thread_ids.dedup();
+
+    if thread_ids.is_empty() {
+        return Ok(());
+    }
+
+    info!("Backing up {} threads", thread_ids.len());
+    let threads_res = fetch_threads(client, &thread_ids).await?;
+
+    stream::iter(
+        threads_res
+            .iter()
+            .map(|thread| process_thread(thread, operator)),
+    )
+    .buffer_unordered(50)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(())
+}
+
+async fn fetch_threads(client: &Client, thread_ids: &[&str]) -> anyhow::Result<Vec<Thread>> {
+    let mut request = client.build();
+    request.get_thread().ids(thread_ids.iter().copied());
+
+    let mut response = request.send().await?.unwrap_method_responses();
+    let threads_res = response.pop();
+
+    match threads_res {
+        Some(threads_res) => {
+            let threads = threads_res.unwrap_get_thread()?.take_list();
+            Ok(threads)
+        }
+        _ => anyhow::bail!("unexpected number of responses"),
+    }
+}
+
+async fn process_thread(thread: &Thread, operator: &Operator) -> anyhow::Result<()> {
+    let path = layout::thread_path(thread.id());
+    let thread_json = serde_json::to_string(&thread)
+        .with_context(|| format!("Error serializing thread {}", thread.id()))?;
+
+    operator
+        .write(&path, thread_json)
+        .await
+        .with_context(|| format!("Error writing thread {}", thread.id()))
+}
+
+/**
+ * Read a conversation from the archive, oldest email first.
+ * The id may be a thread id, or the id of any email in the thread.
+ * Emails of the thread that are not in the archive yet are left out.
+ */
+pub async fn read_conversation(operator: &Operator, id: &str) -> anyhow::Result<Vec<Email>> {
+    let thread = match read_thread(operator, id).await? {
+        Some(thread) => thread,
+        None => {
+            let email = read_email(operator, id)
+                .await?
+                .with_context(|| format!("No thread or email with id {} in the archive", id))?;
+            let thread_id = email
+                .thread_id()
+                .with_context(|| format!("Email {} was backed up without its thread, run a backup first", id))?;
+
+            read_thread(operator, thread_id)
+                .await?
+                .with_context(|| format!("Thread {} is not in the archive, run a backup first", thread_id))?
+        }
+    };
+
+    let mut emails = vec![];
+    for email_id in thread.email_ids() {
+        match read_email(operator, email_id).await? {
+            Some(email) => emails.push(email),
+            None => warn!("Email {} of thread {} is not in the archive", email_id, thread.id()),
+        }
+    }
+
+    emails.sort_by_key(|email| email.received_at());
+
+    Ok(emails)
+}
+
+async fn read_thread(operator: &Operator, id: &str) -> anyhow::Result<Option<Thread>> {
+    if id.len() < 3 {
+        return Ok(None);
+    }
+
+    let path = layout::thread_path(id);
+    if !operator.is_exist(&path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let thread_json = operator
+        .read(&path)
+        .await
+        .with_context(|| format!("Error reading thread {}", id))?;
+    let thread = serde_json::from_slice(&thread_json)
+        .with_context(|| format!("Error deserializing thread {}", id))?;
+
+    Ok(Some(thread))
+}
+
+async fn read_email(operator: &Operator, id: &str) -> anyhow::Result<Option<Email>> {
+    if id.len() < 3 {
+        return Ok(None);
+    }
+
+    let path = layout::email_path(id);
+    if !operator.is_exist(&path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let email_json = operator
+        .read(&path)
+        .await
+        .with_context(|| format!("Error reading email {}", id))?;
+    let email = serde_json::from_slice(&email_json)
+        .with_context(|| format!("Error deserializing email {}", id))?;
+
+    Ok(Some(email))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::fs_operator;
+
+    #[tokio::test]
+    async fn test_read_conversation_in_chronological_order() {
+        let dir = TempDir::new().unwrap();
+        let operator = fs_operator(&dir);
+
+        operator
+            .write(&layout::thread_path("Tabc"), r#"{"id":"Tabc","emailIds":["Mreply","Mfirst","Mmissing"]}"#)
+            .await
+            .unwrap();
+        operator
+            .write(&layout::email_path("Mreply"), r#"{"id":"Mreply","threadId":"Tabc","receivedAt":"2024-01-02T10:00:00Z"}"#)
+            .await
+            .unwrap();
+        operator
+            .write(&layout::email_path("Mfirst"), r#"{"id":"Mfirst","threadId":"Tabc","receivedAt":"2024-01-01T10:00:00Z"}"#)
+            .await
+            .unwrap();
+
+        let ids = |emails: Vec<Email>| emails.iter().map(|e| e.id().unwrap().to_string()).collect::<Vec<_>>();
+
+        let by_thread = read_conversation(&operator, "Tabc").await.unwrap();
+        assert_eq!(ids(by_thread), vec!["Mfirst", "Mreply"]);
+
+        // Looking up the conversation by one of its emails gives the same result
+        let by_email = read_conversation(&operator, "Mreply").await.unwrap();
+        assert_eq!(ids(by_email), vec!["Mfirst", "Mreply"]);
+
+        assert!(read_conversation(&operator, "Tmissing").await.is_err());
+    }
+}
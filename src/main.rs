@@ -5,11 +5,12 @@ mod core;
 mod conf;
 mod cli;
 
-use core::{jmap::create_client, storage::create_storage_backend};
+use core::{index_sync::pull_index, jmap::JmapConnection, layout, lock, migrate::Convert, search::{Indexer, SearchOptions}, storage::create_storage_backend};
+use opendal::Operator;
 use std::{env, path::PathBuf};
 use anyhow::Context;
 use clap::Parser;
-use cli::{backup::backup, cli::{Cli, Commands}, search::search_emails};
+use cli::{backup::{backup_all, backup_profile}, cli::{Cli, Commands, ConfigCommands}, config::check_config, daemon::daemon, init::init, migrate::migrate_archive, reindex::{dedupe_archive_index, reindex_archive}, search::search_emails, secrets::secrets, thread::show_thread};
 use console::style;
 use indicatif::MultiProgress;
 use indicatif_log_bridge::LogWrapper;
@@ -22,13 +23,47 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let multi = MultiProgress::new();
     // Log setup to avoid clashes with indicatif
-    let logger =
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error,postkasse=error")).build();
+    // Logs go to stderr, so the output of commands like search can be piped to other programs
+    let logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error,postkasse=error"))
+        .target(env_logger::Target::Stderr)
+        .build();
 
     LogWrapper::new(multi.clone(), logger).try_init().unwrap();
 
     info!("Welcome to {}!", style("Postkasse").red().bold());
 
+    // There is no config to read before it has been written
+    if let Some(Commands::Init {}) = cli.command {
+        return init(cli.config).map_err(|e| {
+            let err = format!("Error creating config. {}", e);
+            error!("{}", style(err).red().bold());
+            std::process::exit(1);
+        });
+    }
+
+    // Checking the config must work when the config cannot be read as a profile
+    if let Some(Commands::Config { command: ConfigCommands::Check {} }) = cli.command {
+        check_config(&cli).await;
+        return Ok(());
+    }
+
+    if cli.all {
+        // Only backups make sense to run for every profile in one go
+        let Some(Commands::Backup {}) = cli.command else {
+            let err = "--all is only supported by the backup command";
+            error!("{}", style(err).red().bold());
+            std::process::exit(1);
+        };
+
+        let confs = conf::Conf::all(&cli).unwrap_or_else(|e| {
+            let err = format!("Error reading config file {}", e);
+            error!("{}", style(err).red().bold());
+            std::process::exit(1);
+        });
+
+        return backup_all(confs, &multi, cli.break_lock).await;
+    }
+
     let mut conf = conf::Conf::new(&cli).unwrap_or_else(|e| {
         let err = format!("Error reading config file {}", e);
         error!("{}", style(err).red().bold());
@@ -37,66 +72,159 @@ async fn main() -> anyhow::Result<()> {
     
     match cli.command {
         Some(Commands::Backup {}) => {
-            // We need to configure the jmap client and operator for backup to work
+            return backup_profile(&mut conf, &multi, cli.break_lock).await.map_err(|e| {
+                let err = format!("Error backing up {}. {}", conf.name, e);
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            })
+        }
+        Some(Commands::Daemon {}) => {
             conf.set_jmap_secret()?;
             conf.set_storage_secret()?;
 
-            let client = create_client(conf.jmap).await.unwrap_or_else(|e| {
-                let err = format!("{}", e);
+            let connection = connect_client(&conf).await;
+            let operator = connect_storage(&conf);
+            let indexer = connect_indexer(&conf);
+            let daemon_conf = conf.daemon.take().unwrap_or_default();
+            let backup_conf = conf.backup.take().unwrap_or_default();
+
+            return daemon(&connection, &operator, &daemon_conf, &backup_conf, indexer, cli.break_lock).await.map_err(|e| {
+                let err = format!("Error running daemon for {}. {}", conf.name, e);
                 error!("{}", style(err).red().bold());
                 std::process::exit(1);
-            });
+            })
+        }
+        Some(Commands::Status {}) => {
+            return Ok(());
+        }
+        Some(Commands::Search { query, fields, limit, offset, sort, format }) => {
+            if conf.search.as_ref().is_some_and(|search| search.enable && search.sync) {
+                conf.set_storage_secret()?;
+                let operator = connect_storage(&conf);
+                let folder = &conf.search.as_ref().unwrap().folder;
 
-            let operator = create_storage_backend(conf.storage.scheme.into(), conf.storage.config).unwrap_or_else(|e| {
-                let err = format!("{}", e);
+                if let Err(e) = pull_index(&operator, folder).await {
+                    let err = format!("Error copying the search index from the storage. {:#}", e);
+                    error!("{}", style(err).red().bold());
+                    std::process::exit(1);
+                }
+            }
+
+            if let Some(search) = conf.search {
+                search_emails(search, query, SearchOptions { limit, offset, sort }, fields, format);
+            } else {
+                let err = "Search is not enabled in config";
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            }
+
+            Ok(())
+        }
+        Some(Commands::Migrate { ref to, verify, concurrency, split_attachments, join_attachments }) => {
+            let mut target_conf = conf::Conf::profile(&cli, to).unwrap_or_else(|e| {
+                let err = format!("Error reading config for target profile {}", e);
                 error!("{}", style(err).red().bold());
                 std::process::exit(1);
             });
 
-            let indexer = conf.search.map(|s| {
-                if s.enable {
-                    Some(core::search::create_indexer(s.folder).unwrap_or_else(|e| {
-                        let err = format!("Error creating indexer. {}", e);
-                        error!("{}", style(err).red().bold());
-                        std::process::exit(1); // Bail out if indexer cannot be created
-                    }))
-                } else {
-                    None
+            conf.set_storage_secret()?;
+            target_conf.set_storage_secret()?;
+
+            let source = connect_storage(&conf);
+            let target = connect_storage(&target_conf);
+
+            // The target is stored the way its own backups would store messages
+            let convert = match (split_attachments, join_attachments) {
+                (true, _) => {
+                    let threshold_kb = target_conf.backup.take().unwrap_or_default().attachment_threshold_kb;
+                    Convert::Split { threshold: threshold_kb * 1024 }
                 }
-            }).unwrap_or_default();
+                (_, true) => Convert::Join,
+                _ => Convert::None,
+            };
 
-            return backup(client, operator, multi, indexer).await.map_err(|e| {
-                let err = format!("Error backing up {}. {}", conf.name, e);
+            return migrate_archive(&source, &target, &multi, concurrency, verify, convert, cli.break_lock).await.map_err(|e| {
+                let err = format!("Error migrating {} to {}. {}", conf.name, target_conf.name, e);
                 error!("{}", style(err).red().bold());
                 std::process::exit(1);
             })
         }
-        Some(Commands::Status {}) => {
-            return Ok(());
-        }
-        Some(Commands::Search { query, fields, limit }) => {
-            if let Some(search) = conf.search {
-                search_emails(search, query, limit, fields);
-            } else {
-                let err = format!("Search is not enabled in config");
+        Some(Commands::UpgradeArchive {}) => {
+            conf.set_storage_secret()?;
+            let operator = connect_storage(&conf);
+
+            let lock = lock::acquire(&operator, "upgrade-archive", cli.break_lock).await.unwrap_or_else(|e| {
+                let err = format!("{}", e);
                 error!("{}", style(err).red().bold());
                 std::process::exit(1);
+            });
+            let result = lock.hold(layout::upgrade_archive(&operator)).await;
+
+            match result {
+                Ok(from) if from == layout::LAYOUT_VERSION => {
+                    info!("Archive already uses layout version {}", from);
+                }
+                Ok(from) => {
+                    info!(
+                        "{} archive from layout version {} to {}",
+                        style("Upgraded").green(),
+                        from,
+                        layout::LAYOUT_VERSION
+                    );
+                }
+                Err(e) => {
+                    let err = format!("Error upgrading archive {}. {}", conf.name, e);
+                    error!("{}", style(err).red().bold());
+                    std::process::exit(1);
+                }
             }
 
             Ok(())
         }
-        Some(Commands::Open { id }) => {
+        Some(Commands::Reindex { concurrency }) => {
             conf.set_storage_secret()?;
-            let operator = create_storage_backend(conf.storage.scheme.into(), conf.storage.config).unwrap_or_else(|e| {
-                let err = format!("{}", e);
+            let operator = connect_storage(&conf);
+            check_archive(&operator, false).await;
+            let memory_limit_mb = conf.backup.take().unwrap_or_default().memory_limit_mb;
+
+            let Some(search_conf) = conf.search.as_ref().filter(|search| search.enable) else {
+                let err = "Search is not enabled in config";
                 error!("{}", style(err).red().bold());
                 std::process::exit(1);
-            });
-            let blob_path = &format!("/blobs/{}/{}", &id[..2], id);
+            };
+
+            return reindex_archive(&operator, search_conf, &multi, concurrency, memory_limit_mb, cli.break_lock).await.map_err(|e| {
+                let err = format!("Error reindexing {}. {:#}", conf.name, e);
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            })
+        }
+        Some(Commands::DedupeIndex { concurrency }) => {
+            conf.set_storage_secret()?;
+            let operator = connect_storage(&conf);
+            check_archive(&operator, false).await;
+            let memory_limit_mb = conf.backup.take().unwrap_or_default().memory_limit_mb;
+
+            let Some(search_conf) = conf.search.as_ref().filter(|search| search.enable) else {
+                let err = "Search is not enabled in config";
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            };
+
+            return dedupe_archive_index(&operator, search_conf, &multi, concurrency, memory_limit_mb, cli.break_lock).await.map_err(|e| {
+                let err = format!("Error deduplicating the search index of {}. {:#}", conf.name, e);
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            })
+        }
+        Some(Commands::Open { id }) => {
+            conf.set_storage_secret()?;
+            let operator = connect_storage(&conf);
+            check_archive(&operator, false).await;
             let temp_dir: PathBuf = env::temp_dir();
             let temp_file_path = temp_dir.join(format!("{}.eml", id));
 
-            let blob = operator.read(blob_path).await?;
+            let blob = core::blob::read_message(&operator, &id).await?;
             std::fs::write(&temp_file_path, blob).with_context(|| {
                 format!("Error writing blob to file {}", temp_file_path.display())
             })?;
@@ -107,9 +235,78 @@ async fn main() -> anyhow::Result<()> {
 
             Ok(())
         }
+        Some(Commands::Init {}) | Some(Commands::Config { .. }) => {
+            return Ok(()); // Handled before the profile is read
+        }
+        Some(Commands::Thread { id }) => {
+            conf.set_storage_secret()?;
+            let operator = connect_storage(&conf);
+            check_archive(&operator, false).await;
+
+            show_thread(&operator, &id).await;
+
+            Ok(())
+        }
+        Some(Commands::Secrets { command }) => {
+            // Secrets are not resolved here, the command manages them
+            return secrets(&conf, command).await.map_err(|e| {
+                let err = format!("Error managing secrets of {}. {:#}", conf.name, e);
+                error!("{}", style(err).red().bold());
+                std::process::exit(1);
+            })
+        }
         None => {
             return Ok(());
         }
     }
 }
 
+/**
+ * Connect to the JMAP server, exiting the process if the client cannot be created
+ */
+async fn connect_client(conf: &conf::Conf) -> JmapConnection {
+    JmapConnection::new(&conf.jmap, &conf.name).await.unwrap_or_else(|e| {
+        let err = format!("{}", e);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    })
+}
+
+/**
+ * Create the storage operator, exiting the process if the backend cannot be created
+ */
+fn connect_storage(conf: &conf::Conf) -> Operator {
+    create_storage_backend(conf.storage.scheme.into(), conf.storage.config.clone()).unwrap_or_else(|e| {
+        let err = format!("{}", e);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    })
+}
+
+/**
+ * Create the search indexer if search is enabled, exiting the process if the index cannot be opened
+ */
+fn connect_indexer(conf: &conf::Conf) -> Option<Indexer> {
+    conf.search.as_ref().and_then(|s| {
+        if s.enable {
+            Some(core::search::create_indexer(s).unwrap_or_else(|e| {
+                let err = format!("Error creating indexer. {}", e);
+                error!("{}", style(err).red().bold());
+                std::process::exit(1); // Bail out if indexer cannot be created
+            }))
+        } else {
+            None
+        }
+    })
+}
+
+/**
+ * Check that the archive has a layout we understand, exiting the process if it does not
+ */
+async fn check_archive(operator: &Operator, create: bool) {
+    if let Err(e) = layout::open_archive(operator, create).await {
+        let err = format!("{}", e);
+        error!("{}", style(err).red().bold());
+        std::process::exit(1);
+    }
+}